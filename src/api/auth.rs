@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients with a bucket before those idle long enough to have a full one again are dropped
+const MAX_BUCKETS: usize = 1024;
+/// Time an empty bucket takes to fill up, since the burst is a second's worth of requests
+const REFILL: Duration = Duration::from_secs(1);
+
+/// Access level a route (or JSON-RPC method) needs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Queries, plus submitting signed transactions, which any P2P peer can do anyway
+    Read,
+    /// Miner/generator control, peer management and spending from the node's wallet
+    Admin,
+}
+
+/// Why a request was turned away, mapped to an HTTP status code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No token, or a token that matches neither scope
+    Unauthorized,
+    /// A valid read token on an admin route
+    Forbidden,
+    RateLimited,
+}
+
+impl Denied {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Denied::Unauthorized => 401,
+            Denied::Forbidden => 403,
+            Denied::RateLimited => 429,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Denied::Unauthorized => "missing or invalid API token",
+            Denied::Forbidden => "API token does not grant admin access",
+            Denied::RateLimited => "too many requests",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Token required for admin routes. Admin routes are open when this is unset.
+    pub admin_token: Option<String>,
+    /// Token required for read routes (the admin token is accepted too). Read routes are open
+    /// when this is unset.
+    pub read_token: Option<String>,
+    /// Requests per second allowed per client IP, with a burst of the same size. Zero, like
+    /// unset, means no limit.
+    pub rate_limit: Option<u32>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct Auth {
+    config: Config,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl Auth {
+    pub fn new(config: Config) -> Self {
+        Auth {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a client presenting `token` may access a route of the given scope.
+    pub fn check(&self, client: Option<IpAddr>, token: Option<&str>, scope: Scope) -> Result<(), Denied> {
+        if let Some(client) = client {
+            self.take_rate_limit_token(client)?;
+        }
+        let is_admin = match (&self.config.admin_token, token) {
+            (Some(expected), Some(token)) => token_eq(expected, token),
+            _ => false,
+        };
+        let is_reader = match (&self.config.read_token, token) {
+            (Some(expected), Some(token)) => token_eq(expected, token),
+            _ => false,
+        };
+        match scope {
+            Scope::Read => {
+                if self.config.read_token.is_none() || is_reader || is_admin {
+                    Ok(())
+                } else {
+                    Err(Denied::Unauthorized)
+                }
+            }
+            Scope::Admin => {
+                if self.config.admin_token.is_none() || is_admin {
+                    Ok(())
+                } else if is_reader {
+                    Err(Denied::Forbidden)
+                } else {
+                    Err(Denied::Unauthorized)
+                }
+            }
+        }
+    }
+
+    fn take_rate_limit_token(&self, client: IpAddr) -> Result<(), Denied> {
+        let rate = match self.config.rate_limit {
+            Some(r) if r > 0 => r as f64,
+            _ => return Ok(()),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&client) {
+            // a full bucket is the same as none, so only clients seen lately need one
+            buckets.retain(|_, bucket| now.duration_since(bucket.last) < REFILL);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: rate, last: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate).min(rate);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return Err(Denied::RateLimited);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+fn token_eq(expected: &str, token: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok()
+}
+
+/// Scope needed by a REST route. `/rpc` is a read route; its admin methods are checked per call.
+pub fn route_scope(path: &str) -> Scope {
+    match path {
+        "/miner/start" | "/miner/pause" | "/miner/stop" => Scope::Admin,
+        "/tx-generator/start" | "/tx-generator/pause" | "/tx-generator/stop" => Scope::Admin,
+        "/network/ping" | "/network/connect" | "/network/disconnect" => Scope::Admin,
+        "/wallet/new" | "/wallet/import" | "/wallet/send" | "/wallet/send-batch" | "/wallet/cancel" => Scope::Admin,
+        "/wallet/contract" => Scope::Admin,
+        "/multisig/new" | "/multisig/sign" => Scope::Admin,
+        "/node/shutdown" => Scope::Admin,
+        _ => Scope::Read,
+    }
+}
+
+/// Scope needed by a JSON-RPC method.
+pub fn method_scope(method: &str) -> Scope {
+    match method {
+        "minerStart" | "minerPause" | "minerStop" => Scope::Admin,
+        "txGeneratorStart" | "txGeneratorPause" | "txGeneratorStop" => Scope::Admin,
+        "ping" | "connectPeer" | "disconnectPeer" => Scope::Admin,
+        "walletNewAccount" | "walletSend" | "walletSendBatch" | "walletCancel" | "walletContract" => Scope::Admin,
+        "multisigNew" | "multisigSign" => Scope::Admin,
+        _ => Scope::Read,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(admin: Option<&str>, read: Option<&str>, rate_limit: Option<u32>) -> Auth {
+        Auth::new(Config {
+            admin_token: admin.map(|s| s.to_string()),
+            read_token: read.map(|s| s.to_string()),
+            rate_limit,
+        })
+    }
+
+    #[test]
+    fn scopes() {
+        let a = auth(Some("admin"), Some("reader"), None);
+        assert_eq!(a.check(None, None, Scope::Read), Err(Denied::Unauthorized));
+        assert_eq!(a.check(None, Some("reader"), Scope::Read), Ok(()));
+        assert_eq!(a.check(None, Some("admin"), Scope::Read), Ok(()));
+        assert_eq!(a.check(None, Some("reader"), Scope::Admin), Err(Denied::Forbidden));
+        assert_eq!(a.check(None, Some("nope"), Scope::Admin), Err(Denied::Unauthorized));
+        assert_eq!(a.check(None, Some("admin"), Scope::Admin), Ok(()));
+
+        // without a read token only admin routes are protected
+        let a = auth(Some("admin"), None, None);
+        assert_eq!(a.check(None, None, Scope::Read), Ok(()));
+        assert_eq!(a.check(None, None, Scope::Admin), Err(Denied::Unauthorized));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let a = auth(None, None, Some(2));
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(a.check(Some(client), None, Scope::Read), Ok(()));
+        assert_eq!(a.check(Some(client), None, Scope::Read), Ok(()));
+        assert_eq!(a.check(Some(client), None, Scope::Read), Err(Denied::RateLimited));
+        assert_eq!(a.check(Some(other), None, Scope::Read), Ok(()));
+
+        // zero is no limit
+        let a = auth(None, None, Some(0));
+        for _ in 0..10 {
+            assert_eq!(a.check(Some(client), None, Scope::Read), Ok(()));
+        }
+        assert!(a.buckets.lock().unwrap().is_empty());
+
+        // clients idle long enough to be full again are forgotten once there are many
+        let a = auth(None, None, Some(2));
+        for i in 0..MAX_BUCKETS as u32 {
+            a.check(Some(IpAddr::from(i.to_be_bytes())), None, Scope::Read).unwrap();
+        }
+        for bucket in a.buckets.lock().unwrap().values_mut() {
+            bucket.last -= REFILL;
+        }
+        a.check(Some(client), None, Scope::Read).unwrap();
+        a.check(Some(client), None, Scope::Read).unwrap();
+        assert_eq!(a.buckets.lock().unwrap().len(), 1);
+        assert_eq!(a.check(Some(client), None, Scope::Read), Err(Denied::RateLimited));
+    }
+}
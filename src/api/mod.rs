@@ -1,25 +1,49 @@
+pub mod auth;
+pub mod rpc;
+
 use serde::Serialize;
 use crate::blockchain::Blockchain;
+use crate::metrics::METRICS;
 use crate::miner::Handle as MinerHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::shutdown::Handle as ShutdownHandle;
 use crate::tx_generator::GeneratorHandle as TXGeneratorHandle;
+use crate::tx_generator::workload::Workload;
+use crate::types::address::Address;
+use crate::types::hash::{Hashable, H256};
+use crate::types::ledger::{Ledger, LedgerTransaction};
+use crate::types::multisig::MultisigTransaction;
+use crate::types::transaction::{Amount, TransactionMemopool, Validity};
+use crate::wallet::Wallet;
 use std::convert::TryInto;
 use log::info;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
 pub struct Server {
     handle: HTTPServer,
-    miner: MinerHandle,
-    network: NetworkServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    generator: TXGeneratorHandle,
+    ctx: Context,
+    auth: Arc<auth::Auth>,
+}
+
+/// The parts of the node the API drives, cloned for each request
+#[derive(Clone)]
+pub struct Context {
+    pub miner: MinerHandle,
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub generator: TXGeneratorHandle,
+    pub state: Arc<Mutex<dyn Ledger>>,
+    pub trans_memopool: Arc<Mutex<TransactionMemopool>>,
+    pub wallet: Arc<Mutex<Wallet>>,
+    pub shutdown: ShutdownHandle,
 }
 
 #[derive(Serialize)]
@@ -40,6 +64,19 @@ macro_rules! respond_result {
         $req.respond(resp).unwrap();
     }};
 }
+macro_rules! respond_status {
+    ( $req:expr, $status:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiResponse {
+            success: false,
+            message: $message.to_string(),
+        };
+        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type)
+            .with_status_code($status);
+        $req.respond(resp).unwrap();
+    }};
+}
 macro_rules! respond_json {
     ( $req:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -50,28 +87,18 @@ macro_rules! respond_json {
 }
 
 impl Server {
-    pub fn start(
-        addr: std::net::SocketAddr,
-        miner: &MinerHandle,
-        network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
-        generator: &TXGeneratorHandle,
-
-    ) {
+    pub fn start(addr: std::net::SocketAddr, ctx: Context, auth: auth::Auth) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
-            miner: miner.clone(),
-            network: network.clone(),
-            blockchain: Arc::clone(blockchain),
-            generator: generator.clone(),
+            ctx,
+            auth: Arc::new(auth),
         };
         thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let network = server.network.clone();
-                let blockchain = Arc::clone(&server.blockchain);
-                let generator = server.generator.clone();
+            for mut req in server.handle.incoming_requests() {
+                let Context { miner, network, blockchain, generator, state, trans_memopool, wallet, shutdown } =
+                    server.ctx.clone();
+                let auth = Arc::clone(&server.auth);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -82,7 +109,41 @@ impl Server {
                             return;
                         }
                     };
+                    let token = request_token(&req, &url);
+                    // tiny_http returns the remote address either directly or as an Option
+                    let client: Option<&std::net::SocketAddr> = req.remote_addr().into();
+                    let client = client.map(|a| a.ip());
+                    let scope = auth::route_scope(url.path());
+                    if let Err(denied) = auth.check(client, token.as_deref(), scope) {
+                        respond_status!(req, denied.status_code(), denied.message());
+                        return;
+                    }
                     match url.path() {
+                        "/rpc" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "JSON-RPC requests must be POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let ctx = rpc::Context {
+                                miner,
+                                network,
+                                blockchain,
+                                generator,
+                                state,
+                                trans_memopool,
+                                wallet,
+                                admin: auth.check(None, token.as_deref(), auth::Scope::Admin).is_ok(),
+                            };
+                            match rpc::handle(&ctx, &body) {
+                                Some(resp) => respond_json!(req, resp),
+                                None => req.respond(Response::empty(204)).unwrap(),
+                            }
+                        }
                         "/miner/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -104,35 +165,77 @@ impl Server {
                                     return;
                                 }
                             };
+                            if miner.is_stopped() {
+                                respond_result!(req, false, "miner is stopped");
+                                return;
+                            }
+                            if blockchain.lock().unwrap().light {
+                                respond_result!(req, false, "a light node cannot mine");
+                                return;
+                            }
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stop" => {
+                            miner.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
                         "/tx-generator/start" => {
-                            // unimplemented!()
-                            let params = url.query_pairs();
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                            let theta = match params.get("theta") {
-                                Some(v) => v,
-                                None => {
-                                    respond_result!(req, false, "missing theta");
-                                    return;
-                                }
+                            // the workload is POSTed as a JSON workload file, or given by query
+                            // parameters named as its fields
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let workload = if body.trim().is_empty() {
+                                let mut params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                                params.remove("token");
+                                Workload::from_params(&params)
+                            } else {
+                                serde_json::from_str(&body)
+                                    .map_err(|e| format!("error parsing workload: {}", e))
+                                    .and_then(Workload::from_value)
                             };
-                            let theta = match theta.parse::<u64>() {
-                                Ok(v) => v,
+                            let workload = match workload {
+                                Ok(w) => w,
                                 Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing theta: {}", e)
-                                    );
+                                    respond_result!(req, false, e);
                                     return;
                                 }
                             };
-                            // println!("get generator start");
-                            generator.start(theta);
-                            respond_result!(req, true, "ok");  
-                            // respond_result!(req, false, "unimplemented!");
+                            if generator.is_stopped() {
+                                respond_result!(req, false, "tx generator is stopped");
+                                return;
+                            }
+                            if blockchain.lock().unwrap().light {
+                                respond_result!(req, false, "a light node has no state to generate transactions from");
+                                return;
+                            }
+                            if state.lock().unwrap().as_accounts().is_none() {
+                                respond_result!(req, false, "the tx generator only makes account transactions");
+                                return;
+                            }
+                            generator.start(workload);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/pause" => {
+                            generator.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/stop" => {
+                            generator.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/status" => {
+                            respond_json!(req, generator.status());
                         }
                         "/blockchain/state" => {
                             // unimplemented!()
@@ -167,10 +270,397 @@ impl Server {
                             respond_json!(req, accounts_info);                            
                             // respond_result!(req, false, "unimplemented!");
                         }
+                        "/metrics" => {
+                            let content_type = "Content-Type: text/plain; version=0.0.4"
+                                .parse::<Header>()
+                                .unwrap();
+                            let resp = Response::from_string(METRICS.render())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/wallet/accounts" => {
+                            let wallet = wallet.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            respond_json!(req, wallet.accounts_info(&*state));
+                        }
+                        "/wallet/new" => {
+                            let mut wallet = wallet.lock().unwrap();
+                            match wallet.new_account() {
+                                Ok(address) => respond_result!(req, true, address),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/import" => {
+                            // the key is POSTed, so that it stays out of URLs and the logs
+                            // that keep them
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "use POST, with the key in the body");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let pkcs8 = match crate::wallet::decode_pkcs8(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error decoding key: {}", e));
+                                    return;
+                                }
+                            };
+                            let mut wallet = wallet.lock().unwrap();
+                            match wallet.import(&pkcs8) {
+                                Ok(address) => respond_result!(req, true, address),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/transaction/submit" => {
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "transactions must be POSTed");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let check_result = {
+                                let state = state.lock().unwrap();
+                                LedgerTransaction::decode(&body, state.model())
+                                    .and_then(|tx| state.check(&tx).map(|_| tx))
+                            };
+                            let tx = match check_result {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("transaction failed verification: {}", e));
+                                    return;
+                                }
+                            };
+                            match submit_transaction(&network, &trans_memopool, tx) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/send" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (from, to, value) = match (params.get("from"), params.get("to"), params.get("value")) {
+                                (Some(f), Some(t), Some(v)) => (f, t, v),
+                                _ => {
+                                    respond_result!(req, false, "missing from, to or value");
+                                    return;
+                                }
+                            };
+                            let parsed = (from.parse(), to.parse(), value.parse::<Amount>());
+                            let (from, to, value) = match parsed {
+                                (Ok(f), Ok(t), Ok(v)) => (f, t, v),
+                                _ => {
+                                    respond_result!(req, false, "error parsing from, to or value");
+                                    return;
+                                }
+                            };
+                            let validity = match validity_params(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let tx = {
+                                let mut wallet = wallet.lock().unwrap();
+                                let state = state.lock().unwrap();
+                                wallet.transfer(&from, &to, value, validity, &*state)
+                            };
+                            match tx.and_then(|tx| submit_transaction(&network, &trans_memopool, tx)) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/send-batch" => {
+                            // the payments are POSTed as a JSON list of receivers and values
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let from = match params.get("from").map(|f| f.parse::<Address>()) {
+                                Some(Ok(f)) => f,
+                                Some(Err(_)) => {
+                                    respond_result!(req, false, "error parsing from");
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing from");
+                                    return;
+                                }
+                            };
+                            let validity = match validity_params(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let tx = serde_json::from_str(&body)
+                                .map_err(|e| format!("error decoding payments: {}", e))
+                                .and_then(|payments| {
+                                    let mut wallet = wallet.lock().unwrap();
+                                    let state = state.lock().unwrap();
+                                    wallet.batch_transfer(&from, payments, validity, &*state)
+                                });
+                            match tx.and_then(|tx| submit_transaction(&network, &trans_memopool, tx.into())) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/cancel" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let (from, nonce) = match (params.get("from"), params.get("nonce")) {
+                                (Some(f), Some(n)) => (f, n),
+                                _ => {
+                                    respond_result!(req, false, "missing from or nonce");
+                                    return;
+                                }
+                            };
+                            let (from, nonce) = match (from.parse(), nonce.parse::<u32>()) {
+                                (Ok(f), Ok(n)) => (f, n),
+                                _ => {
+                                    respond_result!(req, false, "error parsing from or nonce");
+                                    return;
+                                }
+                            };
+                            let result = cancel_transaction(&wallet, &state, &trans_memopool, &from, nonce)
+                                .and_then(|tx| submit_transaction(&network, &trans_memopool, tx));
+                            match result {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/contract" => {
+                            // the deploy or call is POSTed as JSON
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let (from, gas_limit) = match (params.get("from"), params.get("gas_limit")) {
+                                (Some(f), Some(g)) => (f, g),
+                                _ => {
+                                    respond_result!(req, false, "missing from or gas_limit");
+                                    return;
+                                }
+                            };
+                            let (from, gas_limit) = match (from.parse::<Address>(), gas_limit.parse::<u64>()) {
+                                (Ok(f), Ok(g)) => (f, g),
+                                _ => {
+                                    respond_result!(req, false, "error parsing from or gas_limit");
+                                    return;
+                                }
+                            };
+                            let validity = match validity_params(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let tx = serde_json::from_str(&body)
+                                .map_err(|e| format!("error decoding contract action: {}", e))
+                                .and_then(|action| {
+                                    let mut wallet = wallet.lock().unwrap();
+                                    let state = state.lock().unwrap();
+                                    wallet.contract_transaction(&from, action, gas_limit, validity, &*state)
+                                });
+                            match tx.and_then(|tx| submit_transaction(&network, &trans_memopool, tx.into())) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/multisig/new" | "/multisig/sign" => {
+                            // the policy, or the transfer being signed, is POSTed as JSON
+                            if req.method() != &Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let result = if url.path() == "/multisig/new" {
+                                let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                                let (to, value) = match (params.get("to"), params.get("value")) {
+                                    (Some(t), Some(v)) => (t, v),
+                                    _ => {
+                                        respond_result!(req, false, "missing to or value");
+                                        return;
+                                    }
+                                };
+                                let (to, value) = match (to.parse(), value.parse::<Amount>()) {
+                                    (Ok(t), Ok(v)) => (t, v),
+                                    _ => {
+                                        respond_result!(req, false, "error parsing to or value");
+                                        return;
+                                    }
+                                };
+                                let validity = match validity_params(&params) {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                };
+                                serde_json::from_str(&body)
+                                    .map_err(|e| format!("error decoding policy: {}", e))
+                                    .and_then(|policy| {
+                                        let mut wallet = wallet.lock().unwrap();
+                                        let state = state.lock().unwrap();
+                                        wallet.multisig_transfer(policy, &to, value, validity, &*state)
+                                    })
+                            } else {
+                                MultisigTransaction::decode(&body).and_then(|mut tx| {
+                                    wallet.lock().unwrap().sign_multisig(&mut tx).map(|_| tx)
+                                })
+                            };
+                            match result {
+                                Ok(tx) => respond_json!(req, LedgerTransaction::from(tx)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/node/shutdown" => {
+                            if req.method() != &Method::Post {
+                                respond_status!(req, 405, "use POST");
+                                return;
+                            }
+                            respond_result!(req, true, "shutting down");
+                            shutdown.trigger("API request");
+                        }
+                        "/network/connect" | "/network/disconnect" => {
+                            if req.method() != &Method::Post {
+                                respond_status!(req, 405, "use POST");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let peer_addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let peer_addr = match peer_addr.parse::<std::net::SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing addr: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            if url.path() == "/network/connect" {
+                                match network.connect(peer_addr) {
+                                    Ok(_) => respond_result!(req, true, "ok"),
+                                    Err(e) => respond_result!(
+                                        req,
+                                        false,
+                                        format!("error connecting to {}: {}", peer_addr, e)
+                                    ),
+                                }
+                            } else if network.disconnect(peer_addr) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, format!("not connected to {}", peer_addr));
+                            }
+                        }
+                        "/blockchain/spec" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            respond_json!(req, serde_json::json!({
+                                "genesis": blockchain.spec.genesis().hash().to_string(),
+                                "spec": blockchain.spec,
+                            }));
+                        }
+                        path if path.starts_with("/tx/") && path.ends_with("/proof") => {
+                            let hash = &path["/tx/".len()..path.len() - "/proof".len()];
+                            let hash = match hash.parse::<H256>() {
+                                Ok(h) => h,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing transaction hash: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.transaction_proof(&hash) {
+                                Some(proof) => respond_json!(req, proof),
+                                None => respond_status!(req, 404, format!("transaction {} is not in the longest chain", hash)),
+                            }
+                        }
+                        path if path.starts_with("/oracle/") => {
+                            let id = &path["/oracle/".len()..];
+                            let state = state.lock().unwrap();
+                            match state.as_accounts().and_then(|state| state.feed(id)) {
+                                Some(feed) => respond_json!(req, feed),
+                                None => respond_result!(req, false, format!("no feed {}", id)),
+                            }
+                        }
+                        path if path.starts_with("/contract/") => {
+                            let address = match path["/contract/".len()..].parse::<Address>() {
+                                Ok(a) => a,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+                            let state = state.lock().unwrap();
+                            match state.as_accounts().and_then(|state| state.contract(&address)) {
+                                Some(contract) => respond_json!(req, contract),
+                                None => respond_result!(req, false, format!("{} is not a contract", address)),
+                            }
+                        }
+                        path if path.starts_with("/account/") && path.ends_with("/proof") => {
+                            let address = &path["/account/".len()..path.len() - "/proof".len()];
+                            let address = match address.parse::<Address>() {
+                                Ok(a) => a,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let block = match params.get("block").map(|b| b.parse::<H256>()) {
+                                Some(Ok(h)) => Some(h),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing block: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.account_proof(&address, block) {
+                                Ok(proof) => respond_json!(req, proof),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -180,7 +670,16 @@ impl Server {
                         "/blockchain/longest-chain-tx" => {
                             // unimplemented!()
                             // respond_result!(req, false, "unimplemented!");
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
                             let blockchain = blockchain.lock().unwrap();
+                            // with `details`, the kind, payments, fee and size of each transaction
+                            if params.get("details").is_some_and(|d| d == "true") {
+                                match blockchain.transaction_summaries_in_longest_chain() {
+                                    Some(summaries) => respond_json!(req, summaries),
+                                    None => respond_result!(req, false, "a light node only has the hashes of the transactions proven to it"),
+                                }
+                                return;
+                            }
                             let txs = blockchain.all_transactions_in_longest_chain();
                             // let txs_string: Vec<Vec<String>> = txs.into_iter().map(|h|h.to_string()).collect();
                             respond_json!(req, txs);
@@ -210,3 +709,71 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+/// Put a verified transaction into the mempool, in place of one with the same nonce and a lower
+/// fee, and announce it to our peers
+fn submit_transaction(
+    network: &NetworkServerHandle,
+    trans_memopool: &Arc<Mutex<TransactionMemopool>>,
+    tx: LedgerTransaction,
+) -> Result<H256, String> {
+    let tx_hash = tx.hash();
+    let inserted = {
+        let mut trans_memopool = trans_memopool.lock().unwrap();
+        let inserted = trans_memopool.insert(tx)?;
+        METRICS.mempool_size.set(trans_memopool.trans_map.len() as f64);
+        inserted
+    };
+    if inserted {
+        network.broadcast(Message::NewTransactionHashes(vec![tx_hash]));
+    }
+    Ok(tx_hash)
+}
+
+/// Sign a cancel of the transfer in the mempool from a wallet account with `nonce`, outbidding
+/// its fee
+fn cancel_transaction(
+    wallet: &Arc<Mutex<Wallet>>,
+    state: &Arc<Mutex<dyn Ledger>>,
+    trans_memopool: &Arc<Mutex<TransactionMemopool>>,
+    from: &Address,
+    nonce: u32,
+) -> Result<LedgerTransaction, String> {
+    let pending_fee = match trans_memopool.lock().unwrap().pending(from, nonce) {
+        Some((_, tx)) => tx.transfer().unwrap().fee(),
+        None => return Err(format!("no transaction from {} with nonce {} in the mempool", from, nonce)),
+    };
+    let wallet = wallet.lock().unwrap();
+    let state = state.lock().unwrap();
+    wallet.cancel(from, nonce, pending_fee, &*state).map(LedgerTransaction::from)
+}
+
+/// The window of validity of a transfer, from the optional query parameters of the same names
+fn validity_params(params: &HashMap<String, String>) -> Result<Validity, String> {
+    let param = |name: &str| {
+        params
+            .get(name)
+            .map(|v| v.parse::<u64>().map_err(|e| format!("error parsing {}: {}", name, e)))
+            .transpose()
+    };
+    Ok(Validity {
+        valid_after_height: param("valid_after_height")?,
+        valid_after_timestamp: param("valid_after_timestamp")?,
+        expires_at_height: param("expires_at_height")?,
+        expires_at_timestamp: param("expires_at_timestamp")?,
+    })
+}
+
+/// The API token from an `Authorization: Bearer` header, or else from the `token` query parameter
+fn request_token(req: &tiny_http::Request, url: &Url) -> Option<String> {
+    for header in req.headers() {
+        if header.field.equiv("Authorization") {
+            if let Some(token) = header.value.as_str().strip_prefix("Bearer ") {
+                return Some(token.trim().to_string());
+            }
+        }
+    }
+    url.query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+}
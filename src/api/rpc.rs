@@ -0,0 +1,551 @@
+use serde_json::{json, Value};
+use super::auth::{self, Scope};
+use crate::blockchain::Blockchain;
+use crate::miner::Handle as MinerHandle;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::network::message::Message;
+use crate::tx_generator::GeneratorHandle as TXGeneratorHandle;
+use crate::tx_generator::workload::Workload;
+use crate::types::address::Address;
+use crate::types::batch::Payment;
+use crate::types::block::Block;
+use crate::types::contract::ContractAction;
+use crate::types::hash::{H256, Hashable};
+use crate::types::ledger::{Ledger, LedgerTransaction};
+use crate::types::multisig::{MultisigPolicy, MultisigTransaction};
+use crate::types::transaction::{TransactionMemopool, Validity};
+use crate::wallet::Wallet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+// standard JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// server-defined error codes
+pub const UNAUTHORIZED: i64 = -32001;
+
+/// Everything a JSON-RPC method may need to touch, cloned from the API server per request.
+pub struct Context {
+    pub miner: MinerHandle,
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub generator: TXGeneratorHandle,
+    pub state: Arc<Mutex<dyn Ledger>>,
+    pub trans_memopool: Arc<Mutex<TransactionMemopool>>,
+    pub wallet: Arc<Mutex<Wallet>>,
+    /// Whether the caller presented a token that grants admin methods
+    pub admin: bool,
+}
+
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        RpcError { code, message: message.to_string() }
+    }
+
+    fn invalid_params(message: impl ToString) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+type RpcResult = Result<Value, RpcError>;
+
+/// Handle the body of a JSON-RPC request, which is either a single call or a batch of calls.
+/// Returns `None` when there is nothing to send back, i.e. every call was a notification.
+pub fn handle(ctx: &Context, body: &str) -> Option<Value> {
+    let request: Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+    };
+    match request {
+        Value::Array(calls) => {
+            if calls.is_empty() {
+                return Some(error_response(
+                    Value::Null,
+                    RpcError::new(INVALID_REQUEST, "empty batch"),
+                ));
+            }
+            let responses: Vec<Value> = calls.into_iter().filter_map(|c| handle_call(ctx, c)).collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        call => handle_call(ctx, call),
+    }
+}
+
+/// Handle a single call. Notifications (calls without an `id`) are executed but get no response.
+fn handle_call(ctx: &Context, call: Value) -> Option<Value> {
+    let call = match call {
+        Value::Object(map) => map,
+        _ => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, "request must be an object"),
+            ))
+        }
+    };
+    let is_notification = !call.contains_key("id");
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    match &id {
+        Value::Null | Value::Number(_) | Value::String(_) => {}
+        _ => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, "id must be a string, number or null"),
+            ))
+        }
+    }
+    if call.get("jsonrpc") != Some(&Value::String("2.0".to_string())) {
+        return Some(error_response(id, RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")));
+    }
+    let method = match call.get("method") {
+        Some(Value::String(m)) => m.as_str(),
+        _ => return Some(error_response(id, RpcError::new(INVALID_REQUEST, "missing method"))),
+    };
+    let params = call.get("params").cloned().unwrap_or(Value::Null);
+    match &params {
+        Value::Null | Value::Array(_) | Value::Object(_) => {}
+        _ => {
+            return Some(error_response(
+                id,
+                RpcError::new(INVALID_REQUEST, "params must be an array or an object"),
+            ))
+        }
+    }
+
+    let result = if auth::method_scope(method) == Scope::Admin && !ctx.admin {
+        Err(RpcError::new(UNAUTHORIZED, format!("method {} requires an admin token", method)))
+    } else {
+        dispatch(ctx, method, &params)
+    };
+    if is_notification {
+        return None;
+    }
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+fn dispatch(ctx: &Context, method: &str, params: &Value) -> RpcResult {
+    match method {
+        "getBlockCount" => {
+            let blockchain = ctx.blockchain.lock().unwrap();
+            Ok(json!(blockchain.all_blocks_in_longest_chain().len()))
+        }
+        "getChainSpec" => {
+            let blockchain = ctx.blockchain.lock().unwrap();
+            Ok(json!({
+                "genesis": blockchain.spec.genesis().hash().to_string(),
+                "spec": blockchain.spec,
+            }))
+        }
+        "getBestBlockHash" => {
+            let blockchain = ctx.blockchain.lock().unwrap();
+            Ok(json!(blockchain.tip().to_string()))
+        }
+        "getBlockHash" => {
+            let height = param_u64(params, 0, "height")? as usize;
+            let blockchain = ctx.blockchain.lock().unwrap();
+            let chain = blockchain.all_blocks_in_longest_chain();
+            match chain.get(height) {
+                Some(hash) => Ok(json!(hash.to_string())),
+                None => Err(RpcError::invalid_params(format!("no block at height {}", height))),
+            }
+        }
+        "getBlock" => {
+            let hash = param_hash(params, 0, "hash")?;
+            let blockchain = ctx.blockchain.lock().unwrap();
+            match blockchain.block_map.get(&hash) {
+                Some(block) => Ok(block_json(block, blockchain.block_seq[&hash])),
+                None => Err(RpcError::invalid_params(format!("unknown block {}", hash))),
+            }
+        }
+        "getTransactionProof" => {
+            let hash = param_hash(params, 0, "hash")?;
+            let blockchain = ctx.blockchain.lock().unwrap();
+            match blockchain.transaction_proof(&hash) {
+                Some(proof) => Ok(json!(proof)),
+                None => Err(RpcError::invalid_params(format!("transaction {} is not in the longest chain", hash))),
+            }
+        }
+        "getAccountProof" => {
+            let address = param_address(params, 0, "address")?;
+            let block = match param(params, 1, "block") {
+                Ok(_) => Some(param_hash(params, 1, "block")?),
+                Err(_) => None,
+            };
+            let blockchain = ctx.blockchain.lock().unwrap();
+            match blockchain.account_proof(&address, block) {
+                Ok(proof) => Ok(json!(proof)),
+                Err(e) => Err(RpcError::invalid_params(e)),
+            }
+        }
+        "getLongestChain" => {
+            let blockchain = ctx.blockchain.lock().unwrap();
+            let chain: Vec<String> = blockchain
+                .all_blocks_in_longest_chain()
+                .into_iter()
+                .map(|h| h.to_string())
+                .collect();
+            Ok(json!(chain))
+        }
+        "getBalance" => {
+            let address = param_address(params, 0, "address")?;
+            let state = ctx.state.lock().unwrap();
+            Ok(json!(state.balance(&address)))
+        }
+        "getTransactionCount" => {
+            let address = param_address(params, 0, "address")?;
+            let state = ctx.state.lock().unwrap();
+            Ok(json!(state.nonce(&address)))
+        }
+        "getMempool" => {
+            let trans_memopool = ctx.trans_memopool.lock().unwrap();
+            let txs: Vec<String> = trans_memopool.trans_map.keys().map(|h| h.to_string()).collect();
+            Ok(json!(txs))
+        }
+        "sendTransaction" => {
+            let raw = param_str(params, 0, "transaction")?;
+            let tx = {
+                let state = ctx.state.lock().unwrap();
+                let tx = LedgerTransaction::decode(raw, state.model()).map_err(RpcError::invalid_params)?;
+                state
+                    .check(&tx)
+                    .map_err(|e| RpcError::invalid_params(format!("transaction failed verification: {}", e)))?;
+                tx
+            };
+            let tx_hash =
+                super::submit_transaction(&ctx.network, &ctx.trans_memopool, tx).map_err(RpcError::invalid_params)?;
+            Ok(json!(tx_hash.to_string()))
+        }
+        "walletAccounts" => {
+            let wallet = ctx.wallet.lock().unwrap();
+            let state = ctx.state.lock().unwrap();
+            Ok(json!(wallet.accounts_info(&*state)))
+        }
+        "walletNewAccount" => {
+            let mut wallet = ctx.wallet.lock().unwrap();
+            match wallet.new_account() {
+                Ok(address) => Ok(json!(address.to_string())),
+                Err(e) => Err(RpcError::new(INTERNAL_ERROR, e)),
+            }
+        }
+        "walletSend" => {
+            let from = param_address(params, 0, "from")?;
+            let to = param_address(params, 1, "to")?;
+            let value = param_u64(params, 2, "value")?;
+            let validity = param_validity(params, 3)?;
+            let tx = {
+                let mut wallet = ctx.wallet.lock().unwrap();
+                let state = ctx.state.lock().unwrap();
+                wallet.transfer(&from, &to, value, validity, &*state).map_err(RpcError::invalid_params)?
+            };
+            let tx_hash =
+                super::submit_transaction(&ctx.network, &ctx.trans_memopool, tx).map_err(RpcError::invalid_params)?;
+            Ok(json!(tx_hash.to_string()))
+        }
+        "walletSendBatch" => {
+            let from = param_address(params, 0, "from")?;
+            let payments: Vec<Payment> = serde_json::from_value(param(params, 1, "payments")?.clone())
+                .map_err(|e| RpcError::invalid_params(format!("error parsing payments: {}", e)))?;
+            let validity = param_validity(params, 2)?;
+            let tx = {
+                let mut wallet = ctx.wallet.lock().unwrap();
+                let state = ctx.state.lock().unwrap();
+                wallet.batch_transfer(&from, payments, validity, &*state).map_err(RpcError::invalid_params)?
+            };
+            let tx_hash = super::submit_transaction(&ctx.network, &ctx.trans_memopool, tx.into())
+                .map_err(RpcError::invalid_params)?;
+            Ok(json!(tx_hash.to_string()))
+        }
+        "walletCancel" => {
+            let from = param_address(params, 0, "from")?;
+            let nonce = param_u64(params, 1, "nonce")?;
+            let nonce = u32::try_from(nonce).map_err(|_| RpcError::invalid_params("nonce is out of range"))?;
+            let tx = super::cancel_transaction(&ctx.wallet, &ctx.state, &ctx.trans_memopool, &from, nonce)
+                .map_err(RpcError::invalid_params)?;
+            let tx_hash =
+                super::submit_transaction(&ctx.network, &ctx.trans_memopool, tx).map_err(RpcError::invalid_params)?;
+            Ok(json!(tx_hash.to_string()))
+        }
+        "walletContract" => {
+            let from = param_address(params, 0, "from")?;
+            let action: ContractAction = serde_json::from_value(param(params, 1, "action")?.clone())
+                .map_err(|e| RpcError::invalid_params(format!("error parsing action: {}", e)))?;
+            let gas_limit = param_u64(params, 2, "gas_limit")?;
+            let validity = param_validity(params, 3)?;
+            let tx = {
+                let mut wallet = ctx.wallet.lock().unwrap();
+                let state = ctx.state.lock().unwrap();
+                wallet
+                    .contract_transaction(&from, action, gas_limit, validity, &*state)
+                    .map_err(RpcError::invalid_params)?
+            };
+            let tx_hash = super::submit_transaction(&ctx.network, &ctx.trans_memopool, tx.into())
+                .map_err(RpcError::invalid_params)?;
+            Ok(json!(tx_hash.to_string()))
+        }
+        "getContract" => {
+            let address = param_address(params, 0, "address")?;
+            let state = ctx.state.lock().unwrap();
+            match state.as_accounts().and_then(|state| state.contract(&address)) {
+                Some(contract) => Ok(json!(contract)),
+                None => Err(RpcError::invalid_params(format!("{} is not a contract", address))),
+            }
+        }
+        "getOracleFeed" => {
+            let id = param_str(params, 0, "feed")?;
+            let state = ctx.state.lock().unwrap();
+            match state.as_accounts().and_then(|state| state.feed(id)) {
+                Some(feed) => Ok(json!(feed)),
+                None => Err(RpcError::invalid_params(format!("no feed {}", id))),
+            }
+        }
+        "callContract" => {
+            // a read-only call at the tip, by the zero address unless a caller is given
+            let address = param_address(params, 0, "contract")?;
+            let args: Vec<u64> = serde_json::from_value(param(params, 1, "args")?.clone())
+                .map_err(|e| RpcError::invalid_params(format!("error parsing args: {}", e)))?;
+            let caller = match param(params, 2, "caller") {
+                Ok(_) => param_address(params, 2, "caller")?,
+                Err(_) => Address::default(),
+            };
+            let state = ctx.state.lock().unwrap();
+            let state = state
+                .as_accounts()
+                .ok_or_else(|| RpcError::invalid_params("a UTXO chain has no contracts"))?;
+            match state.call(&address, caller, &args) {
+                Ok(outcome) => Ok(json!(outcome)),
+                Err(e) => Err(RpcError::invalid_params(e)),
+            }
+        }
+        "multisigNew" => {
+            let policy: MultisigPolicy = serde_json::from_value(param(params, 0, "policy")?.clone())
+                .map_err(|e| RpcError::invalid_params(format!("error parsing policy: {}", e)))?;
+            let to = param_address(params, 1, "to")?;
+            let value = param_u64(params, 2, "value")?;
+            let validity = param_validity(params, 3)?;
+            let mut wallet = ctx.wallet.lock().unwrap();
+            let state = ctx.state.lock().unwrap();
+            let tx = wallet
+                .multisig_transfer(policy, &to, value, validity, &*state)
+                .map_err(RpcError::invalid_params)?;
+            Ok(json!(LedgerTransaction::from(tx)))
+        }
+        "multisigSign" => {
+            let mut tx = MultisigTransaction::decode(param_str(params, 0, "transaction")?)
+                .map_err(RpcError::invalid_params)?;
+            ctx.wallet.lock().unwrap().sign_multisig(&mut tx).map_err(RpcError::invalid_params)?;
+            Ok(json!(LedgerTransaction::from(tx)))
+        }
+        "getPeers" => Ok(json!(ctx.network.peers())),
+        "connectPeer" => {
+            let addr = param_socket_addr(params, 0, "addr")?;
+            match ctx.network.connect(addr) {
+                Ok(_) => Ok(json!(true)),
+                Err(e) => Err(RpcError::new(INTERNAL_ERROR, format!("error connecting to {}: {}", addr, e))),
+            }
+        }
+        "disconnectPeer" => {
+            let addr = param_socket_addr(params, 0, "addr")?;
+            Ok(json!(ctx.network.disconnect(addr)))
+        }
+        "ping" => {
+            ctx.network.broadcast(Message::Ping(String::from("Test ping")));
+            Ok(json!(true))
+        }
+        "minerStart" => {
+            let lambda = param_u64(params, 0, "lambda")?;
+            if ctx.miner.is_stopped() {
+                return Err(RpcError::new(INTERNAL_ERROR, "miner is stopped"));
+            }
+            if ctx.blockchain.lock().unwrap().light {
+                return Err(RpcError::new(INTERNAL_ERROR, "a light node cannot mine"));
+            }
+            ctx.miner.start(lambda);
+            Ok(json!(true))
+        }
+        "minerPause" => {
+            ctx.miner.pause();
+            Ok(json!(true))
+        }
+        "minerStop" => {
+            ctx.miner.exit();
+            Ok(json!(true))
+        }
+        "minerStatus" => Ok(json!(ctx.miner.status())),
+        "txGeneratorStart" => {
+            // a workload object, or the theta the generator used to take
+            let workload = match param(params, 0, "workload")? {
+                Value::Number(_) => {
+                    let theta = param_u64(params, 0, "theta")?;
+                    Workload::from_params(&std::iter::once(("theta".to_string(), theta.to_string())).collect())
+                }
+                workload => Workload::from_value(workload.clone()),
+            }
+            .map_err(RpcError::invalid_params)?;
+            if ctx.generator.is_stopped() {
+                return Err(RpcError::new(INTERNAL_ERROR, "tx generator is stopped"));
+            }
+            if ctx.blockchain.lock().unwrap().light {
+                return Err(RpcError::new(INTERNAL_ERROR, "a light node has no state to generate transactions from"));
+            }
+            if ctx.state.lock().unwrap().as_accounts().is_none() {
+                return Err(RpcError::new(INTERNAL_ERROR, "the tx generator only makes account transactions"));
+            }
+            ctx.generator.start(workload);
+            Ok(json!(true))
+        }
+        "txGeneratorPause" => {
+            ctx.generator.pause();
+            Ok(json!(true))
+        }
+        "txGeneratorStop" => {
+            ctx.generator.exit();
+            Ok(json!(true))
+        }
+        "txGeneratorStatus" => Ok(json!(ctx.generator.status())),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method {} not found", method))),
+    }
+}
+
+fn block_json(block: &Block, height: usize) -> Value {
+    json!({
+        "hash": block.hash().to_string(),
+        "height": height,
+        "parent": block.header.parent.to_string(),
+        "nonce": block.header.nonce,
+        "difficulty": block.header.difficulty.to_string(),
+        "timestamp": block.header.timestamp.to_string(),
+        "merkle_root": block.header.merkle_root.to_string(),
+        "state_root": block.header.state_root.to_string(),
+        "transactions": block.get_transactions(),
+        "transaction_details": block.content.content.iter().map(|tx| tx.summary()).collect::<Vec<_>>(),
+    })
+}
+
+/// Look up a parameter either by position (array params) or by name (object params).
+fn param<'a>(params: &'a Value, index: usize, name: &str) -> Result<&'a Value, RpcError> {
+    let value = match params {
+        Value::Array(v) => v.get(index),
+        Value::Object(m) => m.get(name),
+        _ => None,
+    };
+    value.ok_or_else(|| RpcError::invalid_params(format!("missing {}", name)))
+}
+
+fn param_str<'a>(params: &'a Value, index: usize, name: &str) -> Result<&'a str, RpcError> {
+    param(params, index, name)?
+        .as_str()
+        .ok_or_else(|| RpcError::invalid_params(format!("{} must be a string", name)))
+}
+
+fn param_u64(params: &Value, index: usize, name: &str) -> Result<u64, RpcError> {
+    param(params, index, name)?
+        .as_u64()
+        .ok_or_else(|| RpcError::invalid_params(format!("{} must be a non-negative integer", name)))
+}
+
+fn param_hash(params: &Value, index: usize, name: &str) -> Result<H256, RpcError> {
+    param_str(params, index, name)?
+        .parse::<H256>()
+        .map_err(|e| RpcError::invalid_params(format!("error parsing {}: {}", name, e)))
+}
+
+fn param_address(params: &Value, index: usize, name: &str) -> Result<Address, RpcError> {
+    param_str(params, index, name)?
+        .parse::<Address>()
+        .map_err(|e| RpcError::invalid_params(format!("error parsing {}: {}", name, e)))
+}
+
+/// An optional window of validity, as an object with any of the fields of `Validity`
+fn param_validity(params: &Value, index: usize) -> Result<Validity, RpcError> {
+    match param(params, index, "validity") {
+        Ok(value) => serde_json::from_value(value.clone())
+            .map_err(|e| RpcError::invalid_params(format!("error parsing validity: {}", e))),
+        Err(_) => Ok(Validity::default()),
+    }
+}
+
+fn param_socket_addr(params: &Value, index: usize, name: &str) -> Result<std::net::SocketAddr, RpcError> {
+    param_str(params, index, name)?
+        .parse::<std::net::SocketAddr>()
+        .map_err(|e| RpcError::invalid_params(format!("error parsing {}: {}", name, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::types::transaction::generate_address;
+
+    fn test_context() -> Context {
+        let (network, _) = NetworkServerHandle::new_for_test();
+        let spec = ChainSpec::default();
+        let state = crate::types::ledger::new(&spec);
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(&spec, &state)));
+        let trans_memopool = Arc::new(Mutex::new(TransactionMemopool::new()));
+        let (_, miner, _) = crate::miner::new(&blockchain, &trans_memopool, &state, generate_address());
+        let wallet = Arc::new(Mutex::new(Wallet::in_memory()));
+        let (_, generator) = crate::tx_generator::new(&network, &state, &trans_memopool, &wallet, Address::from([0; 20]));
+        Context { miner, network, blockchain, generator, state, trans_memopool, wallet, admin: false }
+    }
+
+    #[test]
+    fn parse_error() {
+        let ctx = test_context();
+        let resp = handle(&ctx, "{not json").unwrap();
+        assert_eq!(resp["error"]["code"], json!(PARSE_ERROR));
+        assert_eq!(resp["id"], Value::Null);
+    }
+
+    #[test]
+    fn unknown_method_and_bad_params() {
+        let ctx = test_context();
+        let resp = handle(&ctx, r#"{"jsonrpc":"2.0","method":"noSuchMethod","id":1}"#).unwrap();
+        assert_eq!(resp["error"]["code"], json!(METHOD_NOT_FOUND));
+        let resp = handle(&ctx, r#"{"jsonrpc":"2.0","method":"getBlock","params":["zz"],"id":2}"#).unwrap();
+        assert_eq!(resp["error"]["code"], json!(INVALID_PARAMS));
+        assert_eq!(resp["id"], json!(2));
+        let resp = handle(&ctx, r#"{"jsonrpc":"2.0","method":"minerStart","params":[0],"id":3}"#).unwrap();
+        assert_eq!(resp["error"]["code"], json!(UNAUTHORIZED));
+    }
+
+    #[test]
+    fn batch_with_notification() {
+        let ctx = test_context();
+        let genesis = ctx.blockchain.lock().unwrap().tip().to_string();
+        let body = r#"[
+            {"jsonrpc":"2.0","method":"getBlockCount","id":"a"},
+            {"jsonrpc":"2.0","method":"getBestBlockHash"},
+            {"jsonrpc":"2.0","method":"getBlockHash","params":{"height":0},"id":"b"},
+            1
+        ]"#;
+        let resp = handle(&ctx, body).unwrap();
+        let resp = resp.as_array().unwrap();
+        assert_eq!(resp.len(), 3);
+        assert_eq!(resp[0]["result"], json!(1));
+        assert_eq!(resp[1]["result"], json!(genesis));
+        assert_eq!(resp[2]["error"]["code"], json!(INVALID_REQUEST));
+        assert!(handle(&ctx, r#"[{"jsonrpc":"2.0","method":"getBlockCount"}]"#).is_none());
+    }
+}
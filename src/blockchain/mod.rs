@@ -1,51 +1,101 @@
-use crate::types::block::{Block, Header, Content};
+pub mod spec;
+pub mod store;
+
+use crate::types::block::{Block, Content, Header, CURRENT_VERSION, TAGGED_VERSION};
 use crate::types::hash::{H256, Hashable};
-use std::collections::HashMap;
-use crate::types::merkle::MerkleTree;
-use rand::Rng;
-use hex_literal::hex;
+use crate::types::address::Address;
+use std::collections::{HashMap, HashSet};
+use spec::{ChainSpec, LedgerModel};
+use store::BlockStore;
+use log::{error, info};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use crate::types::transaction::*;
+use crate::types::ledger::{Ledger, TransactionSummary};
+use crate::spv::{AccountProof, ProvenTransaction, TransactionProof};
+use crate::types::transaction::state_tree;
+
+/// How many proven transactions a light chain keeps waiting for their header
+const MAX_PENDING: usize = 1000;
 
 pub struct Blockchain {
     pub block_map: HashMap<H256, Block>,
     pub block_seq: HashMap<H256, usize>,
     pub tip: H256,
-    pub state: Arc<Mutex<State>>,
-
+    pub state: Arc<Mutex<dyn Ledger>>,
+    pub spec: ChainSpec,
+    /// Where accepted blocks are persisted, if anywhere
+    store: Option<BlockStore>,
+    /// A light chain keeps headers only: its blocks have no transactions
+    pub light: bool,
+    /// Light chain: transactions of interest proven to be in a block, by block hash
+    pub proven: HashMap<H256, Vec<ProvenTransaction>>,
+    /// Light chain: proven transactions whose block header has not arrived yet
+    pending: Vec<ProvenTransaction>,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
-        pub fn new(state: &Arc<Mutex<State>>,) -> Self {
-            let mut rng = rand::thread_rng();
-            let parent = [0; 32].into();
-            let nonce = 0u32;
-            let signed_transactions = Vec::new();
-            let timestamp = 0u128; //系统时间
-
-            let merkle_tree = MerkleTree::new(&signed_transactions);
-            let merkle_root: H256 = merkle_tree.root(); //也可以H256类型
-
-            let difficulty: H256 = hex!("0005511111111111111111111111111111111111111111111111111111111111").into();
-            let header = Header{ parent: parent, nonce: nonce, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_root };
-            let content = Content{ content: signed_transactions};
-            let genesis = Block{ header: header, content: content};
-            let genesis_hash = genesis.hash();
-            let mut block_map = HashMap::new();
-            let mut block_seq = HashMap::new();
-            let mut state_locked = state.lock().unwrap();
-            state_locked.update(&genesis.clone());
-            block_map.insert(genesis_hash, genesis);
-            block_seq.insert(genesis_hash, 0);
-            
-            Blockchain {block_map: block_map, block_seq: block_seq, tip: genesis_hash, state: state.clone(),}
+    /// Create a new blockchain, only containing the genesis block of `spec`
+    pub fn new(spec: &ChainSpec, state: &Arc<Mutex<dyn Ledger>>) -> Self {
+        let genesis = spec.genesis();
+        let genesis_hash = genesis.hash();
+        let mut block_map = HashMap::new();
+        let mut block_seq = HashMap::new();
+        let mut state_locked = state.lock().unwrap();
+        state_locked.update(&genesis, 0).expect("the genesis block has no state root");
+        block_map.insert(genesis_hash, genesis);
+        block_seq.insert(genesis_hash, 0);
+
+        Blockchain {block_map, block_seq, tip: genesis_hash, state: state.clone(), spec: spec.clone(), store: None, light: false, proven: HashMap::new(), pending: Vec::new()}
+    }
+
+    /// Create a light blockchain, which only keeps block headers
+    pub fn new_light(spec: &ChainSpec, state: &Arc<Mutex<dyn Ledger>>) -> Self {
+        let mut blockchain = Blockchain::new(spec, state);
+        blockchain.light = true;
+        blockchain
+    }
+
+    /// Create a blockchain persisted in `dir`, replaying the blocks stored there into the state.
+    pub fn open(spec: &ChainSpec, state: &Arc<Mutex<dyn Ledger>>, dir: &Path) -> Result<Self, String> {
+        Blockchain::new(spec, state).load(dir)
+    }
+
+    /// Create a light blockchain persisted in `dir`
+    pub fn open_light(spec: &ChainSpec, state: &Arc<Mutex<dyn Ledger>>, dir: &Path) -> Result<Self, String> {
+        Blockchain::new_light(spec, state).load(dir)
+    }
+
+    fn load(mut self, dir: &Path) -> Result<Self, String> {
+        let (store, blocks) = BlockStore::open(dir, self.tip)?;
+        let state = Arc::clone(&self.state);
+        let mut state = state.lock().unwrap();
+        for block in &blocks {
+            let height = match self.height(&block.get_parent()) {
+                Some(h) => h + 1,
+                None => return Err(format!("stored block {} comes before its parent", block.hash())),
+            };
+            if !self.light {
+                state.update(block, height).map_err(|e| format!("stored block {}: {}", block.hash(), e))?;
+            }
+            self.insert(block);
         }
+        info!("Loaded {} blocks from {}", blocks.len(), dir.display());
+        self.store = Some(store);
+        Ok(self)
+    }
 
     /// Insert a block into blockchain
     pub fn insert(&mut self, block: &Block) {
         let parent = block.get_parent();
         let block_hash = block.hash();
+        if self.block_map.contains_key(&block_hash) {
+            return;
+        }
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.append(block) {
+                error!("Error storing block {}: {}", block_hash, e);
+            }
+        }
         self.block_map.insert(block_hash, block.clone());
         self.block_seq.insert(block_hash, self.block_seq[&parent] + 1);
         if self.block_seq[&block_hash] > self.block_seq[&self.tip] {
@@ -53,6 +103,89 @@ impl Blockchain {
         }
     }
 
+    /// Check the version of a block whose parent is in the chain. Versions never go down, no
+    /// block above genesis has the legacy Merkle scheme, under which two transaction lists can
+    /// have the same root, and blocks above `current_version_height` of the spec have the current
+    /// version, so none can leave out the state root.
+    pub fn check_version(&self, header: &Header) -> Result<(), String> {
+        let parent = &self.block_map[&header.parent].header;
+        let height = self.block_seq[&header.parent] as u64 + 1;
+        if header.merkle_scheme().is_none() {
+            return Err(format!("unknown block version {}", header.version));
+        }
+        if header.version < parent.version {
+            return Err(format!("version {} is below the version {} of its parent", header.version, parent.version));
+        }
+        if header.version < TAGGED_VERSION {
+            return Err(format!("blocks above genesis must have version {} or later", TAGGED_VERSION));
+        }
+        if height > self.spec.current_version_height && header.version != CURRENT_VERSION {
+            return Err(format!(
+                "blocks above height {} must have version {}",
+                self.spec.current_version_height, CURRENT_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Insert the header of a block into a light blockchain, then accept the pending transactions
+    /// proven to be in it.
+    pub fn insert_header(&mut self, header: &Header) {
+        let hash = header.hash();
+        self.insert(&Block {
+            header: header.clone(),
+            content: Content { content: Vec::new() },
+        });
+        let (ready, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|p| p.proof.block_hash == hash);
+        self.pending = pending;
+        for proven in ready {
+            info!("Transaction {} proven in block {}", proven.proof.tx_hash, hash);
+            self.proven.entry(hash).or_default().push(proven);
+        }
+    }
+
+    /// Accept a transaction proven to be in a block. Returns whether the block is known already;
+    /// if not, the transaction is kept until its header is inserted.
+    pub fn add_proven(&mut self, proven: ProvenTransaction) -> Result<bool, String> {
+        proven.check()?;
+        let block_hash = proven.proof.block_hash;
+        let tx_hash = proven.proof.tx_hash;
+        let known = |p: &ProvenTransaction| p.proof.tx_hash == tx_hash && p.proof.block_hash == block_hash;
+        if self.proven.get(&block_hash).is_some_and(|txs| txs.iter().any(known)) {
+            return Ok(true);
+        }
+        if self.block_map.contains_key(&block_hash) {
+            info!("Transaction {} proven in block {}", tx_hash, block_hash);
+            self.proven.entry(block_hash).or_default().push(proven);
+            return Ok(true);
+        }
+        let header = &proven.proof.header;
+        if header.difficulty != self.spec.difficulty || block_hash > header.difficulty {
+            return Err(format!("block {} does not meet the chain difficulty", block_hash));
+        }
+        if !self.pending.iter().any(known) {
+            if self.pending.len() == MAX_PENDING {
+                self.pending.remove(0);
+            }
+            self.pending.push(proven);
+        }
+        Ok(false)
+    }
+
+    /// Flush the block store to disk, if there is one.
+    pub fn sync(&mut self) -> Result<(), String> {
+        match &mut self.store {
+            Some(store) => store.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Height of a block, the genesis block being at height 0
+    pub fn height(&self, hash: &H256) -> Option<u64> {
+        self.block_seq.get(hash).map(|h| *h as u64)
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         self.tip
@@ -71,23 +204,142 @@ impl Blockchain {
         return chain;
     }
 
+    /// Find a transaction in the longest chain and prove it is included in its block
+    pub fn transaction_proof(&self, tx_hash: &H256) -> Option<TransactionProof> {
+        let mut hash = self.tip;
+        loop {
+            let block = &self.block_map[&hash];
+            if self.light {
+                let found = self.proven.get(&hash).and_then(|txs| txs.iter().find(|p| p.proof.tx_hash == *tx_hash));
+                if let Some(proven) = found {
+                    return Some(proven.proof.clone());
+                }
+            }
+            let txs = &block.content.content;
+            if let Some(index) = txs.iter().position(|tx| tx.hash() == *tx_hash) {
+                return Some(TransactionProof {
+                    tx_hash: *tx_hash,
+                    block_hash: hash,
+                    header: block.header.clone(),
+                    index,
+                    leaf_count: txs.len(),
+                    siblings: block.merkle_tree().proof(index),
+                });
+            }
+            if self.block_seq[&hash] == 0 {
+                return None;
+            }
+            hash = block.get_parent();
+        }
+    }
+
+    /// Prove the nonce and balance of an account after a block, by default the tip
+    pub fn account_proof(&self, address: &Address, block: Option<H256>) -> Result<AccountProof, String> {
+        if self.light {
+            return Err("a light node has no account state".to_string());
+        }
+        if self.spec.ledger != LedgerModel::Account {
+            return Err("a UTXO chain has no accounts to prove".to_string());
+        }
+        let hash = block.unwrap_or(self.tip);
+        let header = match self.block_map.get(&hash) {
+            Some(b) => &b.header,
+            None => return Err(format!("unknown block {}", hash)),
+        };
+        if !header.has_state_root() {
+            return Err(format!("block {} has no state root", hash));
+        }
+        let state = self.state.lock().unwrap();
+        let state = state.as_accounts().unwrap();
+        let after = (state.accounts_at(&hash), state.contracts_at(&hash), state.feeds_at(&hash));
+        let (accounts, contracts, feeds) = match after {
+            (Some(accounts), Some(contracts), Some(feeds)) => (accounts, contracts, feeds),
+            _ => return Err(format!("the state of block {} has been pruned", hash)),
+        };
+        // the leaf of a feed is that of an empty account with the hash of the feed
+        let (account, contract) = match feeds.get(address) {
+            Some(feed) => (Some((0, 0)), Some(feed.hash())),
+            None => (accounts.get(address).copied(), contracts.get(address).map(|contract| contract.hash())),
+        };
+        Ok(AccountProof {
+            address: *address,
+            account,
+            contract,
+            block_hash: hash,
+            header: header.clone(),
+            proof: state_tree(&accounts, &contracts, &feeds).proof(address),
+        })
+    }
+
+    /// Proofs for the transactions of a block that involve one of `addresses`
+    pub fn filter_block(&self, hash: &H256, addresses: &HashSet<Address>) -> Vec<ProvenTransaction> {
+        let block = match self.block_map.get(hash) {
+            Some(b) => b,
+            None => return Vec::new(),
+        };
+        let txs = &block.content.content;
+        let matches: Vec<usize> = (0..txs.len())
+            .filter(|i| txs[*i].addresses().iter().any(|a| addresses.contains(a)))
+            .collect();
+        if matches.is_empty() {
+            return Vec::new();
+        }
+        let tree = block.merkle_tree();
+        matches
+            .into_iter()
+            .map(|index| ProvenTransaction {
+                transaction: txs[index].clone(),
+                proof: TransactionProof {
+                    tx_hash: txs[index].hash(),
+                    block_hash: *hash,
+                    header: block.header.clone(),
+                    index,
+                    leaf_count: txs.len(),
+                    siblings: tree.proof(index),
+                },
+            })
+            .collect()
+    }
+
     pub fn all_transactions_in_longest_chain(&self) -> Vec<Vec<String>> {
         let mut chain = Vec::new();
         let mut hash = self.tip;
         while self.block_seq[&hash] != 0 {
-            chain.push(self.block_map[&hash].get_transactions());
+            chain.push(self.transactions_of(&hash));
             hash = self.block_map[&hash].get_parent();
         }
-        chain.push(self.block_map[&hash].get_transactions());
+        chain.push(self.transactions_of(&hash));
         chain.reverse();
         return chain;
     }
+    /// What the explorer shows of the transactions of each block of the longest chain, from the
+    /// genesis block on. A light chain only has the hashes of those proven to it.
+    pub fn transaction_summaries_in_longest_chain(&self) -> Option<Vec<Vec<TransactionSummary>>> {
+        if self.light {
+            return None;
+        }
+        let chain = self
+            .all_blocks_in_longest_chain()
+            .iter()
+            .map(|hash| self.block_map[hash].content.content.iter().map(|tx| tx.summary()).collect())
+            .collect();
+        Some(chain)
+    }
+
+    /// Hashes of the transactions of a block, or for a light chain those proven to be in it
+    fn transactions_of(&self, hash: &H256) -> Vec<String> {
+        if self.light {
+            let proven = self.proven.get(hash).map(|v| v.as_slice()).unwrap_or(&[]);
+            return proven.iter().map(|p| p.proof.tx_hash.to_string()).collect();
+        }
+        self.block_map[hash].get_transactions()
+    }
+
     // through account history
     pub fn get_accounts_by_block_number(&self, block_hash: H256) -> Vec<String> {
         let state = self.state.lock().unwrap();
         let mut accounts: Vec<String> = Vec::new();
-        let accounts_his = &state.history;
-        let a = accounts_his.get(&block_hash);
+        let a = state.accounts_at(&block_hash);
         let b = match a {
             Some(v) => v,
             None => {
@@ -0,0 +1,274 @@
+use crate::types::address::Address;
+use crate::types::block::{Block, Content, Header, LEGACY_VERSION};
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::ledger::LedgerTransaction;
+use crate::types::transaction::Amount;
+use hex_literal::hex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Parameters that every node of a network must agree on. The genesis block commits to the hash of
+/// the spec, so the genesis hash identifies the network.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChainSpec {
+    pub name: String,
+    /// Milliseconds since the Unix epoch
+    pub genesis_timestamp: u64,
+    /// Proof-of-work target: a block is valid if its hash is at most this
+    pub difficulty: H256,
+    /// Balances that exist from the genesis block on
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+    pub max_block_transactions: usize,
+    /// Maximum bincode size of a block, in bytes
+    pub max_block_size: usize,
+    /// Expected time between blocks, in milliseconds. It is reported to API clients; the
+    /// difficulty is fixed for now.
+    pub target_block_time: u64,
+    pub block_reward: RewardSchedule,
+    /// Left out of the spec hash when it is the default, so older specs keep their genesis block
+    #[serde(default, skip_serializing_if = "LedgerModel::is_account")]
+    pub ledger: LedgerModel,
+    /// Least fee an account transfer pays per byte of its size to get into the mempool. Left out
+    /// of the spec hash when it is zero, like `ledger`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fee_per_byte: Amount,
+    /// Least fee a contract transaction pays per unit of its gas limit, on top of the fee for its
+    /// size. Left out of the spec hash when it is zero.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fee_per_gas: Amount,
+    /// Addresses of the keys whose oracle reports the chain takes. Left out of the spec hash
+    /// when there are none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub oracles: Vec<Address>,
+    /// Height up to which blocks may have an older version than `CURRENT_VERSION`, which commits
+    /// to the state after the block. Left out of the spec hash when it is zero, so every block
+    /// above genesis must have the current version.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub current_version_height: u64,
+}
+
+fn is_zero(amount: &Amount) -> bool {
+    *amount == 0
+}
+
+/// How a chain keeps balances
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerModel {
+    /// Accounts with a nonce and a balance, changed by `SignedTransaction`s
+    #[default]
+    Account,
+    /// Unspent outputs, spent and created by `UtxoTransaction`s
+    Utxo,
+}
+
+impl LedgerModel {
+    pub fn is_account(&self) -> bool {
+        *self == LedgerModel::Account
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Allocation {
+    pub address: Address,
+    pub balance: Amount,
+}
+
+/// The reward paid to the beneficiary of each block: `initial`, halved every `halving_interval`
+/// blocks. The genesis block pays nothing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RewardSchedule {
+    pub initial: Amount,
+    /// Zero means the reward never changes
+    pub halving_interval: u64,
+}
+
+impl RewardSchedule {
+    pub fn at(&self, height: u64) -> Amount {
+        if height == 0 {
+            return 0;
+        }
+        if self.halving_interval == 0 {
+            return self.initial;
+        }
+        let halvings = (height - 1) / self.halving_interval;
+        self.initial.checked_shr(halvings.min(u32::MAX as u64) as u32).unwrap_or(0)
+    }
+}
+
+impl Default for ChainSpec {
+    /// A development network with the parameters this client used before chain specs existed
+    fn default() -> Self {
+        ChainSpec {
+            name: "dev".to_string(),
+            genesis_timestamp: 0,
+            difficulty: hex!("0005511111111111111111111111111111111111111111111111111111111111").into(),
+            allocations: Vec::new(),
+            max_block_transactions: 200,
+            max_block_size: 1 << 20,
+            target_block_time: 10_000,
+            block_reward: RewardSchedule::default(),
+            ledger: LedgerModel::Account,
+            fee_per_byte: 0,
+            fee_per_gas: 0,
+            oracles: Vec::new(),
+            current_version_height: 0,
+        }
+    }
+}
+
+impl Hashable for ChainSpec {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &data).into()
+    }
+}
+
+impl ChainSpec {
+    /// Read a JSON chain spec.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading chain spec {}: {}", path.display(), e))?;
+        let spec: ChainSpec = serde_json::from_str(&data)
+            .map_err(|e| format!("error parsing chain spec {}: {}", path.display(), e))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_block_transactions == 0 || self.max_block_size == 0 {
+            return Err("block limits must be positive".to_string());
+        }
+        if self.fee_per_byte > 0 && !self.ledger.is_account() {
+            return Err("fee_per_byte only applies to account chains".to_string());
+        }
+        if self.fee_per_gas > 0 && !self.ledger.is_account() {
+            return Err("fee_per_gas only applies to account chains".to_string());
+        }
+        if !self.oracles.is_empty() && !self.ledger.is_account() {
+            return Err("oracles only apply to account chains".to_string());
+        }
+        for (i, oracle) in self.oracles.iter().enumerate() {
+            if self.oracles[..i].contains(oracle) {
+                return Err(format!("oracle {} is listed twice", oracle));
+            }
+        }
+        // amounts are unsigned, so negative ones do not parse; they only have to add up
+        let mut total: Amount = 0;
+        for (i, allocation) in self.allocations.iter().enumerate() {
+            total = total
+                .checked_add(allocation.balance)
+                .ok_or_else(|| "the allocations add up to more than the largest amount".to_string())?;
+            if self.allocations[..i].iter().any(|a| a.address == allocation.address) {
+                return Err(format!("{} is allocated twice", allocation.address));
+            }
+        }
+        Ok(())
+    }
+
+    /// The genesis block, whose parent field holds the hash of the spec. It keeps the legacy block
+    /// version, so the genesis hash of existing networks does not change.
+    pub fn genesis(&self) -> Block {
+        let signed_transactions: Vec<LedgerTransaction> = Vec::new();
+        let merkle_root = MerkleTree::new(&signed_transactions).root();
+        let header = Header {
+            version: LEGACY_VERSION,
+            parent: self.hash(),
+            nonce: 0,
+            difficulty: self.difficulty,
+            timestamp: self.genesis_timestamp as u128,
+            merkle_root,
+            state_root: H256::default(),
+            beneficiary: Address::default(),
+        };
+        Block {
+            header,
+            content: Content {
+                content: signed_transactions,
+            },
+        }
+    }
+
+    /// Take transactions, in order, while the block stays within the limits.
+    pub fn select_transactions<'a>(
+        &self,
+        candidates: impl Iterator<Item = &'a LedgerTransaction>,
+    ) -> Vec<LedgerTransaction> {
+        // an empty block: the fixed-size header and the length of the transaction list
+        let mut size = bincode::serialized_size(&self.genesis()).unwrap() as usize;
+        let mut selected = Vec::new();
+        for tx in candidates {
+            if selected.len() == self.max_block_transactions {
+                break;
+            }
+            let tx_size = bincode::serialized_size(tx).unwrap() as usize;
+            if size + tx_size > self.max_block_size {
+                continue;
+            }
+            size += tx_size;
+            selected.push(tx.clone());
+        }
+        selected
+    }
+
+    /// Whether a block respects the size limits
+    pub fn check_limits(&self, block: &Block) -> Result<(), String> {
+        let count = block.content.content.len();
+        if count > self.max_block_transactions {
+            return Err(format!("{} transactions, limit is {}", count, self.max_block_transactions));
+        }
+        let size = bincode::serialized_size(block).unwrap() as usize;
+        if size > self.max_block_size {
+            return Err(format!("{} bytes, limit is {}", size, self.max_block_size));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_identifies_network() {
+        let dev = ChainSpec::default();
+        let mut other = ChainSpec::default();
+        other.allocations.push(Allocation {
+            address: [1; 20].into(),
+            balance: 10,
+        });
+        assert_eq!(dev.genesis().hash(), ChainSpec::default().genesis().hash());
+        assert_ne!(dev.genesis().hash(), other.genesis().hash());
+
+        let json = serde_json::to_string(&other).unwrap();
+        let parsed: ChainSpec = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.genesis().hash(), other.genesis().hash());
+        assert!(!json.contains("ledger"));
+
+        let utxo = ChainSpec {
+            ledger: LedgerModel::Utxo,
+            ..ChainSpec::default()
+        };
+        assert_ne!(utxo.genesis().hash(), dev.genesis().hash());
+        let parsed: ChainSpec = serde_json::from_str(&serde_json::to_string(&utxo).unwrap()).unwrap();
+        assert_eq!(parsed.ledger, LedgerModel::Utxo);
+    }
+
+    #[test]
+    fn reward_halving() {
+        let schedule = RewardSchedule {
+            initial: 50,
+            halving_interval: 10,
+        };
+        assert_eq!(schedule.at(0), 0);
+        assert_eq!(schedule.at(1), 50);
+        assert_eq!(schedule.at(10), 50);
+        assert_eq!(schedule.at(11), 25);
+        assert_eq!(schedule.at(1000), 0);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::types::block::Block;
+use crate::types::hash::H256;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Start of a block store, followed by its `FORMAT_VERSION` as a little-endian `u32`
+const MAGIC: &[u8; 4] = b"blks";
+/// Version of the encoding of the blocks in a store. It is raised whenever the encoding of blocks
+/// changes, and a store in another version is refused, as its blocks would decode wrong or hash
+/// differently.
+const FORMAT_VERSION: u32 = 1;
+
+/// Append-only log of the blocks a node has accepted, in insertion order (so every block comes
+/// after its parent). After the header, each record is a little-endian `u32` length followed by the
+/// bincode block.
+pub struct BlockStore {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl BlockStore {
+    /// Open the store in `dir`, returning the blocks already in it. The directory remembers the
+    /// genesis block it was created for, so a datadir cannot be reused with another chain spec.
+    pub fn open(dir: &Path, genesis: H256) -> Result<(Self, Vec<Block>), String> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("error creating {}: {}", dir.display(), e))?;
+        let path = dir.join("blocks.dat");
+        let mut data = Vec::new();
+        if path.exists() {
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut data))
+                .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+        }
+        if data.is_empty() {
+            data = header();
+            std::fs::write(&path, &data).map_err(|e| format!("error writing {}: {}", path.display(), e))?;
+        } else if !data.starts_with(&header()) {
+            // checked before the genesis block, as the spec hash of the chain may have changed too
+            return Err(format!(
+                "{} was not written in block format {}, the only one this version reads; remove {} \
+                 to sync the chain again",
+                path.display(),
+                FORMAT_VERSION,
+                dir.display()
+            ));
+        }
+
+        let genesis_path = dir.join("genesis");
+        match std::fs::read_to_string(&genesis_path) {
+            Ok(stored) if stored.trim() == genesis.to_string() => {}
+            Ok(stored) => {
+                return Err(format!(
+                    "{} holds chain {}, not {}",
+                    dir.display(),
+                    stored.trim(),
+                    genesis
+                ))
+            }
+            Err(_) => std::fs::write(&genesis_path, genesis.to_string())
+                .map_err(|e| format!("error writing {}: {}", genesis_path.display(), e))?,
+        }
+
+        let header_len = header().len();
+        let (blocks, valid_len) = decode_records(&data[header_len..]);
+        let valid_len = header_len + valid_len;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| format!("error opening {}: {}", path.display(), e))?;
+        if valid_len < data.len() {
+            // a record was cut short, e.g. by a crash in the middle of a write
+            warn!("Dropping {} bytes of incomplete data at the end of {}", data.len() - valid_len, path.display());
+        }
+        file.set_len(valid_len as u64)
+            .map_err(|e| format!("error truncating {}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        std::io::Seek::seek(&mut writer, std::io::SeekFrom::End(0))
+            .map_err(|e| format!("error seeking {}: {}", path.display(), e))?;
+        Ok((BlockStore { path, writer }, blocks))
+    }
+
+    pub fn append(&mut self, block: &Block) -> Result<(), String> {
+        let mut data = Vec::new();
+        encode_record(&mut data, block);
+        self.writer
+            .write_all(&data)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("error writing {}: {}", self.path.display(), e))
+    }
+
+    /// Make sure everything appended so far is on disk.
+    pub fn sync(&mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .and_then(|_| self.writer.get_ref().sync_all())
+            .map_err(|e| format!("error syncing {}: {}", self.path.display(), e))
+    }
+
+}
+
+/// `MAGIC` and `FORMAT_VERSION`, which every store starts with
+fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+fn encode_record(out: &mut Vec<u8>, block: &Block) {
+    let data = bincode::serialize(block).unwrap();
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// Decode complete records, returning the blocks and the length of the data they span
+fn decode_records(data: &[u8]) -> (Vec<Block>, usize) {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while data.len() >= offset + 4 {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&data[offset..offset + 4]);
+        let end = offset + 4 + u32::from_le_bytes(len) as usize;
+        if data.len() < end {
+            break;
+        }
+        match bincode::deserialize(&data[offset + 4..end]) {
+            Ok(block) => blocks.push(block),
+            Err(_) => break,
+        }
+        offset = end;
+    }
+    (blocks, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::{generate_random_hash, Hashable};
+
+    #[test]
+    fn reopen_after_torn_write() {
+        let dir = std::env::temp_dir().join(format!("block-store-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let genesis = generate_random_hash();
+        let b1 = generate_random_block(&genesis);
+        let b2 = generate_random_block(&b1.hash());
+        {
+            let (mut store, blocks) = BlockStore::open(&dir, genesis).unwrap();
+            assert!(blocks.is_empty());
+            store.append(&b1).unwrap();
+            store.append(&b2).unwrap();
+        }
+        // cut the last record in half
+        let path = dir.join("blocks.dat");
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 10).unwrap();
+
+        let (mut store, blocks) = BlockStore::open(&dir, genesis).unwrap();
+        assert_eq!(blocks.iter().map(|b| b.hash()).collect::<Vec<_>>(), vec![b1.hash()]);
+        store.append(&b2).unwrap();
+        drop(store);
+        let (_, blocks) = BlockStore::open(&dir, genesis).unwrap();
+        assert_eq!(blocks.len(), 2);
+
+        assert!(BlockStore::open(&dir, generate_random_hash()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_old_store() {
+        let dir = std::env::temp_dir().join(format!("block-store-old-{}", std::process::id()));
+        let mut newer = MAGIC.to_vec();
+        newer.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        // a store from before the format version, and one in another version
+        for old in [b"blk7".to_vec(), newer] {
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let genesis = generate_random_hash();
+            std::fs::write(dir.join("genesis"), generate_random_hash().to_string()).unwrap();
+            std::fs::write(dir.join("blocks.dat"), &old).unwrap();
+            let err = BlockStore::open(&dir, genesis).err().unwrap();
+            assert!(err.contains("block format"), "{}", err);
+            assert_eq!(std::fs::read(dir.join("blocks.dat")).unwrap(), old);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logs to standard error in the same format as `stderrlog`, and appends timestamped lines to a
+/// file in the datadir.
+struct Logger {
+    level: LevelFilter,
+    file: Mutex<File>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        eprintln!("{} - {}", record.level(), record.args());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(
+            file,
+            "{}.{:03} {} {} - {}",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+/// The level `stderrlog` uses for a number of `-v` flags
+fn level(verbosity: usize) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Set up logging to standard error, and to `file` if given.
+pub fn init(verbosity: usize, file: Option<&Path>) -> Result<(), String> {
+    let path = match file {
+        Some(p) => p,
+        None => {
+            stderrlog::new().verbosity(verbosity).init().unwrap();
+            return Ok(());
+        }
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("error creating {}: {}", dir.display(), e))?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("error opening log file {}: {}", path.display(), e))?;
+    let level = level(verbosity);
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        file: Mutex::new(file),
+    }))
+    .map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(())
+}
+
@@ -0,0 +1,649 @@
+pub mod logger;
+
+use crate::config::{self, NodeConfig};
+use crate::types::address::Address;
+use crate::types::batch::{BatchTransaction, Payment, SignedBatch};
+use crate::types::contract::{ContractAction, ContractTransaction, SignedContractTransaction};
+use crate::types::hash::Hashable;
+use crate::blockchain::spec::LedgerModel;
+use crate::types::key_pair;
+use crate::types::ledger::LedgerTransaction;
+use crate::types::multisig::{MultisigPolicy, MultisigTransaction, PartialSignature};
+use crate::types::oracle::{self, OracleReport, SignedOracleReport};
+use crate::types::transaction::{sign_transaction, Amount, Transaction, Validity};
+use crate::wallet::{self, Wallet};
+use clap::{App, ArgMatches, SubCommand};
+use log::error;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable that can hold the keystore passphrase instead of `--passphrase-file`
+pub const PASSPHRASE_ENV: &str = "BITCOIN_KEYSTORE_PASSPHRASE";
+
+/// Build the node configuration: the config file (`--config`, or `config.json` in the datadir),
+/// then `BITCOIN_*` environment variables, then command line flags. Logging is not set up yet when
+/// this runs, so errors are returned rather than logged.
+pub fn node_config(matches: &ArgMatches) -> Result<NodeConfig, String> {
+    // the datadir decides where the config file is, so it is resolved first
+    let datadir = matches
+        .value_of("datadir")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var(format!("{}DATADIR", config::ENV_PREFIX)).ok().map(PathBuf::from));
+    let config_file = matches
+        .value_of("config")
+        .map(PathBuf::from)
+        .or_else(|| datadir.as_deref().map(config::config_path));
+    let mut config = match config_file {
+        Some(path) => NodeConfig::load(&path)?,
+        None => NodeConfig::default(),
+    };
+    config.apply_env()?;
+
+    fn parse<T: std::str::FromStr>(matches: &ArgMatches, name: &str, what: &str) -> Result<Option<T>, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        match matches.value_of(name) {
+            Some(v) => v.parse().map(Some).map_err(|e| format!("error parsing {}: {}", what, e)),
+            None => Ok(None),
+        }
+    }
+    if datadir.is_some() {
+        config.datadir = datadir;
+    }
+    if let Some(addr) = parse(matches, "peer_addr", "P2P server address")? {
+        config.p2p_addr = addr;
+    }
+    if let Some(addr) = parse(matches, "api_addr", "API server address")? {
+        config.api_addr = addr;
+    }
+    if let Some(peers) = matches.values_of("known_peer") {
+        config.connect = peers.map(|p| p.to_string()).collect();
+    }
+    if let Some(workers) = parse(matches, "p2p_workers", "P2P workers")? {
+        config.p2p_workers = workers;
+    }
+    if matches.occurrences_of("verbose") > 0 {
+        config.verbosity = matches.occurrences_of("verbose") as usize;
+    }
+    for (arg, field) in [
+        ("chain_spec", &mut config.chain_spec),
+        ("keystore", &mut config.keystore),
+        ("passphrase_file", &mut config.passphrase_file),
+        ("api_admin_token_file", &mut config.api_admin_token_file),
+        ("api_read_token_file", &mut config.api_read_token_file),
+    ] {
+        if let Some(v) = matches.value_of(arg) {
+            *field = Some(PathBuf::from(v));
+        }
+    }
+    if let Some(limit) = parse(matches, "api_rate_limit", "API rate limit")? {
+        config.api_rate_limit = Some(limit);
+    }
+    if matches.is_present("light") {
+        config.light = true;
+    }
+    if let Some(depth) = parse(matches, "state_history_depth", "state history depth")? {
+        config.state_history_depth = Some(depth);
+    }
+    Ok(config)
+}
+
+fn passphrase(config: &NodeConfig) -> String {
+    if let Some(path) = &config.passphrase_file {
+        return match std::fs::read_to_string(path) {
+            Ok(p) => p.trim_end_matches(&['\r', '\n'][..]).to_string(),
+            Err(e) => {
+                error!("Error reading passphrase file {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        };
+    }
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(p) => p,
+        Err(_) => {
+            error!("A keystore needs a passphrase: use --passphrase-file or {}", PASSPHRASE_ENV);
+            process::exit(1);
+        }
+    }
+}
+
+/// Open the configured keystore, or an in-memory wallet if there is none.
+pub fn open_wallet(config: &NodeConfig) -> Wallet {
+    let path = match config.keystore_path() {
+        Some(p) => p,
+        None => return Wallet::in_memory(),
+    };
+    Wallet::open(&path, &passphrase(config)).unwrap_or_else(|e| {
+        error!("Error opening wallet: {}", e);
+        process::exit(1);
+    })
+}
+
+/// Key file written by `keygen`. It holds the private key in the clear, so it is meant to stay on
+/// an offline machine (or be imported into a keystore with `wallet import`).
+#[derive(Serialize, Deserialize)]
+pub struct KeyFile {
+    pub address: String,
+    pub public_key: String,
+    pub pkcs8: String,
+}
+
+/// Subcommands that work offline, without starting the node.
+pub fn offline_subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        SubCommand::with_name("keygen")
+            .about("Generates a key pair and prints it as a JSON key file")
+            .args_from_usage("-o, --out [FILE] 'Writes the key file here instead of standard output'"),
+        SubCommand::with_name("address")
+            .about("Prints the address of a public key or key file")
+            .args_from_usage(
+                "--public-key [HEX] 'Hex-encoded Ed25519 public key'
+                 --key [FILE] 'Key file written by keygen'",
+            ),
+        SubCommand::with_name("sign-tx")
+            .about("Signs a transfer with a key file or a keystore account")
+            .args_from_usage(
+                "--key [FILE] 'Signs with this key file'
+                 --from [ADDR] 'Signs with this account of the --keystore'
+                 --to <ADDR> 'Receiver address'
+                 --value <INT> 'Amount to transfer'
+                 --nonce <INT> 'Sender nonce after this transaction, i.e. its current nonce plus one'
+                 --fee [INT] 'Fee for the beneficiary of the block, at least the fee_per_byte of the chain times the size of the transfer; zero if omitted'
+                 --valid-after-height [HEIGHT] 'Only valid in blocks above this height'
+                 --valid-after-timestamp [MS] 'Only valid in blocks with a later timestamp, in milliseconds since the Unix epoch'
+                 --expires-at-height [HEIGHT] 'Only valid in blocks below this height'
+                 --expires-at-timestamp [MS] 'Only valid in blocks with an earlier timestamp'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("batch-tx")
+            .about("Signs payments to several receivers as one transaction, with a key file or a keystore account")
+            .args_from_usage(
+                "--key [FILE] 'Signs with this key file'
+                 --from [ADDR] 'Signs with this account of the --keystore'
+                 --pay <ADDR:INT>... 'A receiver address and the amount it gets'
+                 --nonce <INT> 'Sender nonce after this transaction, i.e. its current nonce plus one'
+                 --fee [INT] 'Fee for the beneficiary of the block, at least the fee_per_byte of the chain times the size of the transfer; zero if omitted'
+                 --valid-after-height [HEIGHT] 'Only valid in blocks above this height'
+                 --valid-after-timestamp [MS] 'Only valid in blocks with a later timestamp, in milliseconds since the Unix epoch'
+                 --expires-at-height [HEIGHT] 'Only valid in blocks below this height'
+                 --expires-at-timestamp [MS] 'Only valid in blocks with an earlier timestamp'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("cancel-tx")
+            .about("Signs a cancel of a pending transfer: nothing sent to the sender at its nonce, for a higher fee")
+            .args_from_usage(
+                "--key [FILE] 'Signs with this key file'
+                 --from [ADDR] 'Signs with this account of the --keystore'
+                 --nonce <INT> 'Nonce of the transfer to cancel'
+                 --fee <INT> 'Fee for the beneficiary of the block, more than that of the transfer to cancel'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("contract-tx")
+            .about("Signs a deploy or call of a contract, with a key file or a keystore account")
+            .args_from_usage(
+                "--key [FILE] 'Signs with this key file'
+                 --from [ADDR] 'Signs with this account of the --keystore'
+                 --deploy [FILE] 'Deploys the code in this file, a JSON list of instructions'
+                 --call [ADDR] 'Calls the contract at this address'
+                 --arg [INT]... 'An argument of the call, in order'
+                 --gas-limit <INT> 'Most gas the deploy or call can use'
+                 --nonce <INT> 'Sender nonce after this transaction, i.e. its current nonce plus one'
+                 --fee [INT] 'Fee for the beneficiary of the block, at least the fee_per_byte of the chain times the size of the transaction plus the fee_per_gas times the gas limit; zero if omitted'
+                 --valid-after-height [HEIGHT] 'Only valid in blocks above this height'
+                 --valid-after-timestamp [MS] 'Only valid in blocks with a later timestamp, in milliseconds since the Unix epoch'
+                 --expires-at-height [HEIGHT] 'Only valid in blocks below this height'
+                 --expires-at-timestamp [MS] 'Only valid in blocks with an earlier timestamp'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("oracle-report")
+            .about("Signs a value observed for a round of an oracle feed, with a key file or a keystore account")
+            .args_from_usage(
+                "--key [FILE] 'Signs with this key file'
+                 --from [ADDR] 'Signs with this account of the --keystore'
+                 --feed <ID> 'Feed ID, such as eth-usd'
+                 --round <INT> 'Round of the feed, above the latest the chain has'
+                 --value <INT> 'Value observed'
+                 --timestamp [MS] 'When the value was observed, in milliseconds since the Unix epoch; now if omitted'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("verify-tx")
+            .about("Checks the signatures of a hex or JSON transaction and prints it")
+            .args_from_usage("[FILE] 'Transaction file, standard input if omitted'"),
+        SubCommand::with_name("multisig-address")
+            .about("Prints the policy and address of a multisig account")
+            .args_from_usage(
+                "--threshold <INT> 'How many of the keys must sign'
+                 --public-key <HEX>... 'Hex-encoded Ed25519 public key of a cosigner'",
+            ),
+        SubCommand::with_name("multisig-tx")
+            .about("Starts a transfer from a multisig account, for its cosigners to sign")
+            .args_from_usage(
+                "--policy <FILE> 'Policy written by multisig-address'
+                 --to <ADDR> 'Receiver address'
+                 --value <INT> 'Amount to transfer'
+                 --nonce <INT> 'Nonce of the multisig account after this transaction'
+                 --fee [INT] 'Fee for the beneficiary of the block, at least the fee_per_byte of the chain times the size of the transfer; zero if omitted'
+                 --valid-after-height [HEIGHT] 'Only valid in blocks above this height'
+                 --valid-after-timestamp [MS] 'Only valid in blocks with a later timestamp, in milliseconds since the Unix epoch'
+                 --expires-at-height [HEIGHT] 'Only valid in blocks below this height'
+                 --expires-at-timestamp [MS] 'Only valid in blocks with an earlier timestamp'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("multisig-sign")
+            .about("Adds a signature to a multisig transfer")
+            .args_from_usage(
+                "[FILE] 'Transaction file, standard input if omitted'
+                 --key [FILE] 'Signs with this key file'
+                 --from [ADDR] 'Signs with this account of the --keystore'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+        SubCommand::with_name("multisig-combine")
+            .about("Merges the signatures of copies of a multisig transfer signed separately")
+            .args_from_usage(
+                "<FILE>... 'Transaction files'
+                 --format [FORMAT] 'Output format'
+                 -o, --out [FILE] 'Writes the transaction here instead of standard output'",
+            ),
+    ]
+}
+
+/// Run a subcommand of the binary instead of the node.
+pub fn run(config: &NodeConfig, name: &str, sub_matches: &ArgMatches) {
+    match name {
+        "wallet" => wallet(config, sub_matches),
+        "keygen" => keygen(sub_matches),
+        "address" => address(sub_matches),
+        "sign-tx" => sign_tx(config, sub_matches),
+        "batch-tx" => batch_tx(config, sub_matches),
+        "cancel-tx" => cancel_tx(config, sub_matches),
+        "contract-tx" => contract_tx(config, sub_matches),
+        "oracle-report" => oracle_report(config, sub_matches),
+        "verify-tx" => verify_tx(sub_matches),
+        "multisig-address" => multisig_address(sub_matches),
+        "multisig-tx" => multisig_tx(sub_matches),
+        "multisig-sign" => multisig_sign(config, sub_matches),
+        "multisig-combine" => multisig_combine(sub_matches),
+        _ => fail(format!("Unknown command {}, see --help", name)),
+    }
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    error!("{}", message);
+    process::exit(1);
+}
+
+/// Print to standard output, or write to `--out` (creating the file with owner-only permissions
+/// since it may hold a key).
+fn output(matches: &ArgMatches, data: &str) {
+    let path = match matches.value_of("out") {
+        Some(p) => p,
+        None => {
+            println!("{}", data);
+            return;
+        }
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let result = options
+        .open(path)
+        .and_then(|mut f| f.write_all(data.as_bytes()).and_then(|_| f.write_all(b"\n")));
+    if let Err(e) = result {
+        fail(format!("Error writing {}: {}", path, e));
+    }
+}
+
+fn read_input(path: Option<&str>) -> String {
+    let mut data = String::new();
+    let result = match path {
+        Some("-") | None => std::io::stdin().read_to_string(&mut data).map(|_| ()),
+        Some(p) => std::fs::read_to_string(p).map(|d| data = d),
+    };
+    if let Err(e) = result {
+        fail(format!("Error reading {}: {}", path.unwrap_or("standard input"), e));
+    }
+    data
+}
+
+fn read_key_file(path: &str) -> Ed25519KeyPair {
+    let key_file: KeyFile = serde_json::from_str(&read_input(Some(path)))
+        .unwrap_or_else(|e| fail(format!("Error parsing key file {}: {}", path, e)));
+    let pkcs8 = hex::decode(&key_file.pkcs8).unwrap_or_else(|e| fail(format!("Error decoding key: {}", e)));
+    wallet::parse_pkcs8(&pkcs8).unwrap_or_else(|e| fail(e))
+}
+
+/// The window of validity given by the `--valid-after-*` and `--expires-at-*` flags
+fn validity(matches: &ArgMatches) -> Validity {
+    let optional = |name| matches.value_of(name).map(|_| parse_arg(matches, name));
+    Validity {
+        valid_after_height: optional("valid-after-height"),
+        valid_after_timestamp: optional("valid-after-timestamp"),
+        expires_at_height: optional("expires-at-height"),
+        expires_at_timestamp: optional("expires-at-timestamp"),
+    }
+}
+
+/// The `--fee`, zero if omitted
+fn fee(matches: &ArgMatches) -> Amount {
+    match matches.value_of("fee") {
+        Some(_) => parse_arg(matches, "fee"),
+        None => 0,
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse()
+        .unwrap_or_else(|e| fail(format!("Error parsing --{} {}: {}", name, value, e)))
+}
+
+/// `keygen` subcommand: create a key without touching any keystore.
+fn keygen(matches: &ArgMatches) {
+    let pkcs8 = key_pair::random_pkcs8();
+    let key = wallet::parse_pkcs8(&pkcs8).unwrap();
+    let key_file = KeyFile {
+        address: wallet::address_of(&key).to_string(),
+        public_key: hex::encode(key.public_key().as_ref()),
+        pkcs8: hex::encode(&pkcs8),
+    };
+    output(matches, &serde_json::to_string_pretty(&key_file).unwrap());
+}
+
+/// `address` subcommand
+fn address(matches: &ArgMatches) {
+    let address = match (matches.value_of("public-key"), matches.value_of("key")) {
+        (Some(public_key), None) => {
+            let bytes = hex::decode(public_key).unwrap_or_else(|e| fail(format!("Error decoding public key: {}", e)));
+            Address::from_public_key_bytes(&bytes)
+        }
+        (None, Some(path)) => wallet::address_of(&read_key_file(path)),
+        _ => fail("Give exactly one of --public-key and --key"),
+    };
+    println!("{}", address);
+}
+
+/// The key given by `--key` or `--from`
+fn signing_key(config: &NodeConfig, sub_matches: &ArgMatches) -> Ed25519KeyPair {
+    match (sub_matches.value_of("key"), sub_matches.value_of("from")) {
+        (Some(path), None) => read_key_file(path),
+        (None, Some(_)) => {
+            if config.keystore_path().is_none() {
+                fail("--from needs --keystore or --datadir");
+            }
+            let from: Address = parse_arg(sub_matches, "from");
+            open_wallet(config)
+                .key_pair(&from)
+                .unwrap_or_else(|| fail(format!("Account {} is not in the keystore", from)))
+        }
+        _ => fail("Give exactly one of --key and --from"),
+    }
+}
+
+/// Print a transaction in the `--format` asked for
+fn output_tx(sub_matches: &ArgMatches, tx: &LedgerTransaction) {
+    match sub_matches.value_of("format").unwrap_or("json") {
+        "json" => output(sub_matches, &tx.to_json()),
+        "hex" => output(sub_matches, &tx.to_hex()),
+        other => fail(format!("Unknown format {}, use json or hex", other)),
+    }
+}
+
+/// `sign-tx` subcommand: the nonce is given explicitly since an offline machine cannot look it up.
+fn sign_tx(config: &NodeConfig, sub_matches: &ArgMatches) {
+    let key = signing_key(config, sub_matches);
+    let to: Address = parse_arg(sub_matches, "to");
+    let value: Amount = parse_arg(sub_matches, "value");
+    let nonce: u32 = parse_arg(sub_matches, "nonce");
+    if value == 0 {
+        fail("--value must be positive");
+    }
+    let transfer = Transaction {
+        nonce,
+        sender: wallet::address_of(&key),
+        receiver: to,
+        value,
+        fee: fee(sub_matches),
+        validity: validity(sub_matches),
+    };
+    output_tx(sub_matches, &sign_transaction(transfer, &key).into());
+}
+
+/// `batch-tx` subcommand: like `sign-tx`, the nonce is given explicitly.
+fn batch_tx(config: &NodeConfig, sub_matches: &ArgMatches) {
+    let key = signing_key(config, sub_matches);
+    let payments = sub_matches
+        .values_of("pay")
+        .unwrap()
+        .map(|pay| {
+            let (receiver, value) = pay
+                .split_once(':')
+                .unwrap_or_else(|| fail(format!("--pay {} is not ADDRESS:VALUE", pay)));
+            let receiver = receiver.parse().unwrap_or_else(|e| fail(format!("Error parsing --pay {}: {}", pay, e)));
+            let value = match value.parse() {
+                Ok(value) if value > 0 => value,
+                Ok(_) => fail(format!("--pay {} must pay a positive value", pay)),
+                Err(e) => fail(format!("Error parsing --pay {}: {}", pay, e)),
+            };
+            Payment { receiver, value }
+        })
+        .collect();
+    let batch = BatchTransaction {
+        nonce: parse_arg(sub_matches, "nonce"),
+        sender: wallet::address_of(&key),
+        payments,
+        fee: fee(sub_matches),
+        validity: validity(sub_matches),
+    };
+    output_tx(sub_matches, &SignedBatch::sign(batch, &key).into());
+}
+
+/// `cancel-tx` subcommand: the mempool only takes the cancel if its fee is higher than that of
+/// the transfer it replaces, which an offline machine cannot look up.
+fn cancel_tx(config: &NodeConfig, sub_matches: &ArgMatches) {
+    let key = signing_key(config, sub_matches);
+    let sender = wallet::address_of(&key);
+    let cancel = Transaction {
+        nonce: parse_arg(sub_matches, "nonce"),
+        sender,
+        receiver: sender,
+        value: 0,
+        fee: parse_arg(sub_matches, "fee"),
+        validity: Validity::default(),
+    };
+    output_tx(sub_matches, &sign_transaction(cancel, &key).into());
+}
+
+/// `contract-tx` subcommand: `verify-tx` prints the address of the contract a deploy creates.
+fn contract_tx(config: &NodeConfig, sub_matches: &ArgMatches) {
+    let key = signing_key(config, sub_matches);
+    let action = match (sub_matches.value_of("deploy"), sub_matches.value_of("call")) {
+        (Some(path), None) => ContractAction::Deploy {
+            code: serde_json::from_str(&read_input(Some(path)))
+                .unwrap_or_else(|e| fail(format!("Error parsing code in {}: {}", path, e))),
+        },
+        (None, Some(_)) => ContractAction::Call {
+            contract: parse_arg(sub_matches, "call"),
+            args: sub_matches
+                .values_of("arg")
+                .into_iter()
+                .flatten()
+                .map(|arg| arg.parse().unwrap_or_else(|e| fail(format!("Error parsing --arg {}: {}", arg, e))))
+                .collect(),
+        },
+        _ => fail("Give one of --deploy or --call"),
+    };
+    let tx = ContractTransaction {
+        nonce: parse_arg(sub_matches, "nonce"),
+        sender: wallet::address_of(&key),
+        action,
+        gas_limit: parse_arg(sub_matches, "gas-limit"),
+        fee: fee(sub_matches),
+        validity: validity(sub_matches),
+    };
+    output_tx(sub_matches, &SignedContractTransaction::sign(tx, &key).into());
+}
+
+/// `oracle-report` subcommand: the key must be one of the oracles of the chain spec.
+fn oracle_report(config: &NodeConfig, sub_matches: &ArgMatches) {
+    let key = signing_key(config, sub_matches);
+    let feed = sub_matches.value_of("feed").unwrap().to_string();
+    oracle::check_feed_id(&feed).unwrap_or_else(|e| fail(e));
+    let timestamp = match sub_matches.value_of("timestamp") {
+        Some(_) => parse_arg(sub_matches, "timestamp"),
+        None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+    };
+    let report = OracleReport {
+        feed,
+        round: parse_arg(sub_matches, "round"),
+        value: parse_arg(sub_matches, "value"),
+        timestamp,
+    };
+    output_tx(sub_matches, &SignedOracleReport::sign(report, &key).into());
+}
+
+/// `verify-tx` subcommand: exits with an error if the signatures do not check out.
+fn verify_tx(matches: &ArgMatches) {
+    let input = read_input(matches.value_of("FILE"));
+    let tx = LedgerTransaction::decode(&input, LedgerModel::Account).unwrap_or_else(|e| fail(e));
+    let valid = tx.verify_signatures();
+    let summary = json!({
+        "hash": tx.hash().to_string(),
+        "valid": valid,
+        "transaction": tx.summary(),
+    });
+    println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+    if !valid {
+        process::exit(1);
+    }
+}
+
+/// `multisig-address` subcommand
+fn multisig_address(matches: &ArgMatches) {
+    let threshold: u32 = parse_arg(matches, "threshold");
+    let public_keys = matches
+        .values_of("public-key")
+        .unwrap()
+        .map(|key| hex::decode(key).unwrap_or_else(|e| fail(format!("Error decoding public key {}: {}", key, e))))
+        .collect();
+    let policy = MultisigPolicy::new(threshold, public_keys).unwrap_or_else(|e| fail(e));
+    let summary = json!({
+        "address": policy.address().to_string(),
+        "threshold": policy.threshold,
+        "public_keys": policy.public_keys.iter().map(hex::encode).collect::<Vec<_>>(),
+    });
+    println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+}
+
+/// `multisig-tx` subcommand: like `sign-tx`, the nonce is given explicitly.
+fn multisig_tx(matches: &ArgMatches) {
+    let path = matches.value_of("policy").unwrap();
+    let policy: MultisigPolicy = serde_json::from_str(&read_input(Some(path)))
+        .unwrap_or_else(|e| fail(format!("Error parsing policy {}: {}", path, e)));
+    let value: Amount = parse_arg(matches, "value");
+    if value == 0 {
+        fail("--value must be positive");
+    }
+    let transfer = Transaction {
+        nonce: parse_arg(matches, "nonce"),
+        sender: policy.address(),
+        receiver: parse_arg(matches, "to"),
+        value,
+        fee: fee(matches),
+        validity: validity(matches),
+    };
+    let tx = MultisigTransaction::unsigned(transfer, policy).unwrap_or_else(|e| fail(e));
+    output(matches, &LedgerTransaction::from(tx).to_json());
+}
+
+fn read_multisig_tx(path: Option<&str>) -> MultisigTransaction {
+    MultisigTransaction::decode(&read_input(path))
+        .unwrap_or_else(|e| fail(format!("Error reading {}: {}", path.unwrap_or("standard input"), e)))
+}
+
+/// Tell how many signatures a multisig transfer still needs, on standard error so the transfer
+/// can be piped on
+fn report_signatures(tx: &MultisigTransaction) {
+    match tx.missing_signatures() {
+        0 => eprintln!("{} of {} signatures, ready to submit", tx.signatures.len(), tx.policy.threshold),
+        n => eprintln!("{} of {} signatures, {} more needed", tx.signatures.len(), tx.policy.threshold, n),
+    }
+}
+
+/// `multisig-sign` subcommand
+fn multisig_sign(config: &NodeConfig, matches: &ArgMatches) {
+    let mut tx = read_multisig_tx(matches.value_of("FILE"));
+    let key = signing_key(config, matches);
+    let signature = PartialSignature::sign(&tx.transaction, &key);
+    tx.add_signature(signature).unwrap_or_else(|e| fail(e));
+    report_signatures(&tx);
+    output_tx(matches, &tx.into());
+}
+
+/// `multisig-combine` subcommand
+fn multisig_combine(matches: &ArgMatches) {
+    let mut paths = matches.values_of("FILE").unwrap();
+    let mut tx = read_multisig_tx(paths.next());
+    for path in paths {
+        tx.combine(&read_multisig_tx(Some(path)))
+            .unwrap_or_else(|e| fail(format!("Error combining {}: {}", path, e)));
+    }
+    report_signatures(&tx);
+    output_tx(matches, &tx.into());
+}
+
+/// `wallet` subcommand: manage the keystore without starting the node.
+pub fn wallet(config: &NodeConfig, sub_matches: &ArgMatches) {
+    if config.keystore_path().is_none() {
+        error!("The wallet subcommand needs --keystore or --datadir");
+        process::exit(1);
+    }
+    let mut wallet = open_wallet(config);
+    match sub_matches.subcommand() {
+        ("new", _) => match wallet.new_account() {
+            Ok(address) => println!("{}", address),
+            Err(e) => {
+                error!("Error creating account: {}", e);
+                process::exit(1);
+            }
+        },
+        ("list", _) => {
+            for address in wallet.addresses() {
+                println!("{}", address);
+            }
+        }
+        ("import", Some(m)) => {
+            let pkcs8 = wallet::decode_pkcs8(&read_input(m.value_of("FILE"))).unwrap_or_else(|e| {
+                error!("Error decoding key: {}", e);
+                process::exit(1);
+            });
+            match wallet.import(&pkcs8) {
+                Ok(address) => println!("{}", address),
+                Err(e) => {
+                    error!("Error importing key: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => {
+            error!("Unknown wallet command, see --help");
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the environment variables that override the config file, e.g. `BITCOIN_P2P_ADDR`
+pub const ENV_PREFIX: &str = "BITCOIN_";
+
+/// Settings of a node. They are read from `<datadir>/config.json` (or `--config`), then overridden
+/// by `BITCOIN_*` environment variables, then by command line flags.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Where chain data, keys, the peer book and logs are kept. Nothing is persisted without it.
+    pub datadir: Option<PathBuf>,
+    pub p2p_addr: SocketAddr,
+    pub api_addr: SocketAddr,
+    /// Peers to connect to at start
+    pub connect: Vec<String>,
+    pub p2p_workers: usize,
+    /// Logging verbosity, as the number of `-v` flags
+    pub verbosity: usize,
+    pub chain_spec: Option<PathBuf>,
+    /// Defaults to `<datadir>/keys/keystore.json`
+    pub keystore: Option<PathBuf>,
+    pub passphrase_file: Option<PathBuf>,
+    pub api_admin_token_file: Option<PathBuf>,
+    pub api_read_token_file: Option<PathBuf>,
+    pub api_rate_limit: Option<u32>,
+    /// Keep block headers only, see `--light`
+    pub light: bool,
+    /// Prune the account state of blocks this far below the tip, see `--state-history-depth`
+    pub state_history_depth: Option<u64>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            datadir: None,
+            p2p_addr: "127.0.0.1:6000".parse().unwrap(),
+            api_addr: "127.0.0.1:7000".parse().unwrap(),
+            connect: Vec::new(),
+            p2p_workers: 4,
+            verbosity: 0,
+            chain_spec: None,
+            keystore: None,
+            passphrase_file: None,
+            api_admin_token_file: None,
+            api_read_token_file: None,
+            api_rate_limit: None,
+            light: false,
+            state_history_depth: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Read a config file. A missing file gives the defaults, so a fresh datadir needs no setup.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(NodeConfig::default());
+        }
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading config {}: {}", path.display(), e))?;
+        serde_json::from_str(&data).map_err(|e| format!("error parsing config {}: {}", path.display(), e))
+    }
+
+    /// Apply the `BITCOIN_*` environment variables.
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        self.apply_vars(|name| std::env::var(format!("{}{}", ENV_PREFIX, name)).ok())
+    }
+
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .parse()
+                .map_err(|e| format!("error parsing {}{}={}: {}", ENV_PREFIX, name, value, e))
+        }
+        if let Some(v) = var("DATADIR") {
+            self.datadir = Some(v.into());
+        }
+        if let Some(v) = var("P2P_ADDR") {
+            self.p2p_addr = parse("P2P_ADDR", &v)?;
+        }
+        if let Some(v) = var("API_ADDR") {
+            self.api_addr = parse("API_ADDR", &v)?;
+        }
+        if let Some(v) = var("CONNECT") {
+            self.connect = v.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+        }
+        if let Some(v) = var("P2P_WORKERS") {
+            self.p2p_workers = parse("P2P_WORKERS", &v)?;
+        }
+        if let Some(v) = var("VERBOSITY") {
+            self.verbosity = parse("VERBOSITY", &v)?;
+        }
+        if let Some(v) = var("CHAIN_SPEC") {
+            self.chain_spec = Some(v.into());
+        }
+        if let Some(v) = var("KEYSTORE") {
+            self.keystore = Some(v.into());
+        }
+        if let Some(v) = var("PASSPHRASE_FILE") {
+            self.passphrase_file = Some(v.into());
+        }
+        if let Some(v) = var("API_ADMIN_TOKEN_FILE") {
+            self.api_admin_token_file = Some(v.into());
+        }
+        if let Some(v) = var("API_READ_TOKEN_FILE") {
+            self.api_read_token_file = Some(v.into());
+        }
+        if let Some(v) = var("API_RATE_LIMIT") {
+            self.api_rate_limit = Some(parse("API_RATE_LIMIT", &v)?);
+        }
+        if let Some(v) = var("LIGHT") {
+            self.light = parse("LIGHT", &v)?;
+        }
+        if let Some(v) = var("STATE_HISTORY_DEPTH") {
+            self.state_history_depth = Some(parse("STATE_HISTORY_DEPTH", &v)?);
+        }
+        Ok(())
+    }
+
+    /// The keystore to use: the configured one, or the one in the datadir
+    pub fn keystore_path(&self) -> Option<PathBuf> {
+        self.keystore
+            .clone()
+            .or_else(|| self.datadir.as_ref().map(|d| d.join("keys").join("keystore.json")))
+    }
+
+    /// Where blocks are stored. Light nodes keep their headers apart, as they are not full blocks.
+    pub fn chain_dir(&self) -> Option<PathBuf> {
+        let name = if self.light { "headers" } else { "chain" };
+        self.datadir.as_ref().map(|d| d.join(name))
+    }
+
+    pub fn peer_book_path(&self) -> Option<PathBuf> {
+        self.datadir.as_ref().map(|d| d.join("peers.json"))
+    }
+
+    pub fn log_path(&self) -> Option<PathBuf> {
+        self.datadir.as_ref().map(|d| d.join("logs").join("node.log"))
+    }
+}
+
+/// The config file of a datadir
+pub fn config_path(datadir: &Path) -> PathBuf {
+    datadir.join("config.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn file_then_env() {
+        let config: NodeConfig = serde_json::from_str(r#"{"p2p_addr": "0.0.0.0:6001", "p2p_workers": 8}"#).unwrap();
+        assert_eq!(config.api_addr, NodeConfig::default().api_addr);
+        assert_eq!(config.p2p_workers, 8);
+
+        let mut config = config;
+        let vars: HashMap<&str, &str> = vec![("P2P_WORKERS", "2"), ("CONNECT", "127.0.0.1:6002, 127.0.0.1:6003")]
+            .into_iter()
+            .collect();
+        config.apply_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.p2p_addr, "0.0.0.0:6001".parse().unwrap());
+        assert_eq!(config.p2p_workers, 2);
+        assert_eq!(config.connect, vec!["127.0.0.1:6002", "127.0.0.1:6003"]);
+
+        assert!(config.apply_vars(|name| if name == "API_ADDR" { Some("nope".to_string()) } else { None }).is_err());
+        assert!(serde_json::from_str::<NodeConfig>(r#"{"p2p": "0.0.0.0:6001"}"#).is_err());
+    }
+}
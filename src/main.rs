@@ -5,18 +5,28 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod cli;
+pub mod config;
+pub mod metrics;
 pub mod types;
 pub mod miner;
 pub mod network;
+pub mod shutdown;
+pub mod spv;
 pub mod tx_generator;
+pub mod vm;
+pub mod wallet;
 
 use blockchain::Blockchain;
+use blockchain::spec::ChainSpec;
+use metrics::LockKind;
+use network::message::Message;
+use network::peer_book::PeerBook;
 use clap::clap_app;
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
-use types::transaction::*;
-use std::collections::HashMap;
+use types::hash::Hashable;
 use std::net;
 use std::process;
 use std::sync::{Arc, Mutex};
@@ -31,81 +41,164 @@ fn main() {
      (version: "0.1")
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg datadir: --datadir [DIR] "Keeps config, keys, chain data, the peer book and logs in this directory")
+     (@arg config: --config [FILE] "Reads settings from this file instead of config.json in the datadir")
+     (@arg peer_addr: --p2p [ADDR] "Sets the IP address and the port of the P2P server [default: 127.0.0.1:6000]")
+     (@arg api_addr: --api [ADDR] "Sets the IP address and the port of the API server [default: 127.0.0.1:7000]")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
-     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg p2p_workers: --("p2p-workers") [INT] "Sets the number of worker threads for P2P server [default: 4]")
+     (@arg api_admin_token: --("api-admin-token") [TOKEN] "Requires this token for admin API routes")
+     (@arg api_admin_token_file: --("api-admin-token-file") [FILE] conflicts_with("api_admin_token") "Reads the admin API token from a file")
+     (@arg api_read_token: --("api-read-token") [TOKEN] "Requires this token (or the admin token) for read-only API routes")
+     (@arg api_read_token_file: --("api-read-token-file") [FILE] conflicts_with("api_read_token") "Reads the read-only API token from a file")
+     (@arg api_rate_limit: --("api-rate-limit") [INT] "Limits each API client to this many requests per second, 0 for no limit")
+     (@arg chain_spec: --("chain-spec") [FILE] "Loads the genesis block and chain parameters from a JSON chain spec")
+     (@arg keystore: --keystore [FILE] "Loads the wallet from this encrypted keystore, creating it if missing")
+     (@arg passphrase_file: --("passphrase-file") [FILE] "Reads the keystore passphrase from a file")
+     (@arg light: --light "Keeps block headers only, and gets proofs of the wallet's transactions from full peers")
+     (@arg state_history_depth: --("state-history-depth") [BLOCKS] "Prunes the ledger state of blocks more than this many blocks below the tip")
+     (@subcommand wallet =>
+      (about: "Manages the keystore without starting the node")
+      (@subcommand new => (about: "Generates a new account and prints its address"))
+      (@subcommand list => (about: "Prints the address of every account"))
+      (@subcommand import =>
+       (about: "Imports an Ed25519 key")
+       (@arg FILE: +required "A key file written by keygen, or a file holding the key as a hex-encoded PKCS#8 document, or - for standard input")
+      )
+     )
     )
+    .subcommands(cli::offline_subcommands())
     .get_matches();
 
+    // merge the config file, environment variables and flags
+    let config = cli::node_config(&matches).unwrap_or_else(|e| {
+        eprintln!("ERROR - {}", e);
+        process::exit(1);
+    });
+
+    // subcommands run on their own, without starting the node, and only log to standard error
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        stderrlog::new().verbosity(config.verbosity).init().unwrap();
+        cli::run(&config, name, sub_matches);
+        return;
+    }
+
     // init logger
-    let verbosity = matches.occurrences_of("verbose") as usize;
-    stderrlog::new().verbosity(verbosity).init().unwrap();
-
-    // parse p2p server address
-    let p2p_addr = matches
-        .value_of("peer_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P server address: {}", e);
+    if let Err(e) = cli::logger::init(config.verbosity, config.log_path().as_deref()) {
+        eprintln!("ERROR - {}", e);
+        process::exit(1);
+    }
+    info!("Effective config: {}", serde_json::to_string(&config).unwrap());
+
+    // a signal starts a graceful shutdown
+    let (shutdown, shutdown_requests) = shutdown::new();
+    if let Err(e) = shutdown.handle_signals() {
+        error!("Error installing signal handlers: {}", e);
+        process::exit(1);
+    }
+
+    // open the wallet, whose first account holds the initial balance, funds the accounts of generated
+    // transactions and seeds them
+    let mut wallet = cli::open_wallet(&config);
+    let address = wallet.default_address().unwrap_or_else(|e| {
+        error!("Error creating wallet account: {}", e);
+        process::exit(1);
+    });
+    info!("Node account is {}", address);
+    let wallet = Arc::new(Mutex::new(wallet));
+
+    // load the chain spec, which identifies the network we join
+    let spec = match &config.chain_spec {
+        Some(path) => ChainSpec::load(path).unwrap_or_else(|e| {
+            error!("Error loading chain spec: {}", e);
             process::exit(1);
-        });
+        }),
+        None => ChainSpec::default(),
+    };
+    info!("Chain {} with genesis block {}", spec.name, spec.genesis().hash());
 
-    // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing API server address: {}", e);
+    // parse api access control
+    let api_auth = api::auth::Config {
+        admin_token: read_token(&matches, "api_admin_token", config.api_admin_token_file.as_deref()),
+        read_token: read_token(&matches, "api_read_token", config.api_read_token_file.as_deref()),
+        rate_limit: config.api_rate_limit,
+    };
+
+    // the peer book lets a restarted node reconnect to the peers it knew
+    let peer_book = match config.peer_book_path() {
+        Some(path) => PeerBook::load(&path).unwrap_or_else(|e| {
+            error!("Error loading peer book: {}", e);
             process::exit(1);
-        });
+        }),
+        None => PeerBook::in_memory(),
+    };
+    let book_peers: Vec<net::SocketAddr> = peer_book.peers().to_vec();
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
+    // kept to close the queue on shutdown
+    let msg_queue = msg_rx.clone();
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(config.p2p_addr, msg_tx, peer_book).unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
-    let p2p_workers = matches
-        .value_of("p2p_workers")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P workers: {}", e);
-            process::exit(1);
-        });
-    let address = generate_address();
-    let state = Arc::new(Mutex::new(State::new(address)));
-    let blockchain = Blockchain::new(&state);
+    let state = types::ledger::new(&spec);
+    state.lock().unwrap().set_history_depth(config.state_history_depth);
+    let blockchain = match (config.chain_dir(), config.light) {
+        (Some(dir), false) => Blockchain::open(&spec, &state, &dir),
+        (Some(dir), true) => Blockchain::open_light(&spec, &state, &dir),
+        (None, false) => Ok(Blockchain::new(&spec, &state)),
+        (None, true) => Ok(Blockchain::new_light(&spec, &state)),
+    };
+    let blockchain = blockchain.unwrap_or_else(|e| {
+        error!("Error loading chain data: {}", e);
+        process::exit(1);
+    });
+    if config.light {
+        info!("Running as a light node");
+    }
     let blockchain = Arc::new(Mutex::new(blockchain));
-    let orph_buff = Arc::new(Mutex::new(HashMap::new()));
     let trans_memopool = Arc::new(Mutex::new(TransactionMemopool::new()));
+    let filters = network::filter::Filters::new();
     let worker_ctx = network::worker::Worker::new(
-        p2p_workers,
+        config.p2p_workers,
         msg_rx,
         &server,
         &blockchain,
-        &orph_buff,
         &trans_memopool,
         &state,
+        &filters,
     );
-    worker_ctx.start();
+    let worker_threads = worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &trans_memopool, &state);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &trans_memopool, &state, address);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &filters);
     miner_ctx.start();
     miner_worker_ctx.start();
 
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
+    // connect to known peers: configured ones until they answer, ones from the peer book once
+    let known_peers = config.connect.clone();
+    let book_peers: Vec<net::SocketAddr> = book_peers
+        .into_iter()
+        .filter(|p| !known_peers.contains(&p.to_string()))
+        .collect();
+    // a light node asks its peers for the transactions of its wallet
+    let filter = if config.light {
+        Some(Message::SetFilter(wallet.lock().unwrap().addresses()))
+    } else {
+        None
+    };
+    if !known_peers.is_empty() || !book_peers.is_empty() {
         let server = server.clone();
         thread::spawn(move || {
+            let send_filter = |mut peer: network::peer::Handle| {
+                if let Some(msg) = &filter {
+                    peer.write(msg.clone());
+                }
+            };
             for peer in known_peers {
                 loop {
                     let addr = match peer.parse::<net::SocketAddr>() {
@@ -116,8 +209,9 @@ fn main() {
                         }
                     };
                     match server.connect(addr) {
-                        Ok(_) => {
+                        Ok(peer) => {
                             info!("Connected to outgoing peer {}", &addr);
+                            send_filter(peer);
                             break;
                         }
                         Err(e) => {
@@ -131,24 +225,72 @@ fn main() {
                     }
                 }
             }
+            for addr in book_peers {
+                match server.connect(addr) {
+                    Ok(peer) => {
+                        info!("Reconnected to peer {}", addr);
+                        send_filter(peer);
+                    }
+                    Err(e) => warn!("Error reconnecting to peer {}: {}", addr, e),
+                }
+            }
         });
     }
 
-
-    let (generator_ctx, generator) = tx_generator::new(&server, &state, address);
+    let (generator_ctx, generator) = tx_generator::new(&server, &state, &trans_memopool, &wallet, address);
     generator_ctx.start();
 
     // start the API server
-    ApiServer::start(
-        api_addr,
-        &miner,
-        &server,
-        &blockchain,
-        &generator,
-    );
+    let api_ctx = api::Context {
+        miner: miner.clone(),
+        network: server.clone(),
+        blockchain: Arc::clone(&blockchain),
+        generator: generator.clone(),
+        state: Arc::clone(&state),
+        trans_memopool: Arc::clone(&trans_memopool),
+        wallet: Arc::clone(&wallet),
+        shutdown: shutdown.clone(),
+    };
+    ApiServer::start(config.api_addr, api_ctx, api::auth::Auth::new(api_auth));
 
-    loop {
-        std::thread::park();
+    // run until a signal or the API asks us to stop
+    let reason = shutdown_requests.recv().unwrap();
+    info!("Shutting down ({})", reason);
+    miner.exit();
+    generator.exit();
+    let timeout = time::Duration::from_secs(10);
+    if !shutdown::wait_for(timeout, || miner.is_stopped() && generator.is_stopped()) {
+        warn!("Miner or generator did not stop in time");
+    }
+    // no new messages once peers are gone; the workers handle what is queued, then exit
+    server.shutdown();
+    msg_queue.close();
+    for worker in worker_threads {
+        let _ = worker.join();
     }
+    let code = match metrics::lock(&blockchain, LockKind::Blockchain).sync() {
+        Ok(()) => shutdown::EXIT_OK,
+        Err(e) => {
+            error!("Error flushing chain data: {}", e);
+            shutdown::EXIT_FLUSH_FAILED
+        }
+    };
+    info!("Shutdown complete");
+    log::logger().flush();
+    process::exit(code);
 }
 
+/// Get an API token given either directly on the command line or as a file holding it
+fn read_token(matches: &clap::ArgMatches, arg: &str, file: Option<&std::path::Path>) -> Option<String> {
+    if let Some(token) = matches.value_of(arg) {
+        return Some(token.to_string());
+    }
+    let path = file?;
+    match std::fs::read_to_string(path) {
+        Ok(token) => Some(token.trim().to_string()),
+        Err(e) => {
+            error!("Error reading API token file {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use crate::network::message::Message;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A monotonically increasing count.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A value that can go up and down, stored as the bits of an `f64`.
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Sum and count of observed durations, exported as a Prometheus summary without quantiles.
+pub struct Summary {
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Summary {
+    const fn new() -> Self {
+        Summary {
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        self.sum_nanos.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sum_seconds(&self) -> f64 {
+        self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The shared structures whose lock wait time is tracked.
+#[derive(Copy, Clone)]
+pub enum LockKind {
+    Blockchain,
+    State,
+    Mempool,
+    Orphans,
+}
+
+const LOCK_NAMES: [&str; 4] = ["blockchain", "state", "mempool", "orphans"];
+
+const MESSAGE_NAMES: [&str; 12] = [
+    "ping",
+    "pong",
+    "new_block_hashes",
+    "get_blocks",
+    "blocks",
+    "new_transaction_hashes",
+    "get_transactions",
+    "transactions",
+    "get_headers",
+    "headers",
+    "set_filter",
+    "filtered_transactions",
+];
+
+fn message_index(msg: &Message) -> usize {
+    match msg {
+        Message::Ping(_) => 0,
+        Message::Pong(_) => 1,
+        Message::NewBlockHashes(_) => 2,
+        Message::GetBlocks(_) => 3,
+        Message::Blocks(_) => 4,
+        Message::NewTransactionHashes(_) => 5,
+        Message::GetTransactions(_) => 6,
+        Message::Transactions(_) => 7,
+        Message::GetHeaders(_) => 8,
+        Message::Headers(_) => 9,
+        Message::SetFilter(_) => 10,
+        Message::FilteredTransactions(_) => 11,
+    }
+}
+
+/// Short name of a message type, as used in metric labels
+pub fn message_name(msg: &Message) -> &'static str {
+    MESSAGE_NAMES[message_index(msg)]
+}
+
+pub struct Metrics {
+    pub blocks_mined: Counter,
+    pub blocks_received: Counter,
+    pub orphan_blocks: Gauge,
+    pub mempool_size: Gauge,
+    pub peers_connected: Gauge,
+    pub miner_hashes: Counter,
+    pub miner_hash_rate: Gauge,
+    pub state_update: Summary,
+    bytes_in: [Counter; 12],
+    bytes_out: [Counter; 12],
+    lock_wait: [Summary; 4],
+}
+
+/// Process-wide metrics, exported in the Prometheus text format at `/metrics`.
+pub static METRICS: Metrics = Metrics {
+    blocks_mined: Counter::new(),
+    blocks_received: Counter::new(),
+    orphan_blocks: Gauge::new(),
+    mempool_size: Gauge::new(),
+    peers_connected: Gauge::new(),
+    miner_hashes: Counter::new(),
+    miner_hash_rate: Gauge::new(),
+    state_update: Summary::new(),
+    bytes_in: [
+        Counter::new(), Counter::new(), Counter::new(), Counter::new(),
+        Counter::new(), Counter::new(), Counter::new(), Counter::new(),
+        Counter::new(), Counter::new(), Counter::new(), Counter::new(),
+    ],
+    bytes_out: [
+        Counter::new(), Counter::new(), Counter::new(), Counter::new(),
+        Counter::new(), Counter::new(), Counter::new(), Counter::new(),
+        Counter::new(), Counter::new(), Counter::new(), Counter::new(),
+    ],
+    lock_wait: [Summary::new(), Summary::new(), Summary::new(), Summary::new()],
+};
+
+impl Metrics {
+    pub fn message_in(&self, msg: &Message, bytes: usize) {
+        self.bytes_in[message_index(msg)].add(bytes as u64);
+    }
+
+    pub fn message_out(&self, msg: &Message, bytes: usize) {
+        self.bytes_out[message_index(msg)].add(bytes as u64);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "blocks_mined_total", "Blocks mined by this node", self.blocks_mined.get());
+        counter(&mut out, "blocks_received_total", "Valid blocks received from peers", self.blocks_received.get());
+        gauge(&mut out, "orphan_blocks", "Blocks buffered while waiting for their parent", self.orphan_blocks.get());
+        gauge(&mut out, "mempool_size", "Transactions in the mempool", self.mempool_size.get());
+        gauge(&mut out, "peers_connected", "Connected peers", self.peers_connected.get());
+        counter(&mut out, "miner_hashes_total", "Block hashes tried by the miner", self.miner_hashes.get());
+        gauge(&mut out, "miner_hash_rate", "Block hashes tried per second", self.miner_hash_rate.get());
+
+        header(&mut out, "state_update_seconds", "Time spent applying a block in State::update", "summary");
+        writeln!(out, "bitcoin_state_update_seconds_sum {}", self.state_update.sum_seconds()).unwrap();
+        writeln!(out, "bitcoin_state_update_seconds_count {}", self.state_update.count()).unwrap();
+
+        header(&mut out, "lock_wait_seconds", "Time spent waiting for shared locks", "summary");
+        for (name, s) in LOCK_NAMES.iter().zip(self.lock_wait.iter()) {
+            writeln!(out, "bitcoin_lock_wait_seconds_sum{{lock=\"{}\"}} {}", name, s.sum_seconds()).unwrap();
+            writeln!(out, "bitcoin_lock_wait_seconds_count{{lock=\"{}\"}} {}", name, s.count()).unwrap();
+        }
+
+        header(&mut out, "network_bytes_in_total", "Bytes received per message type", "counter");
+        for (name, c) in MESSAGE_NAMES.iter().zip(self.bytes_in.iter()) {
+            writeln!(out, "bitcoin_network_bytes_in_total{{type=\"{}\"}} {}", name, c.get()).unwrap();
+        }
+        header(&mut out, "network_bytes_out_total", "Bytes sent per message type", "counter");
+        for (name, c) in MESSAGE_NAMES.iter().zip(self.bytes_out.iter()) {
+            writeln!(out, "bitcoin_network_bytes_out_total{{type=\"{}\"}} {}", name, c.get()).unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP bitcoin_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE bitcoin_{} {}", name, kind).unwrap();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, v: u64) {
+    header(out, name, help, "counter");
+    writeln!(out, "bitcoin_{} {}", name, v).unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, v: f64) {
+    header(out, name, help, "gauge");
+    writeln!(out, "bitcoin_{} {}", name, v).unwrap();
+}
+
+/// Lock a shared structure, recording how long we waited for it.
+pub fn lock<T: ?Sized>(mutex: &Mutex<T>, kind: LockKind) -> MutexGuard<'_, T> {
+    let start = Instant::now();
+    let guard = mutex.lock().unwrap();
+    METRICS.lock_wait[kind as usize].observe(start.elapsed());
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_exposition_format() {
+        let metrics = Metrics {
+            blocks_mined: Counter::new(),
+            blocks_received: Counter::new(),
+            orphan_blocks: Gauge::new(),
+            mempool_size: Gauge::new(),
+            peers_connected: Gauge::new(),
+            miner_hashes: Counter::new(),
+            miner_hash_rate: Gauge::new(),
+            state_update: Summary::new(),
+            bytes_in: Default::default(),
+            bytes_out: Default::default(),
+            lock_wait: Default::default(),
+        };
+        metrics.blocks_mined.add(3);
+        metrics.mempool_size.set(7.0);
+        metrics.message_out(&Message::Ping(String::new()), 12);
+        metrics.state_update.observe(Duration::from_millis(500));
+        let out = metrics.render();
+        assert!(out.contains("# TYPE bitcoin_blocks_mined_total counter\nbitcoin_blocks_mined_total 3\n"));
+        assert!(out.contains("bitcoin_mempool_size 7\n"));
+        assert!(out.contains("bitcoin_network_bytes_out_total{type=\"ping\"} 12\n"));
+        assert!(out.contains("bitcoin_state_update_seconds_sum 0.5\n"));
+        assert!(out.contains("bitcoin_state_update_seconds_count 1\n"));
+    }
+}
@@ -1,6 +1,7 @@
 pub mod worker;
 
-use log::info;
+use log::{error, info, warn};
+use serde::Serialize;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
@@ -12,18 +13,23 @@ use crate::types::block::Block;
 use crate::blockchain::Blockchain;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::types::merkle::MerkleTree;
+use crate::types::merkle::{MerkleTree, Scheme};
 use crate::types::block::*;
+use crate::types::ledger::{Ledger, LedgerTransaction};
 use crate::types::transaction::*;
 
 
 use rand::Rng;
-use crate::types::hash::Hashable;
+use crate::types::hash::{H256, Hashable};
+use crate::metrics::{self, LockKind, METRICS};
 
+/// How long the miner waits before trying again when it cannot build a block on the tip
+const RETRY_INTERVAL: time::Duration = time::Duration::from_millis(500);
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause, // stop mining but keep the thread alive, so it can be started again
     Exit,
 }
 
@@ -33,6 +39,15 @@ enum OperatingState {
     ShutDown,
 }
 
+/// Snapshot of what the miner is doing, as reported by `/miner/status`
+#[derive(Serialize, Clone, Debug)]
+pub struct Status {
+    pub state: String,
+    pub lambda: Option<u64>,
+    pub hash_rate: f64,
+    pub blocks_found: u64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -40,22 +55,43 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     trans_memopool: Arc<Mutex<TransactionMemopool>>,
-    state: Arc<Mutex<State>>
+    state: Arc<Mutex<dyn Ledger>>,
+    /// Account that receives the reward of the blocks we mine
+    beneficiary: address::Address,
+    /// Hashes tried since `rate_window_start`, used to estimate the hash rate
+    window_hashes: u64,
+    rate_window_start: time::Instant,
+    status: Arc<Mutex<Status>>,
+    /// Parent and Merkle root of the last block tried, and its timestamp if a contract in it can
+    /// read it, with the state root after it, so the state root is only computed again when the
+    /// parent or the transactions change
+    state_root_cache: Option<(H256, H256, Option<u128>, H256)>,
+    /// Parent and Merkle root of the last block that could not be made, the Merkle root being
+    /// zero when the coinbase could not
+    failed_block: Option<(H256, H256)>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
 }
 
 pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     trans_memopool: &Arc<Mutex<TransactionMemopool>>,
-    state: &Arc<Mutex<State>>,
+    state: &Arc<Mutex<dyn Ledger>>,
+    beneficiary: address::Address,
 ) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let status = Arc::new(Mutex::new(Status {
+        state: "paused".to_string(),
+        lambda: None,
+        hash_rate: 0.0,
+        blocks_found: 0,
+    }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -64,10 +100,17 @@ pub fn new(
         blockchain: Arc::clone(blockchain),
         trans_memopool: Arc::clone(trans_memopool),
         state: Arc::clone(state),
+        beneficiary,
+        window_hashes: 0,
+        rate_window_start: time::Instant::now(),
+        status: Arc::clone(&status),
+        state_root_cache: None,
+        failed_block: None,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -80,17 +123,35 @@ pub fn new(
 
 impl Handle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        self.send(ControlSignal::Exit);
     }
 
     pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+        self.send(ControlSignal::Start(lambda));
+    }
+
+    pub fn pause(&self) {
+        self.send(ControlSignal::Pause);
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        self.send(ControlSignal::Update);
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Whether the miner thread has exited and no longer accepts signals
+    pub fn is_stopped(&self) -> bool {
+        self.status.lock().unwrap().state == "stopped"
+    }
+
+    fn send(&self, signal: ControlSignal) {
+        // the miner thread is gone after an exit signal, so later signals are dropped
+        if self.control_chan.send(signal).is_err() {
+            warn!("Miner is stopped, ignoring control signal");
+        }
     }
 }
 
@@ -105,6 +166,37 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
+    fn set_state(&mut self, operating_state: OperatingState) {
+        let mut status = self.status.lock().unwrap();
+        match operating_state {
+            OperatingState::Paused => {
+                status.state = "paused".to_string();
+                status.hash_rate = 0.0;
+            }
+            OperatingState::Run(i) => {
+                status.state = "running".to_string();
+                status.lambda = Some(i);
+                self.window_hashes = 0;
+                self.rate_window_start = time::Instant::now();
+            }
+            OperatingState::ShutDown => {
+                status.state = "stopped".to_string();
+                status.hash_rate = 0.0;
+            }
+        }
+        METRICS.miner_hash_rate.set(status.hash_rate);
+        self.operating_state = operating_state;
+    }
+
+    /// Log why no block can be mined on `parent`, once: the error stays until the tip or the
+    /// mempool changes, so the loop waits instead of trying every nonce on the same block
+    fn report_failure(&mut self, parent: H256, merkle_root: H256, e: &str) {
+        if self.failed_block != Some((parent, merkle_root)) {
+            error!("Cannot mine on {}: {}", parent, e);
+            self.failed_block = Some((parent, merkle_root));
+        }
+    }
+
     fn miner_loop(&mut self) {
         // main mining loop
         //let locked_parent = self.blockchain.lock().unwrap();
@@ -119,13 +211,13 @@ impl Context {
                     match signal {
                         ControlSignal::Exit => {
                             info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
                             info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.set_state(OperatingState::Run(i));
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Update | ControlSignal::Pause => {
                             // in paused state, don't need to update
                         }
                     };
@@ -139,11 +231,15 @@ impl Context {
                         match signal {
                             ControlSignal::Exit => {
                                 info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
+                                self.set_state(OperatingState::ShutDown);
                             }
                             ControlSignal::Start(i) => {
                                 info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.set_state(OperatingState::Run(i));
+                            }
+                            ControlSignal::Pause => {
+                                info!("Miner paused");
+                                self.set_state(OperatingState::Paused);
                             }
                             ControlSignal::Update => {
                                 unimplemented!()
@@ -160,57 +256,102 @@ impl Context {
             // TODO for student: actual mining, create a block
             // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
             
-            let locked_parent = self.blockchain.lock().unwrap();
+            let locked_parent = metrics::lock(&self.blockchain, LockKind::Blockchain);
             let mut parent = locked_parent.tip();
             let difficulty =locked_parent.block_map[&parent].header.difficulty;
 
             let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-            let mut signed_transactions:Vec<SignedTransaction> = Vec::new();
-            let transaction_size = 200;
-            let mut counter = 0;
-            let mut transaction_memopool = self.trans_memopool.lock().unwrap();
+            let height = locked_parent.block_seq[&parent] as u64 + 1;
+            let mut transaction_memopool = metrics::lock(&self.trans_memopool, LockKind::Mempool);
             // wrap the transactions in pool to block through block content transactions 
-            for (trans_hash, trans) in &transaction_memopool.trans_map {
-                signed_transactions.push(trans.clone());
-                counter += 1;
-                if counter > transaction_size {
-                    break;
+            // the ledger picks what can go on top of the tip, starting with any coinbase
+            let coinbase = metrics::lock(&self.state, LockKind::State).coinbase(self.beneficiary, height);
+            let coinbase = match coinbase {
+                Ok(coinbase) => coinbase,
+                Err(e) => {
+                    drop(transaction_memopool);
+                    drop(locked_parent);
+                    self.report_failure(parent, H256::default(), &e);
+                    thread::sleep(RETRY_INTERVAL);
+                    continue;
                 }
-            } 
+            };
+            let signed_transactions = {
+                let state = metrics::lock(&self.state, LockKind::State);
+                let candidates = state.select(transaction_memopool.ready(height, timestamp));
+                locked_parent.spec.select_transactions(coinbase.iter().chain(candidates))
+            };
 
-            let merkle_tree = MerkleTree::new(&signed_transactions);
+            let merkle_tree = MerkleTree::with_scheme(&signed_transactions, Scheme::Tagged);
             let merkle_root = merkle_tree.root();
             let mut rng = rand::thread_rng();
             let nonce = rng.gen();
             
             let header = Header{
+                version: CURRENT_VERSION,
                 parent:parent,
                 nonce:nonce,
                 difficulty:difficulty,
                 timestamp:timestamp,
                 merkle_root:merkle_root,
+                state_root: H256::default(),
+                beneficiary: self.beneficiary,
             };
             let content = Content{
                 content: signed_transactions
             };
 
-            let block = Block{
+            let mut block = Block{
                 header: header,
                 content: content,
             };
+            let contracts = block.content.content.iter().any(|tx| matches!(tx, LedgerTransaction::Contract(_)));
+            let timed = contracts.then_some(timestamp);
+            block.header.state_root = match self.state_root_cache {
+                Some((p, m, t, root)) if p == parent && m == merkle_root && t == timed => root,
+                _ => {
+                    let root = metrics::lock(&self.state, LockKind::State).root_after(&block, height);
+                    match root {
+                        Ok(root) => {
+                            self.state_root_cache = Some((parent, merkle_root, timed, root));
+                            root
+                        }
+                        Err(e) => {
+                            drop(transaction_memopool);
+                            drop(locked_parent);
+                            self.report_failure(parent, merkle_root, &e);
+                            thread::sleep(RETRY_INTERVAL);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            METRICS.miner_hashes.inc();
+            self.window_hashes += 1;
+            let window = self.rate_window_start.elapsed();
+            if window >= time::Duration::from_secs(1) {
+                let hash_rate = self.window_hashes as f64 / window.as_secs_f64();
+                METRICS.miner_hash_rate.set(hash_rate);
+                self.status.lock().unwrap().hash_rate = hash_rate;
+                self.window_hashes = 0;
+                self.rate_window_start = time::Instant::now();
+            }
 
             if block.hash() <= difficulty {
                 // println!("parent  1 {:?}", block.header.parent);
                 // remove tx from mempool and update state
-                // for tx in block.clone().content.content {
-                //     
-                //     if transaction_memopool.trans_map.contains_key(&tx.hash()){
-                //         transaction_memopool.trans_map.remove(&tx.hash());
-                //     }
-                // }
-                let mut state_un = self.state.lock().unwrap();
-                state_un.update(&block);
-                self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+                let mut state_un = metrics::lock(&self.state, LockKind::State);
+                match state_un.update(&block, height) {
+                    Ok(()) => {
+                        transaction_memopool.remove_block(&block, height);
+                        METRICS.mempool_size.set(transaction_memopool.trans_map.len() as f64);
+                        METRICS.blocks_mined.inc();
+                        self.status.lock().unwrap().blocks_found += 1;
+                        self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+                    }
+                    Err(e) => error!("Mined an invalid block {}: {}", block.hash(), e),
+                }
             }
 
             if let OperatingState::Run(i) = self.operating_state {
@@ -228,7 +369,7 @@ impl Context {
 // #[cfg(test)]
 // mod test {
 //     use ntest::timeout;
-//     use crate::types::hash::Hashable;
+//     use crate::types::hash::{H256, Hashable};
 
 //     #[test]
 //     #[timeout(30000)]
@@ -254,4 +395,44 @@ impl Context {
 //     }
 // }
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+#[cfg(test)]
+mod control_test {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::types::ledger;
+    use ntest::timeout;
+
+    fn wait_for_state(handle: &Handle, state: &str) {
+        while handle.status().state != state {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn pause_and_stop() {
+        let spec = ChainSpec::default();
+        let state = ledger::new(&spec);
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(&spec, &state)));
+        let trans_memopool = Arc::new(Mutex::new(TransactionMemopool::new()));
+        let (miner_ctx, miner_handle, _finished_block_chan) =
+            new(&blockchain, &trans_memopool, &state, generate_address());
+        miner_ctx.start();
+        assert_eq!(miner_handle.status().state, "paused");
+
+        miner_handle.start(100);
+        wait_for_state(&miner_handle, "running");
+        assert_eq!(miner_handle.status().lambda, Some(100));
+
+        miner_handle.pause();
+        wait_for_state(&miner_handle, "paused");
+
+        miner_handle.exit();
+        wait_for_state(&miner_handle, "stopped");
+        assert!(miner_handle.is_stopped());
+        // signals to a stopped miner are dropped instead of panicking
+        miner_handle.start(100);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,5 +1,6 @@
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use log::{debug, info};
+use crate::network::filter::Filters;
 use crate::network::message::Message;
 use crate::types::block::{Block, self};
 use crate::network::server::Handle as ServerHandle;
@@ -7,24 +8,28 @@ use crate::types::hash::Hashable;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
+use crate::metrics::{self, LockKind};
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
+    filters: Filters,
 }
 
 impl Worker {
     pub fn new(
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>
+        blockchain: &Arc<Mutex<Blockchain>>,
+        filters: &Filters,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(blockchain),
+            filters: filters.clone(),
         }
     }
 
@@ -40,14 +45,20 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            let _block = self.finished_block_chan.recv().expect("Receive finished block error");
+            let _block = match self.finished_block_chan.recv() {
+                Ok(block) => block,
+                // the miner has exited
+                Err(_) => break,
+            };
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
 
             //println!("Miner Blocks: {:?}", _block);
-            self.blockchain.lock().unwrap().insert(&_block);
+            let mut blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+            blockchain.insert(&_block);
             let mut vec = Vec::new(); //change
             vec.push(_block.hash()); // push hash vec
-            self.server.broadcast(Message::NewBlockHashes(vec));
+            self.server.broadcast(Message::NewBlockHashes(vec.clone()));
+            self.filters.deliver(&self.server, &blockchain, &vec);
         }
     }
 }
@@ -0,0 +1,46 @@
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// How many addresses a light peer may put in its filter
+pub const MAX_FILTER_ADDRESSES: usize = 1000;
+
+/// Addresses light peers want proven transactions for. It is shared by the network workers,
+/// which receive filters and blocks, and the miner, which makes blocks.
+#[derive(Clone, Default)]
+pub struct Filters {
+    peers: Arc<Mutex<HashMap<SocketAddr, HashSet<Address>>>>,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the filter of a peer, replacing any previous one
+    pub fn set(&self, peer: SocketAddr, addresses: HashSet<Address>) {
+        self.peers.lock().unwrap().insert(peer, addresses);
+    }
+
+    /// Send light peers proofs of the transactions of newly inserted blocks that match their filter
+    pub fn deliver(&self, server: &ServerHandle, blockchain: &Blockchain, blocks: &[H256]) {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.is_empty() || blocks.is_empty() {
+            return;
+        }
+        // forget the filters of peers that went away
+        let connected: HashSet<SocketAddr> = server.peers().iter().map(|p| p.address).collect();
+        peers.retain(|addr, _| connected.contains(addr));
+        for (addr, addresses) in peers.iter() {
+            let proven: Vec<_> = blocks.iter().flat_map(|h| blockchain.filter_block(h, addresses)).collect();
+            if !proven.is_empty() {
+                server.send(*addr, Message::FilteredTransactions(proven));
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::spv::ProvenTransaction;
+use crate::types::{address::Address, hash::H256, block::{Block, Header}, ledger::LedgerTransaction};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -11,5 +12,12 @@ pub enum Message {
     Blocks(Vec<Block>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
-    Transactions(Vec<SignedTransaction>),
+    Transactions(Vec<LedgerTransaction>),
+    /// Sent by light nodes, which only keep headers
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// Asks a full node for proofs of the transactions touching these addresses: those already in
+    /// its longest chain, then those in every block it accepts
+    SetFilter(Vec<Address>),
+    FilteredTransactions(Vec<ProvenTransaction>),
 }
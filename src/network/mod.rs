@@ -1,4 +1,6 @@
+pub mod filter;
 pub mod message;
 pub mod peer;
+pub mod peer_book;
 pub mod server;
 pub mod worker;
@@ -1,30 +1,82 @@
 use super::message::Message;
+use crate::metrics::{self, METRICS};
 use futures::{channel::mpsc, sink::SinkExt};
 use log::trace;
+use serde::Serialize;
 use smol::Async;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
+    direction: Direction,
 ) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        stream: Some(Arc::new(stream.get_ref().try_clone()?)),
+        stats: Arc::new(Stats::new()),
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// Traffic counters shared by all clones of a peer handle
+#[derive(Debug)]
+struct Stats {
+    connected_since: u64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    last_message: Mutex<Option<(u64, &'static str)>>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            connected_since: now_millis(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            last_message: Mutex::new(None),
+        }
+    }
+}
+
+/// What `/network/peers` reports about a connected peer
+#[derive(Serialize, Clone, Debug)]
+pub struct PeerInfo {
+    pub address: std::net::SocketAddr,
+    pub direction: Direction,
+    /// Milliseconds since the Unix epoch
+    pub connected_since: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Type of the last message received from this peer
+    pub last_message: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub last_message_at: Option<u64>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    direction: Direction,
+    stream: Option<Arc<std::net::TcpStream>>,
+    stats: Arc<Stats>,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -35,6 +87,9 @@ pub struct TestReceiver {
 impl Handle {
     pub fn write(&mut self, msg: Message) {
         let buffer = bincode::serialize(&msg).unwrap();
+        METRICS.message_out(&msg, buffer.len());
+        // 4 bytes of frame header go out with every message
+        self.stats.bytes_sent.fetch_add(buffer.len() as u64 + 4, Ordering::Relaxed);
         smol::block_on(async move {
             if self.write_queue.send(buffer).await.is_err() {
                 trace!("Trying to send to disconnected peer");
@@ -46,12 +101,48 @@ impl Handle {
         &self.addr
     }
 
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Record a message of `len` bytes received from this peer
+    pub fn record_received(&self, msg: &Message, len: usize) {
+        self.stats.bytes_received.fetch_add(len as u64 + 4, Ordering::Relaxed);
+        *self.stats.last_message.lock().unwrap() = Some((now_millis(), metrics::message_name(msg)));
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        let last_message = *self.stats.last_message.lock().unwrap();
+        PeerInfo {
+            address: self.addr,
+            direction: self.direction,
+            connected_since: self.stats.connected_since,
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.stats.bytes_received.load(Ordering::Relaxed),
+            last_message: last_message.map(|(_, name)| name.to_string()),
+            last_message_at: last_message.map(|(at, _)| at),
+        }
+    }
+
+    /// Close the connection. The reader and writer tasks of this peer exit afterwards.
+    pub fn disconnect(&mut self) {
+        if let Some(stream) = &self.stream {
+            if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
+                trace!("Error shutting down peer {}: {}", self.addr, e);
+            }
+        }
+        self.write_queue.close_channel();
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
             write_queue: s,
+            direction: Direction::Incoming,
+            stream: None,
+            stats: Arc::new(Stats::new()),
         },
         TestReceiver {
             r
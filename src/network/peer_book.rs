@@ -0,0 +1,58 @@
+use log::warn;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// How many addresses the peer book remembers; the oldest are forgotten first
+const MAX_PEERS: usize = 64;
+
+/// Addresses of peers we managed to connect to, kept across restarts so a node can rejoin the
+/// network without `--connect`.
+pub struct PeerBook {
+    /// Where the book is saved; `None` keeps it in memory only
+    path: Option<PathBuf>,
+    peers: Vec<SocketAddr>,
+}
+
+impl PeerBook {
+    pub fn in_memory() -> Self {
+        PeerBook {
+            path: None,
+            peers: Vec::new(),
+        }
+    }
+
+    /// Load the book at `path`, or start an empty one if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let peers = if path.exists() {
+            let data = std::fs::read_to_string(path)
+                .map_err(|e| format!("error reading peer book {}: {}", path.display(), e))?;
+            serde_json::from_str(&data)
+                .map_err(|e| format!("error parsing peer book {}: {}", path.display(), e))?
+        } else {
+            Vec::new()
+        };
+        Ok(PeerBook {
+            path: Some(path.to_path_buf()),
+            peers,
+        })
+    }
+
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// Remember a peer as the most recent one, saving the book if it is backed by a file.
+    pub fn add(&mut self, addr: SocketAddr) {
+        self.peers.retain(|p| *p != addr);
+        self.peers.push(addr);
+        if self.peers.len() > MAX_PEERS {
+            self.peers.remove(0);
+        }
+        if let Some(path) = &self.path {
+            let data = serde_json::to_string_pretty(&self.peers).unwrap();
+            if let Err(e) = std::fs::write(path, data) {
+                warn!("Error saving peer book {}: {}", path.display(), e);
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use crate::types::address::Address;
 use super::peer;
 use super::message;
+use super::peer_book::PeerBook;
+use crate::metrics::METRICS;
 
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
@@ -16,6 +17,7 @@ use std::thread;
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    peer_book: PeerBook,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
@@ -27,6 +29,8 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        peer_book,
+        shutting_down: false,
     };
     Ok((ctx, handle))
 }
@@ -37,6 +41,10 @@ pub struct Context {
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    /// Outgoing peers we connected to, remembered across restarts
+    peer_book: PeerBook,
+    /// Set on shutdown: peers are disconnected and new connections refused
+    shutting_down: bool,
 }
 
 impl Context {
@@ -82,7 +90,15 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
+                    if self.shutting_down {
+                        let error = std::io::Error::other("node is shutting down");
+                        result_chan.send(Err(error)).unwrap();
+                        continue;
+                    }
                     let handle = self.connect(&addr, ex.clone()).await;
+                    if handle.is_ok() {
+                        self.peer_book.add(addr);
+                    }
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
@@ -93,15 +109,58 @@ impl Context {
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    if self.shutting_down {
+                        // dropping the stream closes the connection
+                        continue;
+                    }
+                    // a peer that hangs up right away must not take the server down
+                    if let Err(e) = self.accept(stream, ex.clone()).await {
+                        debug!("Error registering incoming peer: {}", e);
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    if let Some(mut hd) = self.peers.remove(&addr) {
+                        // make sure both the reader and the writer task of this peer exit
+                        hd.disconnect();
+                        METRICS.peers_connected.set(self.peers.len() as f64);
+                        info!("Peer {} disconnected", addr);
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let peers = self.peers.values().map(|hd| hd.info()).collect();
+                    result_chan.send(peers).unwrap();
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::DisconnectPeer(addr, result_chan) => {
+                    trace!("Processing DisconnectPeer({})", addr);
+                    let found = match self.peers.remove(&addr) {
+                        Some(mut hd) => {
+                            hd.disconnect();
+                            METRICS.peers_connected.set(self.peers.len() as f64);
+                            info!("Disconnected from peer {}", addr);
+                            true
+                        }
+                        None => false,
+                    };
+                    result_chan.send(found).unwrap();
+                }
+                ControlSignal::Shutdown(result_chan) => {
+                    trace!("Processing Shutdown command");
+                    self.shutting_down = true;
+                    for (_, mut hd) in self.peers.drain() {
+                        hd.disconnect();
+                    }
+                    METRICS.peers_connected.set(0.0);
+                    info!("Closed all peer connections");
+                    result_chan.send(()).unwrap();
+                }
+                ControlSignal::SendToPeer(addr, msg) => {
+                    trace!("Processing SendToPeer({})", addr);
+                    match self.peers.get_mut(&addr) {
+                        Some(hd) => hd.write(msg),
+                        None => debug!("Not sending to {}, which is not connected", addr),
+                    }
                 }
             }
         }
@@ -133,15 +192,16 @@ impl Context {
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (mut write_queue, handle) = peer::new(&stream, direction)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
+        let reader_control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
 
         // start the reactor for this peer
@@ -170,10 +230,10 @@ impl Context {
                 {
                     Ok(_) => {
                         let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
+                        // the queue is closed when the node shuts down
+                        if new_msg_chan.send((new_payload, handle_copy.clone())).await.is_err() {
+                            break;
+                        }
                     }
                     Err(_) => {
                         break;
@@ -181,6 +241,10 @@ impl Context {
                 }
             }
             // the peer is disconnected
+            reader_control_chan
+                .send(ControlSignal::DroppedPeer(addr))
+                .await
+                .unwrap();
         })
             .detach();
 
@@ -188,8 +252,11 @@ impl Context {
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
             loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                // first, get a message to write from the queue, which is closed on disconnect
+                let new_msg = match write_queue.next().await {
+                    Some(msg) => msg,
+                    None => break,
+                };
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -224,6 +291,7 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        METRICS.peers_connected.set(self.peers.len() as f64);
         Ok(handle)
     }
 }
@@ -263,8 +331,32 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
+    /// Get information about all currently connected peers
+    pub fn peers(&self) -> Vec<peer::PeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Close the connection to a peer. Returns false if we are not connected to it.
+    pub fn disconnect(&self, addr: std::net::SocketAddr) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::DisconnectPeer(addr, sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Disconnect every peer and refuse new connections, for a graceful shutdown.
+    pub fn shutdown(&self) {
+        let (sender, receiver) = oneshot::channel();
+        // if the server task is already gone there is nothing left to close
+        if smol::block_on(self.control_chan.send(ControlSignal::Shutdown(sender))).is_ok() {
+            let _ = smol::block_on(receiver);
+        }
+    }
+
+    /// Send a message to one connected peer
+    pub fn send(&self, peer: std::net::SocketAddr, msg: message::Message) {
+        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer(peer, msg))).unwrap();
     }
 
     #[cfg(any(test,test_utilities))]
@@ -284,5 +376,8 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    GetPeers(oneshot::Sender<Vec<peer::PeerInfo>>),
+    DisconnectPeer(std::net::SocketAddr, oneshot::Sender<bool>),
+    Shutdown(oneshot::Sender<()>),
+    SendToPeer(std::net::SocketAddr, message::Message),
 }
@@ -1,15 +1,17 @@
+use super::filter::{Filters, MAX_FILTER_ADDRESSES};
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
+use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::hash::{H256, Hashable};
-use crate::types::transaction::{TransactionMemopool, State};
-use crate::types::transaction::SignedTransaction;
-use crate::types::transaction::verify;
-use log::{debug, warn, error};
+use crate::types::ledger::{Ledger, LedgerTransaction};
+use crate::types::transaction::TransactionMemopool;
+use crate::metrics::{self, LockKind, METRICS};
+use log::{debug, warn};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -25,7 +27,8 @@ pub struct Worker {
     blockchain: Arc<Mutex<Blockchain>>,
     orph_buff: Arc<Mutex<HashMap<H256, Block>>>,
     trans_memopool: Arc<Mutex<TransactionMemopool>>,
-    state: Arc<Mutex<State>>,
+    state: Arc<Mutex<dyn Ledger>>,
+    filters: Filters,
 }
 
 
@@ -35,42 +38,49 @@ impl Worker {
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
-        orph_buff: &Arc<Mutex<HashMap<H256, Block>>>,
         trans_memopool: &Arc<Mutex<TransactionMemopool>>,
-        state: &Arc<Mutex<State>>,
+        state: &Arc<Mutex<dyn Ledger>>,
+        filters: &Filters,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
-            orph_buff: Arc::clone(orph_buff),
+            orph_buff: Arc::new(Mutex::new(HashMap::new())),
             trans_memopool: Arc::clone(trans_memopool),
-            state: Arc::clone(state)
+            state: Arc::clone(state),
+            filters: filters.clone(),
         }
     }
 
-    pub fn start(self) {
+    /// Start the worker threads. They exit once the message queue is closed and drained.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
+        let mut threads = Vec::new();
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
+            threads.push(thread::spawn(move || {
                 cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+                debug!("Worker thread {} exited", i);
+            }));
         }
+        threads
     }
 
     fn worker_loop(&self) {
         loop {
             let result = smol::block_on(self.msg_chan.recv());
-            if let Err(e) = result {
-                error!("network worker terminated {}", e);
+            if result.is_err() {
+                // the queue was closed and everything in it has been handled
                 break;
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
+            let msg_len = msg.len();
             let msg: Message = bincode::deserialize(&msg).unwrap();
+            METRICS.message_in(&msg, msg_len);
+            peer.record_received(&msg, msg_len);
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -86,20 +96,28 @@ impl Worker {
                 Message::NewBlockHashes(nonce) =>{
                     if nonce.len() != 0{
                         let mut vec_hash: Vec<H256> = Vec::new();
-                        let blockchain = self.blockchain.lock().unwrap();
+                        let blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
                         for hash in nonce.clone() {   
                             if blockchain.block_map.get(&hash).is_none(){
                                 vec_hash.push(hash);
                             }
                         }
                         if vec_hash.len() != 0 {
-                            peer.write(Message::GetBlocks(vec_hash));
+                            if blockchain.light {
+                                peer.write(Message::GetHeaders(vec_hash));
+                            } else {
+                                peer.write(Message::GetBlocks(vec_hash));
+                            }
                         }
                     }
                 }
                 //receive hashes and reply Blocks.
                 Message::GetBlocks(nonce) => {
-                    let blockchain = self.blockchain.lock().unwrap();
+                    let blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+                    if blockchain.light {
+                        // the blocks of a light chain have no transactions
+                        continue;
+                    }
                     let mut blocks = nonce.clone();
                     let mut vec = Vec::new();
                     for i in 0..blocks.len() {
@@ -115,12 +133,16 @@ impl Worker {
 
                 //receive block, insert block and broadcast block hashes
                 Message::Blocks(nonce) => {
-                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let mut blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+                    if blockchain.light {
+                        continue;
+                    }
                     let blocks = nonce.clone();
-                    let mut orph_buff = self.orph_buff.lock().unwrap();
+                    let mut orph_buff = metrics::lock(&self.orph_buff, LockKind::Orphans);
                     let mut new_blocks: Vec<H256> = Vec::new();
-                    let mut trans_memopool = self.trans_memopool.lock().unwrap();
-                    let mut state = self.state.lock().unwrap();
+                    let mut inserted: Vec<H256> = Vec::new();
+                    let mut trans_memopool = metrics::lock(&self.trans_memopool, LockKind::Mempool);
+                    let mut state = metrics::lock(&self.state, LockKind::State);
                     for block in blocks { 
                         let mut hash = block.hash();
                         let mut block = block.clone();
@@ -130,6 +152,14 @@ impl Worker {
                         if blockchain.block_map.contains_key(&hash) { //already in blockchain
                             continue;
                         }
+                        if let Err(e) = blockchain.spec.check_limits(&block) {
+                            warn!("Dropping block {} over the chain limits: {}", hash, e);
+                            continue;
+                        }
+                        if let Err(e) = block.check_merkle_root() {
+                            warn!("Dropping block {}: {}", hash, e);
+                            continue;
+                        }
                         new_blocks.push(hash);
                         // check parent
                         let mut p_hash = block.header.parent;
@@ -141,14 +171,21 @@ impl Worker {
                             let mut p_block = blockchain.block_map[&p_hash].clone();
                             let mut p_diff = p_block.header.difficulty;
                             let mut diff = block.header.difficulty;
-                            let content = block.content.content.clone();
-                            // println!("***************** {:?}", content.len());
-                            let mut removeContent = false;
                             loop{
                                 if hash < diff && diff == p_diff{ //PoW check    
-                                    removeContent = true; 
-                                    state.update(&block);           
+                                    if let Err(e) = blockchain.check_version(&block.header) {
+                                        warn!("Dropping block {}: {}", hash, e);
+                                        break;
+                                    }
+                                    let height = blockchain.block_seq[&p_hash] as u64 + 1;
+                                    if let Err(e) = state.update(&block, height) {
+                                        warn!("Dropping block {}: {}", hash, e);
+                                        break;
+                                    }
+                                    trans_memopool.remove_block(&block, height);
                                     blockchain.insert(&block);
+                                    inserted.push(hash);
+                                    METRICS.blocks_received.inc();
                                     if orph_buff.contains_key(&hash) { 
                                         let orph_block = orph_buff.remove(&hash).unwrap();
                                         block = orph_block.clone();
@@ -164,14 +201,10 @@ impl Worker {
                                     break; //exit loop
                                 }
                             }  
-                            if removeContent {
-                                for trans in content {
-                                    let trans_hash = trans.hash();
-                                    trans_memopool.trans_map.remove(&trans_hash);
-                                }
-                            }                  
                         }
                     }
+                    METRICS.orphan_blocks.set(orph_buff.len() as f64);
+                    METRICS.mempool_size.set(trans_memopool.trans_map.len() as f64);
                     let mut buffered_block_hashs: Vec<H256> = Vec::new();
                     for (orph_p_hash, orph_block) in orph_buff.clone() {
                         buffered_block_hashs.push(orph_p_hash);
@@ -182,11 +215,120 @@ impl Worker {
                     if new_blocks.len() != 0 {
                         self.server.broadcast(Message::NewBlockHashes(new_blocks));
                     }
+                    self.filters.deliver(&self.server, &blockchain, &inserted);
+                }
+                Message::GetHeaders(hashes) => {
+                    let blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+                    let headers: Vec<_> = hashes
+                        .iter()
+                        .filter_map(|h| blockchain.block_map.get(h))
+                        .map(|b| b.header.clone())
+                        .collect();
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+                Message::Headers(headers) => {
+                    let mut blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+                    if !blockchain.light {
+                        continue;
+                    }
+                    let mut orph_buff = metrics::lock(&self.orph_buff, LockKind::Orphans);
+                    let mut missing: Vec<H256> = Vec::new();
+                    for header in headers {
+                        let hash = header.hash();
+                        if hash > header.difficulty || header.difficulty != blockchain.spec.difficulty {
+                            continue;
+                        }
+                        if header.merkle_scheme().is_none() {
+                            warn!("Dropping header {} of unknown version {}", hash, header.version);
+                            continue;
+                        }
+                        if blockchain.block_map.contains_key(&hash) {
+                            continue;
+                        }
+                        if !blockchain.block_map.contains_key(&header.parent) {
+                            // keep it until the parent arrives, as for full blocks
+                            missing.push(header.parent);
+                            orph_buff.entry(header.parent).or_insert(Block {
+                                header,
+                                content: crate::types::block::Content { content: Vec::new() },
+                            });
+                            continue;
+                        }
+                        if let Err(e) = blockchain.check_version(&header) {
+                            warn!("Dropping header {}: {}", hash, e);
+                            continue;
+                        }
+                        blockchain.insert_header(&header);
+                        let mut hash = hash;
+                        while let Some(orphan) = orph_buff.remove(&hash) {
+                            if let Err(e) = blockchain.check_version(&orphan.header) {
+                                warn!("Dropping header {}: {}", orphan.hash(), e);
+                                break;
+                            }
+                            hash = orphan.hash();
+                            blockchain.insert_header(&orphan.header);
+                        }
+                    }
+                    METRICS.orphan_blocks.set(orph_buff.len() as f64);
+                    missing.retain(|h| !blockchain.block_map.contains_key(h));
+                    if !missing.is_empty() {
+                        peer.write(Message::GetHeaders(missing));
+                    }
+                }
+                Message::SetFilter(addresses) => {
+                    let blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+                    if blockchain.light {
+                        continue;
+                    }
+                    let addresses: HashSet<Address> = addresses.into_iter().take(MAX_FILTER_ADDRESSES).collect();
+                    // send what the peer missed so far, then keep it posted as blocks arrive
+                    let proven: Vec<_> = blockchain
+                        .all_blocks_in_longest_chain()
+                        .iter()
+                        .flat_map(|h| blockchain.filter_block(h, &addresses))
+                        .collect();
+                    debug!("Peer {} set a filter of {} addresses", peer.addr(), addresses.len());
+                    self.filters.set(*peer.addr(), addresses);
+                    if !proven.is_empty() {
+                        peer.write(Message::FilteredTransactions(proven));
+                    }
+                }
+                Message::FilteredTransactions(proven) => {
+                    let mut blockchain = metrics::lock(&self.blockchain, LockKind::Blockchain);
+                    if !blockchain.light {
+                        continue;
+                    }
+                    let mut trans_memopool = metrics::lock(&self.trans_memopool, LockKind::Mempool);
+                    let mut missing: Vec<H256> = Vec::new();
+                    for p in proven {
+                        let tx_hash = p.proof.tx_hash;
+                        let block_hash = p.proof.block_hash;
+                        match blockchain.add_proven(p) {
+                            Ok(known) => {
+                                // our own transactions are out of the mempool once mined
+                                trans_memopool.trans_map.remove(&tx_hash);
+                                if !known && !missing.contains(&block_hash) {
+                                    missing.push(block_hash);
+                                }
+                            }
+                            Err(e) => warn!("Invalid proof from {}: {}", peer.addr(), e),
+                        }
+                    }
+                    METRICS.mempool_size.set(trans_memopool.trans_map.len() as f64);
+                    if !missing.is_empty() {
+                        peer.write(Message::GetHeaders(missing));
+                    }
                 }
                 // receive transaction hashes and find transaction hash not in mempool 
                 Message::NewTransactionHashes(vec_transaction_hashs) => {
+                    if metrics::lock(&self.blockchain, LockKind::Blockchain).light {
+                        // a light node only relays its own transactions
+                        continue;
+                    }
                     // println!("NewTransactionHashes");
-                    let mut trans_memopool = self.trans_memopool.lock().unwrap();
+                    let trans_memopool = metrics::lock(&self.trans_memopool, LockKind::Mempool);
                     let mut vec_hash: Vec<H256> = Vec::new();
                     for trans_hash in vec_transaction_hashs {
                         if trans_memopool.trans_map.get(&trans_hash).is_none() {
@@ -200,8 +342,8 @@ impl Worker {
                 // receive transaction hashes and reply trans
                 Message::GetTransactions(vec_transaction_hashs) => {
                     // println!("GetTransactions");
-                    let mut trans_memopool = self.trans_memopool.lock().unwrap();
-                    let mut vec_trans: Vec<SignedTransaction> = Vec::new();
+                    let trans_memopool = metrics::lock(&self.trans_memopool, LockKind::Mempool);
+                    let mut vec_trans: Vec<LedgerTransaction> = Vec::new();
                     for trans_hash in vec_transaction_hashs {
                         if !trans_memopool.trans_map.get(&trans_hash).is_none() {
                             vec_trans.push(trans_memopool.trans_map.get(&trans_hash).unwrap().clone());
@@ -211,29 +353,27 @@ impl Worker {
                         peer.write(Message::Transactions(vec_trans));
                     }
                 }
-                //receive transaction, insert transcation and broadcast inserted transaction hashes
+                //receive transaction, insert transcation and broadcast inserted transaction hashes,
+                // including those that replace one with the same nonce for a higher fee
                 Message::Transactions(vec_transactions) => {
                     // println!("Transactions");
-                    let mut trans_memopool = self.trans_memopool.lock().unwrap();
-                    let mut state = self.state.lock().unwrap();
+                    let mut trans_memopool = metrics::lock(&self.trans_memopool, LockKind::Mempool);
+                    let state = metrics::lock(&self.state, LockKind::State);
                     let mut vec_hash: Vec<H256> = Vec::new();
                     for trans in vec_transactions {
-                        let check_result = verify(&trans.transaction, &trans.public_key, &trans.signature, &state);
-                        if check_result {
+                        if state.check(&trans).is_ok() {
                             let trans_hash = trans.hash();
-                            if trans_memopool.trans_map.get(&trans_hash).is_none() {
-                                trans_memopool.trans_map.insert(trans_hash, trans.clone());
+                            if let Ok(true) = trans_memopool.insert(trans) {
                                 // println!("1 {:?}", trans_memopool.trans_map.len());
                                 vec_hash.push(trans_hash);
                             }
                         }
                     }
+                    METRICS.mempool_size.set(trans_memopool.trans_map.len() as f64);
                     if vec_hash.len() > 0 {
                         self.server.broadcast(Message::NewTransactionHashes(vec_hash));
                     }
                 }
-                _ => unimplemented!()
-               
             }
         }
     }
@@ -325,4 +465,81 @@ impl TestMsgSender {
 //     }
 // }
 
+#[cfg(test)]
+mod version_test {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::types::block::{Content, Header, CURRENT_VERSION, LEGACY_VERSION, TAGGED_VERSION};
+    use crate::types::merkle::MerkleTree;
+    use ntest::timeout;
+
+    /// An empty block on `parent` with `version` that meets the difficulty, committing to the
+    /// state after it if the version has a state root
+    fn mine(blockchain: &Blockchain, parent: H256, version: u32) -> Block {
+        let mut header = Header {
+            version,
+            parent,
+            nonce: 0,
+            difficulty: blockchain.spec.difficulty,
+            timestamp: 1,
+            merkle_root: H256::default(),
+            state_root: H256::default(),
+            beneficiary: Address::default(),
+        };
+        header.merkle_root = MerkleTree::with_scheme(&Vec::<LedgerTransaction>::new(), header.merkle_scheme().unwrap()).root();
+        let mut block = Block { header, content: Content { content: Vec::new() } };
+        if block.header.has_state_root() {
+            let height = blockchain.block_seq[&parent] as u64 + 1;
+            block.header.state_root = blockchain.state.lock().unwrap().root_after(&block, height).unwrap();
+        }
+        while block.hash() > block.header.difficulty {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    /// A worker on a chain of `spec`, and a function sending it a block that tells whether the
+    /// block was taken
+    fn start(spec: ChainSpec) -> (Arc<Mutex<Blockchain>>, impl Fn(&Block) -> bool) {
+        let state = crate::types::ledger::new(&spec);
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(&spec, &state)));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let trans_memopool = Arc::new(Mutex::new(TransactionMemopool::new()));
+        Worker::new(1, msg_chan, &server, &blockchain, &trans_memopool, &state, &Filters::new()).start();
+        let chain = Arc::clone(&blockchain);
+        // the worker announces every block that meets the difficulty, whether it takes it or not
+        let send = move |block: &Block| {
+            test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+            server_receiver.recv();
+            chain.lock().unwrap().block_map.contains_key(&block.hash())
+        };
+        (blockchain, send)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reject_downgraded_blocks() {
+        let (blockchain, send) = start(ChainSpec::default());
+        let genesis = blockchain.lock().unwrap().tip();
+        let legacy = mine(&blockchain.lock().unwrap(), genesis, LEGACY_VERSION);
+        assert!(!send(&legacy));
+        // a block without a state root, which light clients could not check accounts against
+        let tagged = mine(&blockchain.lock().unwrap(), genesis, TAGGED_VERSION);
+        assert!(!send(&tagged));
+        let current = mine(&blockchain.lock().unwrap(), genesis, CURRENT_VERSION);
+        assert!(send(&current));
+
+        // older versions up to the activation height, but never below the parent
+        let (blockchain, send) = start(ChainSpec { current_version_height: 3, ..ChainSpec::default() });
+        let genesis = blockchain.lock().unwrap().tip();
+        let tagged = mine(&blockchain.lock().unwrap(), genesis, TAGGED_VERSION);
+        assert!(send(&tagged));
+        let current = mine(&blockchain.lock().unwrap(), tagged.hash(), CURRENT_VERSION);
+        assert!(send(&current));
+        let downgraded = mine(&blockchain.lock().unwrap(), current.hash(), TAGGED_VERSION);
+        assert!(!send(&downgraded));
+    }
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
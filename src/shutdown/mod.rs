@@ -0,0 +1,66 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use log::{error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit status of a node that shut down cleanly
+pub const EXIT_OK: i32 = 0;
+/// Exit status when persistent data could not be flushed on shutdown
+pub const EXIT_FLUSH_FAILED: i32 = 1;
+/// Exit status when a second signal cuts a graceful shutdown short, as shells report SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Asks the node to shut down gracefully. It is cloned into the signal handler and the API server.
+#[derive(Clone)]
+pub struct Handle {
+    chan: Sender<String>,
+}
+
+/// Create a shutdown trigger, and the receiver `main` waits on.
+pub fn new() -> (Handle, Receiver<String>) {
+    // one pending request is enough, later ones are dropped
+    let (sender, receiver) = bounded(1);
+    (Handle { chan: sender }, receiver)
+}
+
+impl Handle {
+    pub fn trigger(&self, reason: &str) {
+        if self.chan.try_send(reason.to_string()).is_err() {
+            warn!("Shutdown already in progress");
+        }
+    }
+
+    /// Trigger a shutdown on SIGINT or SIGTERM. A second signal exits at once.
+    pub fn handle_signals(&self) -> std::io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = self.clone();
+        thread::Builder::new().name("signals".to_string()).spawn(move || {
+            let mut received = false;
+            for signal in signals.forever() {
+                if received {
+                    error!("Received a second signal, exiting without cleaning up");
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+                received = true;
+                let name = if signal == SIGINT { "SIGINT" } else { "SIGTERM" };
+                info!("Received {}, shutting down (send it again to exit immediately)", name);
+                handle.trigger(name);
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Poll `done` until it holds or `timeout` elapses. Returns whether it held.
+pub fn wait_for(timeout: Duration, done: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while !done() {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
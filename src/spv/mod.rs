@@ -0,0 +1,330 @@
+use crate::types::address::Address;
+use crate::types::block::Header;
+use crate::types::hash::{Hashable, H256};
+use crate::types::state_tree::{self, StateProof};
+use crate::types::transaction::Amount;
+use crate::types::ledger::LedgerTransaction;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Evidence that a transaction is in a block, as served by `GET /tx/{hash}/proof`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionProof {
+    pub tx_hash: H256,
+    pub block_hash: H256,
+    pub header: Header,
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Number of transactions in the block
+    pub leaf_count: usize,
+    /// Sibling hashes from the leaf up to (not including) the Merkle root
+    pub siblings: Vec<H256>,
+}
+
+impl TransactionProof {
+    /// Check that the proof is consistent: the header is that of the block, and the siblings lead
+    /// from the transaction to its Merkle root. Whether the block is on a chain is up to the caller.
+    pub fn check(&self) -> Result<(), String> {
+        if self.header.hash() != self.block_hash {
+            return Err("header does not match the block hash".to_string());
+        }
+        let scheme = match self.header.merkle_scheme() {
+            Some(s) => s,
+            None => return Err(format!("unknown block version {}", self.header.version)),
+        };
+        if !scheme.verify(&self.header.merkle_root, &self.tx_hash, &self.siblings, self.index, self.leaf_count) {
+            return Err(format!("transaction {} is not in block {}", self.tx_hash, self.block_hash));
+        }
+        Ok(())
+    }
+}
+
+/// A transaction delivered to a light node, with the proof that it is in a block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvenTransaction {
+    pub transaction: LedgerTransaction,
+    pub proof: TransactionProof,
+}
+
+impl ProvenTransaction {
+    pub fn check(&self) -> Result<(), String> {
+        if self.transaction.hash() != self.proof.tx_hash {
+            return Err(format!("transaction does not match proof for {}", self.proof.tx_hash));
+        }
+        self.proof.check()
+    }
+}
+
+/// Evidence of the nonce and balance of an account after a block, as served by
+/// `GET /account/{address}/proof`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub address: Address,
+    /// Nonce and balance, or `None` if there is no such account
+    pub account: Option<(u32, Amount)>,
+    /// Hash of the contract or oracle feed at the address, if it is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract: Option<H256>,
+    pub block_hash: H256,
+    pub header: Header,
+    pub proof: StateProof,
+}
+
+impl AccountProof {
+    /// Check that the header is that of the block and commits to the account. Whether the block is
+    /// on a chain is up to the caller.
+    pub fn check(&self) -> Result<(), String> {
+        if self.header.hash() != self.block_hash {
+            return Err("header does not match the block hash".to_string());
+        }
+        if !self.header.has_state_root() {
+            return Err(format!("block {} has no state root", self.block_hash));
+        }
+        if !state_tree::verify(&self.header.state_root, &self.address, self.account, self.contract.as_ref(), &self.proof) {
+            return Err(format!("account {} does not match the state of block {}", self.address, self.block_hash));
+        }
+        Ok(())
+    }
+}
+
+/// Chain of block headers held by a light client, which checks transaction proofs without
+/// downloading block contents.
+pub struct HeaderChain {
+    headers: HashMap<H256, Header>,
+    heights: HashMap<H256, u64>,
+    tip: H256,
+}
+
+impl HeaderChain {
+    /// Start a chain at the genesis header of the network the client follows
+    pub fn new(genesis: Header) -> Self {
+        let hash = genesis.hash();
+        let mut headers = HashMap::new();
+        let mut heights = HashMap::new();
+        headers.insert(hash, genesis);
+        heights.insert(hash, 0);
+        HeaderChain { headers, heights, tip: hash }
+    }
+
+    /// Add a header whose parent is known, after checking its proof of work. The difficulty is
+    /// fixed by the chain spec, so it must match the parent's.
+    pub fn insert(&mut self, header: Header) -> Result<(), String> {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return Ok(());
+        }
+        let parent = match self.headers.get(&header.parent) {
+            Some(p) => p,
+            None => return Err(format!("unknown parent {} of header {}", header.parent, hash)),
+        };
+        if header.difficulty != parent.difficulty {
+            return Err(format!("header {} changes the difficulty", hash));
+        }
+        if header.merkle_scheme().is_none() {
+            return Err(format!("header {} has unknown version {}", hash, header.version));
+        }
+        if hash > header.difficulty {
+            return Err(format!("header {} does not meet its difficulty", hash));
+        }
+        let height = self.heights[&header.parent] + 1;
+        self.headers.insert(hash, header);
+        self.heights.insert(hash, height);
+        if height > self.heights[&self.tip] {
+            self.tip = hash;
+        }
+        Ok(())
+    }
+
+    pub fn tip(&self) -> H256 {
+        self.tip
+    }
+
+    pub fn height(&self) -> u64 {
+        self.heights[&self.tip]
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Header> {
+        self.headers.get(hash)
+    }
+
+    /// Number of blocks from `hash` to the tip, counting both, if `hash` is on the longest chain
+    pub fn confirmations(&self, hash: &H256) -> Option<u64> {
+        let height = *self.heights.get(hash)?;
+        let mut cur = self.tip;
+        while self.heights[&cur] > height {
+            cur = self.headers[&cur].parent;
+        }
+        if cur == *hash {
+            Some(self.heights[&self.tip] - height + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Check that the proof places its transaction in a block of the longest chain, returning
+    /// the number of confirmations the transaction has.
+    pub fn verify(&self, proof: &TransactionProof) -> Result<u64, String> {
+        proof.check()?;
+        self.confirmed(&proof.block_hash)
+    }
+
+    /// Check that the proof gives the account as of a block of the longest chain, returning the
+    /// number of confirmations of the block.
+    pub fn verify_account(&self, proof: &AccountProof) -> Result<u64, String> {
+        proof.check()?;
+        self.confirmed(&proof.block_hash)
+    }
+
+    fn confirmed(&self, hash: &H256) -> Result<u64, String> {
+        if !self.headers.contains_key(hash) {
+            return Err(format!("unknown block {}", hash));
+        }
+        self.confirmations(hash)
+            .ok_or_else(|| format!("block {} is not on the longest chain", hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::{Allocation, ChainSpec};
+    use crate::blockchain::Blockchain;
+    use crate::types::address::Address;
+    use crate::types::block::{Block, Content, CURRENT_VERSION};
+    use crate::types::key_pair;
+    use crate::types::merkle::{MerkleTree, Scheme};
+    use crate::types::ledger;
+    use crate::types::transaction::generate_random_signed_transaction;
+    use ring::signature::KeyPair;
+
+    /// A block on top of `parent` holding `count` random transactions, mined against an easy target
+    fn mine(parent: &Header, count: usize) -> Block {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let content: Vec<LedgerTransaction> = (0..count)
+            .map(|nonce| generate_random_signed_transaction(sender, sender, 1, nonce as u32, &key).into())
+            .collect();
+        let mut header = Header {
+            version: CURRENT_VERSION,
+            parent: parent.hash(),
+            nonce: 0,
+            difficulty: parent.difficulty,
+            timestamp: parent.timestamp + 1,
+            merkle_root: MerkleTree::with_scheme(&content, Scheme::Tagged).root(),
+            state_root: H256::default(),
+            beneficiary: parent.beneficiary,
+        };
+        seal(&mut header);
+        Block { header, content: Content { content } }
+    }
+
+    fn seal(header: &mut Header) {
+        while header.hash() > header.difficulty {
+            header.nonce += 1;
+        }
+    }
+
+    #[test]
+    fn verify_against_headers() {
+        let spec = ChainSpec {
+            difficulty: [0x0f; 32].into(),
+            ..Default::default()
+        };
+        let state = ledger::new(&spec);
+        let mut blockchain = Blockchain::new(&spec, &state);
+        let genesis = spec.genesis();
+        let b1 = mine(&genesis.header, 3);
+        let b2 = mine(&b1.header, 1);
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+
+        let mut headers = HeaderChain::new(genesis.header.clone());
+        headers.insert(b1.header.clone()).unwrap();
+        headers.insert(b2.header.clone()).unwrap();
+        assert_eq!(headers.height(), 2);
+
+        let tx = b1.content.content[2].hash();
+        let proof = blockchain.transaction_proof(&tx).unwrap();
+        assert_eq!((proof.index, proof.leaf_count), (2, 3));
+        assert_eq!(headers.verify(&proof), Ok(2));
+
+        let mut forged = proof.clone();
+        forged.tx_hash = b1.content.content[0].hash();
+        assert!(headers.verify(&forged).is_err());
+
+        // a fork that loses to the longest chain gives no confirmations
+        let fork = mine(&genesis.header, 1);
+        headers.insert(fork.header.clone()).unwrap();
+        blockchain.insert(&fork);
+        let proof = blockchain.transaction_proof(&fork.content.content[0].hash());
+        assert!(proof.is_none());
+    }
+
+    #[test]
+    fn light_chain_waits_for_headers() {
+        let spec = ChainSpec {
+            difficulty: [0x0f; 32].into(),
+            ..Default::default()
+        };
+        let state = ledger::new(&spec);
+        let mut full = Blockchain::new(&spec, &state);
+        let mut light = Blockchain::new_light(&spec, &state);
+        let b1 = mine(&spec.genesis().header, 2);
+        full.insert(&b1);
+
+        let tx = &b1.content.content[1];
+        let addresses = vec![tx.addresses()[1]].into_iter().collect();
+        let proven = full.filter_block(&b1.hash(), &addresses);
+        assert_eq!(proven.len(), 2);
+
+        // the proof arrives before the header
+        assert_eq!(light.add_proven(proven[1].clone()), Ok(false));
+        assert!(light.transaction_proof(&tx.hash()).is_none());
+        light.insert_header(&b1.header);
+        assert_eq!(light.transaction_proof(&tx.hash()).unwrap().index, 1);
+        assert_eq!(light.all_transactions_in_longest_chain()[1], vec![tx.hash().to_string()]);
+
+        let mut forged = proven[0].clone();
+        forged.transaction = tx.clone();
+        assert!(light.add_proven(forged).is_err());
+    }
+
+    #[test]
+    fn verify_account_against_headers() {
+        let rich = Address::from([7; 20]);
+        let spec = ChainSpec {
+            difficulty: [0x0f; 32].into(),
+            allocations: vec![Allocation { address: rich, balance: 50 }],
+            ..Default::default()
+        };
+        let state = ledger::new(&spec);
+        let mut blockchain = Blockchain::new(&spec, &state);
+        let genesis = spec.genesis();
+        let mut b1 = mine(&genesis.header, 2);
+        assert!(state.lock().unwrap().update(&b1, 1).is_err());
+        b1.header.state_root = state.lock().unwrap().root_after(&b1, 1).unwrap();
+        seal(&mut b1.header);
+        state.lock().unwrap().update(&b1, 1).unwrap();
+        blockchain.insert(&b1);
+        let mut headers = HeaderChain::new(genesis.header.clone());
+        headers.insert(b1.header.clone()).unwrap();
+
+        let proof = blockchain.account_proof(&rich, None).unwrap();
+        assert_eq!(proof.account, Some((0, 50)));
+        assert_eq!(headers.verify_account(&proof), Ok(1));
+        let mut forged = proof.clone();
+        forged.account = Some((0, 51));
+        assert!(headers.verify_account(&forged).is_err());
+
+        // the transfers in the block come from an address without an account, so they are skipped
+        let sender = b1.content.content[0].addresses()[0];
+        let proof = blockchain.account_proof(&sender, Some(b1.hash())).unwrap();
+        assert_eq!(proof.account, None);
+        assert_eq!(headers.verify_account(&proof), Ok(1));
+        let absent = blockchain.account_proof(&Address::from([8; 20]), None).unwrap();
+        assert_eq!(absent.account, None);
+        assert_eq!(headers.verify_account(&absent), Ok(1));
+        // the genesis block predates state roots
+        assert!(blockchain.account_proof(&rich, Some(genesis.hash())).is_err());
+    }
+}
@@ -1,99 +1,156 @@
 pub mod tx_generator;
+pub mod workload;
 
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::time;
+use std::time::{Duration, Instant};
 
 use std::thread;
 
+use crate::metrics::METRICS;
 use crate::network::message::Message;
 use crate::types::address::Address;
-use crate::types::block::Block;
-use crate::blockchain::Blockchain;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use crate::types::merkle::MerkleTree;
-use crate::types::block::*;
+use crate::types::ledger::{Ledger, LedgerTransaction};
 use crate::types::transaction::*;
+use crate::types::batch::Payment;
 use crate::network::server::Handle as ServerHandle;
-use crate::types::key_pair;
-use ring::signature::KeyPair;
-use std::{collections::{HashMap, HashSet}, ops::Add};
-use rand::{thread_rng, Rng};
+use crate::wallet::Wallet;
 
+use self::workload::{Plan, Workload};
 
-// use rand::Rng;
-use crate::types::hash::Hashable;
-use ring::signature::Ed25519KeyPair;
-
+/// How long the generator waits before looking again when no local account can send
+const IDLE: Duration = Duration::from_millis(10);
 
 enum ControlSignal {
-    Start(u64), // the number controls the theta of interval between transaction generation
+    Start(Workload), // the workload to send, replacing any being sent
     Update, // update the transaction generator, it may due to new transaction
+    Pause, // stop generating but keep the thread alive, so it can be started again
     Exit,
 }
 
 enum OperatingState {
     Paused,
-    Run(u64),
+    Run,
     ShutDown,
 }
 
+/// Snapshot of what the generator is doing, as reported by `/tx-generator/status`
+#[derive(Serialize, Clone, Debug)]
+pub struct Status {
+    pub state: String,
+    /// The workload last started, with its seed
+    pub workload: Option<Workload>,
+    /// Transfers sent, valid or not, leaving out the batch funding the local accounts
+    pub transactions_sent: u64,
+    /// Transfers made invalid on purpose
+    pub invalid_sent: u64,
+    /// Transactions the mempool of the node refused, which should be the invalid ones
+    pub rejected: u64,
+}
+
 pub struct TXGenerator {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    state: Arc<Mutex<State>>,
-    key: Ed25519KeyPair,
-    address: Address
+    state: Arc<Mutex<dyn Ledger>>,
+    trans_memopool: Arc<Mutex<TransactionMemopool>>,
+    /// Wallet holding the account of the node, which funds the local accounts
+    wallet: Arc<Mutex<Wallet>>,
+    /// Address of the account of the node, which the seed of a workload defaults to
+    address: Address,
+    status: Arc<Mutex<Status>>,
 }
 
 #[derive(Clone)]
 pub struct GeneratorHandle {
     /// Channel for sending signal to the transaction generator thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
 }
 
-pub fn new(server: &ServerHandle, state: &Arc<Mutex<State>>, addr: Address) -> (TXGenerator, GeneratorHandle) {
+/// A workload being sent
+struct Run {
+    plan: Plan,
+    /// Nonce of the transaction each local account is waiting to see applied, if any
+    pending: Vec<Option<u32>>,
+    /// Whether the local accounts missing from the state have been funded
+    funded: bool,
+    /// Local account to try sending from first
+    next: usize,
+    /// When the next transfer is due
+    due: Instant,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    state: &Arc<Mutex<dyn Ledger>>,
+    trans_memopool: &Arc<Mutex<TransactionMemopool>>,
+    wallet: &Arc<Mutex<Wallet>>,
+    address: Address,
+) -> (TXGenerator, GeneratorHandle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
-    let key = key_pair::random();
+    let status = Arc::new(Mutex::new(Status {
+        state: "paused".to_string(),
+        workload: None,
+        transactions_sent: 0,
+        invalid_sent: 0,
+        rejected: 0,
+    }));
     let generator = TXGenerator {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
         state: Arc::clone(state),
-        key: key,
-        address: addr,
+        trans_memopool: Arc::clone(trans_memopool),
+        wallet: Arc::clone(wallet),
+        address,
+        status: Arc::clone(&status),
     };
-   
+
 
     let handle = GeneratorHandle {
         control_chan: signal_chan_sender,
+        status,
     };
 
     (generator, handle)
 }
 
-// #[cfg(any(test,test_utilities))]
-// fn test_new() -> (Context, Handle, Receiver<Block>) {
-//     new(&Arc::new(Mutex::new(Blockchain::new())))
-// }
-
 impl GeneratorHandle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        self.send(ControlSignal::Exit);
     }
 
-    pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+    pub fn start(&self, workload: Workload) {
+        self.send(ControlSignal::Start(workload));
+    }
+
+    pub fn pause(&self) {
+        self.send(ControlSignal::Pause);
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        self.send(ControlSignal::Update);
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Whether the generator thread has exited and no longer accepts signals
+    pub fn is_stopped(&self) -> bool {
+        self.status.lock().unwrap().state == "stopped"
+    }
+
+    fn send(&self, signal: ControlSignal) {
+        // the generator thread is gone after an exit signal, so later signals are dropped
+        if self.control_chan.send(signal).is_err() {
+            warn!("Generator is stopped, ignoring control signal");
+        }
     }
 }
 
@@ -101,7 +158,7 @@ impl TXGenerator {
 
     pub fn start(mut self) {
         thread::Builder::new()
-            .name("miner".to_string())
+            .name("tx-generator".to_string())
             .spawn(move || {
                 self.generator_loop();
             })
@@ -109,119 +166,162 @@ impl TXGenerator {
         info!("Generator initialized into paused mode");
     }
 
+    fn set_state(&mut self, operating_state: OperatingState) {
+        let mut status = self.status.lock().unwrap();
+        match operating_state {
+            OperatingState::Paused => status.state = "paused".to_string(),
+            OperatingState::Run => status.state = "running".to_string(),
+            OperatingState::ShutDown => status.state = "stopped".to_string(),
+        }
+        self.operating_state = operating_state;
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal, run: &mut Option<Run>) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Generator shutting down");
+                self.set_state(OperatingState::ShutDown);
+            }
+            ControlSignal::Start(mut workload) => {
+                // the seed defaults to one of its own on each node, so their accounts differ
+                let mut seed = [0; 8];
+                seed.copy_from_slice(&self.address.as_ref()[..8]);
+                workload.seed.get_or_insert(u64::from_le_bytes(seed));
+                info!("Generator starting {} accounts at {} tps with seed {}", workload.accounts, workload.tps, workload.seed.unwrap());
+                self.status.lock().unwrap().workload = Some(workload.clone());
+                *run = Some(Run {
+                    pending: vec![None; workload.accounts],
+                    funded: false,
+                    plan: Plan::new(workload),
+                    next: 0,
+                    due: Instant::now(),
+                });
+                self.set_state(OperatingState::Run);
+            }
+            ControlSignal::Pause => {
+                info!("Generator paused");
+                self.set_state(OperatingState::Paused);
+            }
+            ControlSignal::Update => {
+                // the generator reads the state each time it sends, so there is nothing to update
+            }
+        }
+    }
+
     fn generator_loop(&mut self) {
         // main transaction generator loop
-        let mut sent_created_account = false;
-        let mut current_nonce = 0;
-        let mut other_accounts = Vec::new();
-        let mut other_accounts_hashSet = HashSet::new();
+        let mut run = None;
         loop {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
                     let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Generator shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Generator starting in continuous mode with theta {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
+                    self.handle_signal(signal, &mut run);
                     continue;
                 }
                 OperatingState::ShutDown => {
                     return;
                 }
                 _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Generator shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Generator starting in continuous mode with theta {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
-                    }
+                    Ok(signal) => self.handle_signal(signal, &mut run),
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => panic!("Generator control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-
-            // println!("Generator is running");
-            let state = self.state.lock().unwrap();
-            if sent_created_account == false {
-                sent_created_account = true;
-                let account = self.address;
-                let signedTransaction = generate_random_signed_transaction(account, account, 1000, 0, &self.key);
-                self.server.broadcast(Message::Transactions(vec![signedTransaction]));
+            let run = match (&self.operating_state, run.as_mut()) {
+                (OperatingState::Run, Some(run)) => run,
+                _ => continue,
+            };
+            let now = Instant::now();
+            if now < run.due {
+                // sleep in short steps so control signals are not kept waiting
+                thread::sleep((run.due - now).min(IDLE));
                 continue;
             }
-            if state.accounts.len() > 1 {
-                if state.accounts.len() > other_accounts.len() + 1 {
-                    for (account, _) in &state.accounts {
-                        let account = *account;
-                        if !other_accounts_hashSet.contains(&account) && account != self.address {
-                            other_accounts_hashSet.insert(account);
-                            other_accounts.push(account);
-                        }
+            if !self.send_next(run) {
+                thread::sleep(IDLE);
+            }
+        }
+    }
+
+    /// Fund the local accounts missing from the state from the account of the node, once, and
+    /// send a transfer from the next local account with nothing pending that can pay a fee,
+    /// returning whether there was one. Every transaction goes to the peers, which check it
+    /// themselves, and the ones that pass the check of this node into its mempool.
+    fn send_next(&mut self, run: &mut Run) -> bool {
+        let n = run.plan.addresses.len();
+        let mut txs: Vec<(LedgerTransaction, Result<(), String>)> = Vec::new();
+        let mut sent = None;
+        {
+            // the wallet is locked before the state, as the API does
+            let mut wallet = (!run.funded).then(|| self.wallet.lock().unwrap());
+            let ledger = self.state.lock().unwrap();
+            let state = match ledger.as_accounts() {
+                Some(state) => state,
+                // the API does not start the generator on a UTXO chain
+                None => return false,
+            };
+            let accounts = state.accounts();
+            let fee = |tx: &Transaction| state.min_fee(tx);
+            if let Some(wallet) = wallet.as_mut() {
+                let payments: Vec<Payment> = run
+                    .plan
+                    .addresses
+                    .iter()
+                    .filter(|address| !accounts.contains_key(address))
+                    .map(|&receiver| Payment { receiver, value: run.plan.workload.fund })
+                    .collect();
+                if !payments.is_empty() {
+                    match wallet.batch_transfer(&self.address, payments, Validity::default(), &*ledger) {
+                        Ok(batch) => txs.push((batch.into(), Ok(()))),
+                        Err(e) => warn!("Generator cannot fund its accounts from {}: {}", self.address, e),
                     }
                 }
-                let account_info = match state.accounts.get(&self.address) {
-                    Some(v) => v,
-                    None => {
-                        return;
-                    }
-                };
-                let account_info = *account_info;
-                if current_nonce == account_info.0 {
-                    current_nonce = account_info.0 + 1;
-                    let nonce = current_nonce;
-
-                    let old_value = account_info.1;
-                    if old_value <= 3 {
-                        continue;
-                    }
-                    let mut rng = rand::thread_rng();
-
-                    let rand_index = rng.gen_range(0..other_accounts.len());
-                    let receiver = other_accounts[rand_index];
-                    let value = rng.gen_range(1..old_value/2);
-                    let signedTransaction = generate_random_signed_transaction(self.address, receiver, value, nonce, &self.key);
-                    self.server.broadcast(Message::Transactions(vec![signedTransaction]));
-                    // println!("SENT from {:?} nonce {:?}", self.address, nonce);
+                run.funded = true;
+            }
+            let ready = (0..n).map(|k| (run.next + k) % n).find_map(|i| {
+                let (nonce, balance) = *accounts.get(&run.plan.addresses[i])?;
+                let template = Transaction { nonce, sender: run.plan.addresses[i], value: 1, ..Default::default() };
+                let free = run.pending[i].is_none_or(|pending| nonce >= pending);
+                (free && balance > fee(&template)).then_some((i, nonce, balance))
+            });
+            if let Some((i, nonce, balance)) = ready {
+                let (tx, invalid) = run.plan.transaction(i, nonce + 1, balance, fee);
+                if !invalid {
+                    run.pending[i] = Some(nonce + 1);
                 }
-                
-                
+                run.next = (i + 1) % n;
+                txs.push((tx.into(), Ok(())));
+                sent = Some(invalid);
             }
-            
-            // let signedTransaction = generate_random_signed_transaction();
-
-            // self.server.broadcast(Message::Transactions(vec![signedTransaction]));
-
-
-
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    // println!("theta is {:?}", i);
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+            for (tx, checked) in txs.iter_mut() {
+                *checked = ledger.check(tx);
+            }
+        }
+        if txs.is_empty() {
+            return false;
+        }
+        let mut rejected = 0;
+        {
+            let mut trans_memopool = self.trans_memopool.lock().unwrap();
+            for (tx, checked) in &txs {
+                if checked.clone().and_then(|_| trans_memopool.insert(tx.clone())).is_err() {
+                    rejected += 1;
                 }
             }
+            METRICS.mempool_size.set(trans_memopool.trans_map.len() as f64);
         }
+        self.server.broadcast(Message::Transactions(txs.into_iter().map(|(tx, _)| tx).collect()));
+        let mut status = self.status.lock().unwrap();
+        status.rejected += rejected;
+        if let Some(invalid) = sent {
+            status.transactions_sent += 1;
+            status.invalid_sent += invalid as u64;
+            if run.plan.workload.tps > 0.0 {
+                // a generator fallen behind does not make up for it in a burst
+                run.due = (run.due + Duration::from_secs_f64(1.0 / run.plan.workload.tps)).max(Instant::now());
+            }
+        }
+        sent.is_some()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,309 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+use crate::types::address::Address;
+use crate::types::transaction::{sign_transaction, Amount, SignedTransaction, Transaction, Validity};
+
+/// Most local accounts a workload can have
+pub const MAX_ACCOUNTS: usize = 10_000;
+
+/// How the receiver of a transaction is picked among the local accounts other than its sender
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Receivers {
+    Uniform,
+    /// The account of rank k, counting from 1, with weight 1/k^`zipf_exponent`
+    Zipf,
+    /// `hot_share` of the transactions to the first `hot_accounts` accounts, the rest to the others
+    HotSpot,
+}
+
+/// How the value of a transaction is picked. It is cut down to what the sender has left after
+/// the fee.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Values {
+    /// Always `value`
+    Fixed,
+    /// Uniform from `min_value` to `max_value`
+    Uniform,
+    /// Exponential with mean `value`, kept from `min_value` to `max_value`
+    Exponential,
+}
+
+/// What the tx generator sends, from the query parameters of `/tx-generator/start` or a workload
+/// file with the same fields in JSON. Every field is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Workload {
+    /// Local accounts to send from and to, with keys derived from the seed
+    pub accounts: usize,
+    /// Seed of the keys and of every random pick, by default taken from the address of the node
+    pub seed: Option<u64>,
+    /// Transactions per second to aim for, or 0 to send as fast as accounts are free. Each account
+    /// has one transaction pending at a time, so the accounts also bound the rate per block.
+    pub tps: f64,
+    pub receivers: Receivers,
+    pub zipf_exponent: f64,
+    pub hot_accounts: usize,
+    pub hot_share: f64,
+    pub values: Values,
+    pub value: Amount,
+    pub min_value: Amount,
+    pub max_value: Amount,
+    /// Share of the transactions made invalid on purpose, which every node should reject
+    pub invalid_ratio: f64,
+    /// What the account of the node pays each local account without an account in the state,
+    /// opening it. Local accounts with an allocation in the chain spec already have one.
+    pub fund: Amount,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            accounts: 10,
+            seed: None,
+            tps: 10.0,
+            receivers: Receivers::Uniform,
+            zipf_exponent: 1.0,
+            hot_accounts: 1,
+            hot_share: 0.9,
+            values: Values::Uniform,
+            value: 10,
+            min_value: 1,
+            max_value: 100,
+            invalid_ratio: 0.0,
+            fund: 1000,
+        }
+    }
+}
+
+impl Workload {
+    /// A workload from query parameters named as its fields. `theta`, the microseconds the
+    /// generator used to sleep between transactions, still sets `tps` when `tps` is not given.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let mut fields = Map::new();
+        for (name, value) in params {
+            let value = match serde_json::from_str::<Value>(value) {
+                Ok(number) if number.is_number() => number,
+                _ => Value::String(value.clone()),
+            };
+            fields.insert(name.clone(), value);
+        }
+        if let Some(theta) = fields.remove("theta") {
+            let theta = theta.as_u64().ok_or("theta must be a non-negative integer")?;
+            let tps = if theta == 0 { 0.0 } else { 1e6 / theta as f64 };
+            fields.entry("tps").or_insert(json!(tps));
+        }
+        Self::from_value(Value::Object(fields))
+    }
+
+    /// A workload from a JSON object, as in a workload file
+    pub fn from_value(value: Value) -> Result<Self, String> {
+        let workload: Self = serde_json::from_value(value).map_err(|e| format!("error parsing workload: {}", e))?;
+        workload.check()?;
+        Ok(workload)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.accounts < 2 || self.accounts > MAX_ACCOUNTS {
+            return Err(format!("a workload needs 2 to {} accounts", MAX_ACCOUNTS));
+        }
+        if !(self.tps.is_finite() && self.tps >= 0.0) {
+            return Err("tps must be a non-negative number".to_string());
+        }
+        if !(self.zipf_exponent.is_finite() && self.zipf_exponent >= 0.0) {
+            return Err("zipf_exponent must be a non-negative number".to_string());
+        }
+        if self.hot_accounts == 0 || self.hot_accounts >= self.accounts {
+            return Err("hot_accounts must be at least 1 and fewer than the accounts".to_string());
+        }
+        for (name, share) in [("hot_share", self.hot_share), ("invalid_ratio", self.invalid_ratio)] {
+            if !(0.0..=1.0).contains(&share) {
+                return Err(format!("{} must be from 0 to 1", name));
+            }
+        }
+        if self.value == 0 || self.min_value == 0 || self.fund == 0 {
+            return Err("value, min_value and fund must be positive".to_string());
+        }
+        if self.min_value > self.max_value {
+            return Err("min_value must be at most max_value".to_string());
+        }
+        Ok(())
+    }
+
+    /// The weight of each local account as a receiver, by rank
+    fn weights(&self) -> Vec<f64> {
+        let n = self.accounts;
+        (0..n)
+            .map(|k| match self.receivers {
+                Receivers::Uniform => 1.0,
+                Receivers::Zipf => 1.0 / ((k + 1) as f64).powf(self.zipf_exponent),
+                Receivers::HotSpot if k < self.hot_accounts => self.hot_share / self.hot_accounts as f64,
+                Receivers::HotSpot => (1.0 - self.hot_share) / (n - self.hot_accounts) as f64,
+            })
+            .collect()
+    }
+}
+
+/// Ways a transaction is made invalid, each rejected by the check of the mempool
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Invalid {
+    Signature,
+    Value,
+    Expired,
+}
+
+/// The local accounts of a workload and its random picks, all following from the seed
+pub struct Plan {
+    pub workload: Workload,
+    pub keys: Vec<Ed25519KeyPair>,
+    pub addresses: Vec<Address>,
+    rng: StdRng,
+    /// Running sums of the receiver weights
+    cumulative: Vec<f64>,
+}
+
+impl Plan {
+    /// The workload must have been checked and have its seed set
+    pub fn new(workload: Workload) -> Self {
+        let seed = workload.seed.unwrap_or(0);
+        let keys: Vec<_> = (0..workload.accounts as u64).map(|i| account_key(seed, i)).collect();
+        let addresses = keys.iter().map(|key| Address::from_public_key_bytes(key.public_key().as_ref())).collect();
+        let cumulative = workload
+            .weights()
+            .iter()
+            .scan(0.0, |sum, weight| {
+                *sum += weight;
+                Some(*sum)
+            })
+            .collect();
+        Plan { workload, keys, addresses, rng: StdRng::seed_from_u64(seed), cumulative }
+    }
+
+    /// The next transaction from local account `sender`, with `nonce` after it and `balance` to
+    /// spend on its value and fee, and whether it was made invalid. `fee` prices a transaction.
+    pub fn transaction(
+        &mut self,
+        sender: usize,
+        nonce: u32,
+        balance: Amount,
+        fee: impl Fn(&Transaction) -> Amount,
+    ) -> (SignedTransaction, bool) {
+        let receiver = self.receiver(sender);
+        let mut tx = Transaction {
+            nonce,
+            sender: self.addresses[sender],
+            receiver: self.addresses[receiver],
+            value: self.value(),
+            ..Default::default()
+        };
+        tx.fee = fee(&tx);
+        tx.value = tx.value.min(balance.saturating_sub(tx.fee)).max(1);
+        let invalid = if self.rng.gen_bool(self.workload.invalid_ratio) {
+            Some([Invalid::Signature, Invalid::Value, Invalid::Expired][self.rng.gen_range(0..3)])
+        } else {
+            None
+        };
+        match invalid {
+            Some(Invalid::Value) => tx.value = 0,
+            Some(Invalid::Expired) => tx.validity = Validity { expires_at_timestamp: Some(1), ..Default::default() },
+            _ => {}
+        }
+        let mut signed = sign_transaction(tx, &self.keys[sender]);
+        if invalid == Some(Invalid::Signature) {
+            signed.signature[0] ^= 1;
+        }
+        (signed, invalid.is_some())
+    }
+
+    /// A local account other than `sender`, by the receiver weights
+    fn receiver(&mut self, sender: usize) -> usize {
+        let before = if sender == 0 { 0.0 } else { self.cumulative[sender - 1] };
+        let weight = self.cumulative[sender] - before;
+        let total = self.cumulative[self.cumulative.len() - 1] - weight;
+        if total <= 0.0 {
+            // only the sender has any weight, so any other account will do
+            return (sender + self.rng.gen_range(1..self.cumulative.len())) % self.cumulative.len();
+        }
+        // skip over the weight of the sender
+        let mut x = self.rng.gen_range(0.0..total);
+        if x >= before {
+            x += weight;
+        }
+        let k = self.cumulative.partition_point(|&sum| sum <= x).min(self.cumulative.len() - 1);
+        if k == sender {
+            // rounding landed on the sender
+            return if sender == 0 { 1 } else { sender - 1 };
+        }
+        k
+    }
+
+    fn value(&mut self) -> Amount {
+        let w = &self.workload;
+        match w.values {
+            Values::Fixed => w.value,
+            Values::Uniform => self.rng.gen_range(w.min_value..=w.max_value),
+            Values::Exponential => {
+                let u: f64 = self.rng.gen();
+                let value = -(1.0 - u).ln() * w.value as f64;
+                (value as Amount).clamp(w.min_value, w.max_value)
+            }
+        }
+    }
+}
+
+/// The key of local account `i` of the workload with `seed`
+fn account_key(seed: u64, i: u64) -> Ed25519KeyPair {
+    let mut data = b"tx-generator".to_vec();
+    data.extend(seed.to_le_bytes());
+    data.extend(i.to_le_bytes());
+    Ed25519KeyPair::from_seed_unchecked(digest::digest(&digest::SHA256, &data).as_ref()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::verify_signature;
+
+    fn plan(params: &[(&str, &str)]) -> Plan {
+        let params = params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Plan::new(Workload::from_params(&params).unwrap())
+    }
+
+    #[test]
+    fn seeded_workloads() {
+        let params = [("seed", "7"), ("accounts", "20"), ("receivers", "zipf"), ("invalid_ratio", "0.2")];
+        let (mut a, mut b) = (plan(&params), plan(&params));
+        assert_eq!(a.addresses, b.addresses);
+        let mut received = [0; 20];
+        let mut invalid = 0;
+        for i in 0..2000 {
+            let (tx, bad) = a.transaction(i % 20, 1, 1000, |_| 0);
+            let (other, _) = b.transaction(i % 20, 1, 1000, |_| 0);
+            assert_eq!(tx.signature, other.signature);
+            assert_ne!(tx.transaction.receiver, tx.transaction.sender);
+            assert_eq!(bad, !verify_signature(&tx.transaction, &tx.public_key, &tx.signature) || tx.transaction.value == 0 || tx.transaction.validity.expires_at_timestamp.is_some());
+            received[a.addresses.iter().position(|r| *r == tx.transaction.receiver).unwrap()] += 1;
+            invalid += bad as usize;
+        }
+        // rank 1 gets about twice what rank 2 does and 20 times what rank 20 does
+        assert!(received[0] > received[1] && received[1] > received[19] * 5);
+        assert!((300..500).contains(&invalid));
+        assert_ne!(plan(&[("seed", "8")]).addresses, plan(&[("seed", "7")]).addresses);
+
+        let mut hot = plan(&[("receivers", "hot_spot"), ("hot_accounts", "2"), ("hot_share", "1")]);
+        for _ in 0..100 {
+            assert!(hot.receiver(5) < 2);
+            assert_eq!(hot.receiver(0), 1);
+        }
+        assert!(Workload::from_params(&std::iter::once(("theta".to_string(), "100".to_string())).collect()).unwrap().tps == 10_000.0);
+        assert!(Workload::from_value(json!({"accounts": 1})).is_err());
+        assert!(Workload::from_value(json!({"speed": 1})).is_err());
+    }
+}
@@ -1,8 +1,8 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use ring::digest::{Context, SHA256};
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct Address([u8; 20]);
 
 impl std::convert::From<&[u8; 20]> for Address {
@@ -19,6 +19,12 @@ impl std::convert::From<[u8; 20]> for Address {
     }
 }
 
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let start = if let Some(precision) = f.precision() {
@@ -61,6 +67,39 @@ impl Address {
         Address::from(v)
     }
 }
+
+impl std::str::FromStr for Address {
+    type Err = hex::FromHexError;
+
+    /// Parse an address from its 40-character hex representation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut buffer: [u8; 20] = [0; 20];
+        hex::decode_to_slice(s.trim_start_matches("0x"), &mut buffer)?;
+        Ok(Address(buffer))
+    }
+}
+
+/// Hex in human-readable formats such as JSON, the raw bytes in bincode.
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        } else {
+            <[u8; 20]>::deserialize(deserializer).map(Address)
+        }
+    }
+}
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -0,0 +1,185 @@
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+
+use super::address::Address;
+use super::hash::{Hashable, H256};
+use super::transaction::{sign, Amount, Transfer, Validity};
+
+/// An amount a transfer pays to one receiver
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Payment {
+    pub receiver: Address,
+    pub value: Amount,
+}
+
+/// Payments from one sender to several receivers under a single nonce and signature. The state
+/// makes all of them or, if the sender cannot pay for all of them, none.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BatchTransaction {
+    pub nonce: u32,
+    pub sender: Address,
+    pub payments: Vec<Payment>,
+    pub fee: Amount,
+    #[serde(default)]
+    pub validity: Validity,
+}
+
+impl Transfer for BatchTransaction {
+    fn sender(&self) -> Address {
+        self.sender
+    }
+
+    fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    fn fee(&self) -> Amount {
+        self.fee
+    }
+
+    fn validity(&self) -> &Validity {
+        &self.validity
+    }
+
+    fn payments(&self) -> Vec<Payment> {
+        self.payments.clone()
+    }
+
+    fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SignedBatch {
+    pub batch: BatchTransaction,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedBatch {
+    pub fn sign(batch: BatchTransaction, key: &Ed25519KeyPair) -> Self {
+        let signature = sign(&batch, key);
+        SignedBatch {
+            batch,
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        }
+    }
+}
+
+impl Hashable for SignedBatch {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::LedgerModel;
+    use crate::types::key_pair;
+    use crate::types::ledger::LedgerTransaction;
+    use crate::blockchain::spec::{Allocation, ChainSpec};
+    use crate::types::block::generate_random_block;
+    use crate::types::ledger::Ledger;
+    use crate::types::transaction::{
+        generate_address, generate_random_signed_transaction, generate_random_transaction, sign_transaction,
+        verify_signature, State,
+    };
+
+    #[test]
+    fn sign_and_decode() {
+        let key = key_pair::random();
+        let batch = BatchTransaction {
+            nonce: 1,
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            payments: (1..4).map(|value| Payment { receiver: generate_address(), value }).collect(),
+            fee: 2,
+            ..Default::default()
+        };
+        let tx = SignedBatch::sign(batch.clone(), &key);
+        assert!(verify_signature(&tx.batch, &tx.public_key, &tx.signature));
+        let mut changed = tx.batch.clone();
+        changed.payments[2].value = 4;
+        assert!(!verify_signature(&changed, &tx.public_key, &tx.signature));
+
+        // each payment makes the batch bigger, and so its fee
+        let mut longer = batch.clone();
+        longer.payments.push(Payment { receiver: generate_address(), value: 1 });
+        assert_eq!(longer.size(), batch.size() + 28);
+
+        let tx = LedgerTransaction::from(tx);
+        for encoded in [tx.to_hex(), tx.to_json()] {
+            let decoded = LedgerTransaction::decode(&encoded, LedgerModel::Account).unwrap();
+            assert!(matches!(decoded, LedgerTransaction::Batch(_)));
+            assert_eq!(decoded.hash(), tx.hash());
+        }
+        assert!(LedgerTransaction::decode(&format!("{}00", tx.to_hex()), LedgerModel::Account).is_err());
+    }
+
+    #[test]
+    fn batches_and_fees() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receivers = [generate_address(), generate_address()];
+        let miner = generate_address();
+        let spec = ChainSpec {
+            allocations: vec![
+                Allocation { address: sender, balance: 10_000 },
+                Allocation { address: receivers[0], balance: 0 },
+                Allocation { address: receivers[1], balance: 0 },
+            ],
+            fee_per_byte: 2,
+            ..Default::default()
+        };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+
+        let batch = |nonce, payments: Vec<(Address, Amount)>, fee| {
+            let payments = payments.into_iter().map(|(receiver, value)| Payment { receiver, value }).collect();
+            let batch = BatchTransaction { nonce, sender, payments, fee, ..Default::default() };
+            LedgerTransaction::from(SignedBatch::sign(batch, &key))
+        };
+        let two = batch(1, vec![(receivers[0], 10), (receivers[1], 20)], 0);
+        let fee = state.min_fee(two.transfer().unwrap());
+        assert!(fee > state.min_fee(&generate_random_transaction(sender, receivers[0], 10, 1)));
+        assert!(state.check(&two).unwrap_err().starts_with("the fee must be at least"));
+        let two = batch(1, vec![(receivers[0], 10), (receivers[1], 20)], fee);
+        state.check(&two).unwrap();
+        assert!(state.check(&batch(1, Vec::new(), fee)).is_err());
+
+        // a batch the sender cannot pay in full makes none of its payments, and one paying an
+        // address without an account opens it
+        let overdrawn = batch(2, vec![(receivers[0], 5), (receivers[1], 10_000)], fee);
+        let stranger = generate_address();
+        let repeat = batch(4, vec![(receivers[0], 1), (receivers[0], 2)], fee);
+        let mut block = generate_random_block(&genesis.hash());
+        block.header.beneficiary = miner;
+        block.content.content = vec![two, overdrawn, batch(2, vec![(receivers[0], 1), (stranger, 2)], fee)];
+        block.header.state_root = state.root_after(&block, 1).unwrap();
+        state.update(&block, 1).unwrap();
+        let accounts = state.accounts();
+        assert_eq!(accounts[&sender], (2, 10_000 - 33 - 2 * fee));
+        assert_eq!(accounts[&receivers[0]], (0, 11));
+        assert_eq!(accounts[&receivers[1]], (0, 20));
+        assert_eq!(accounts[&stranger], (0, 2));
+        assert_eq!(accounts[&miner], (0, 2 * fee));
+
+        // the best paying first, but never a nonce before the one it follows
+        let cheap = LedgerTransaction::from(generate_random_signed_transaction(sender, receivers[0], 1, 3, &key));
+        let other = key_pair::random();
+        let other_sender = Address::from_public_key_bytes(other.public_key().as_ref());
+        let mut middle = generate_random_transaction(other_sender, receivers[0], 1, 1);
+        middle.fee = 1000;
+        let middle = LedgerTransaction::from(sign_transaction(middle, &other));
+        let selected = state.select(vec![&repeat, &middle, &cheap]);
+        let order: Vec<H256> = selected.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![middle.hash(), cheap.hash(), repeat.hash()]);
+    }
+}
@@ -4,29 +4,104 @@ use futures::TryFutureExt;
 use serde::{Serialize, Deserialize};
 use crate::types::hash::{H256, Hashable};
 use rand::Rng;
-use super::{merkle::MerkleTree, transaction::SignedTransaction};
+use super::{address::Address, ledger::LedgerTransaction, merkle::{MerkleTree, Scheme}};
+use std::collections::HashSet;
+
+/// Blocks whose transactions are committed to with `Scheme::Legacy`. Their headers hash the way
+/// they did before headers had a version.
+pub const LEGACY_VERSION: u32 = 1;
+/// Blocks whose transactions are committed to with `Scheme::Tagged`
+pub const TAGGED_VERSION: u32 = 2;
+/// Blocks that also commit to the accounts after them through `state_root`
+pub const CURRENT_VERSION: u32 = 3;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header{
+    /// Block format, see `CURRENT_VERSION`
+    pub version: u32,
     pub parent: H256,
     pub nonce: u32,
     pub difficulty: H256,
     pub timestamp: u128, // 待定
     pub merkle_root: H256, //待定
+    /// Root of the `StateTree` of the accounts after the block, zero before `CURRENT_VERSION`
+    pub state_root: H256,
+    /// Account credited with the block reward
+    pub beneficiary: Address,
 }
 impl Hashable for Header {
     fn hash(&self) -> H256 {
         //unimplemented!() https://docs.rs/ring/0.5.3/ring/digest/fn.digest.html 
         //https://stackoverflow.com/questions/28127165/how-to-convert-struct-to-u8
-        let data = bincode::serialize(&self).unwrap(); //u8 type
+        let data = match self.version {
+            LEGACY_VERSION => bincode::serialize(&LegacyHeader::from(self)).unwrap(),
+            // the layout before headers had a state root
+            TAGGED_VERSION => bincode::serialize(&(self.version, LegacyHeader::from(self))).unwrap(),
+            _ => bincode::serialize(&self).unwrap(), //u8 type
+        };
         return ring::digest::digest(&ring::digest::SHA256, &data).into();
     }
 }
 
+impl Header {
+    /// How the transactions of the block are committed to, if the version is known
+    pub fn merkle_scheme(&self) -> Option<Scheme> {
+        match self.version {
+            LEGACY_VERSION => Some(Scheme::Legacy),
+            TAGGED_VERSION | CURRENT_VERSION => Some(Scheme::Tagged),
+            _ => None,
+        }
+    }
+
+    /// Whether `state_root` commits to the accounts after the block
+    pub fn has_state_root(&self) -> bool {
+        self.version >= CURRENT_VERSION
+    }
+}
+
+/// Layout of headers before they had a version. Legacy headers are hashed in it (and
+/// `TAGGED_VERSION` headers in it after their version), and block stores written before then hold it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyHeader {
+    pub parent: H256,
+    pub nonce: u32,
+    pub difficulty: H256,
+    pub timestamp: u128,
+    pub merkle_root: H256,
+    pub beneficiary: Address,
+}
+
+impl From<&Header> for LegacyHeader {
+    fn from(header: &Header) -> Self {
+        LegacyHeader {
+            parent: header.parent,
+            nonce: header.nonce,
+            difficulty: header.difficulty,
+            timestamp: header.timestamp,
+            merkle_root: header.merkle_root,
+            beneficiary: header.beneficiary,
+        }
+    }
+}
+
+impl From<LegacyHeader> for Header {
+    fn from(header: LegacyHeader) -> Self {
+        Header {
+            version: LEGACY_VERSION,
+            parent: header.parent,
+            nonce: header.nonce,
+            difficulty: header.difficulty,
+            timestamp: header.timestamp,
+            merkle_root: header.merkle_root,
+            state_root: H256::default(),
+            beneficiary: header.beneficiary,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content{
-    pub content: Vec<SignedTransaction>, // 我们之前没有SignedTransaction类型，
-                                     //同时需要给SignedTransaction实现hashable
+    pub content: Vec<LedgerTransaction>,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
@@ -51,6 +126,31 @@ impl Block {
         self.header.difficulty
     }
 
+    /// The Merkle tree of the transactions, built as the block version says
+    pub fn merkle_tree(&self) -> MerkleTree {
+        MerkleTree::with_scheme(&self.content.content, self.header.merkle_scheme().unwrap_or_default())
+    }
+
+    /// Check that the transactions are the ones the header commits to
+    pub fn check_merkle_root(&self) -> Result<(), String> {
+        let scheme = match self.header.merkle_scheme() {
+            Some(s) => s,
+            None => return Err(format!("unknown block version {}", self.header.version)),
+        };
+        if scheme == Scheme::Legacy {
+            // repeating transactions can keep a legacy root, so a valid block could be forged
+            // into an invalid one with the same hash
+            let mut seen = HashSet::new();
+            if !self.content.content.iter().all(|tx| seen.insert(tx.hash())) {
+                return Err("legacy block repeats a transaction".to_string());
+            }
+        }
+        if MerkleTree::with_scheme(&self.content.content, scheme).root() != self.header.merkle_root {
+            return Err("transactions do not match the Merkle root".to_string());
+        }
+        Ok(())
+    }
+
     pub fn get_transactions(&self) -> Vec<String> {
         let mut txs: Vec<String> = Vec::new();
         for trans in &self.content.content {
@@ -68,13 +168,13 @@ pub fn generate_random_block(parent: &H256) -> Block {
     let signed_transactions = Vec::new();
     let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis(); //系统时间
 
-    let merkle_tree = MerkleTree::new(&signed_transactions);
+    let merkle_tree = MerkleTree::with_scheme(&signed_transactions, Scheme::Tagged);
     let merkle_root: H256 = merkle_tree.root(); //也可以H256类型
 
     let mut buffer: [u8; 32] = [0; 32];
     let difficulty: H256 = buffer.into(); //
 
-    let header = Header{ parent: *parent, nonce: nonce, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_root };
+    let header = Header{ version: CURRENT_VERSION, parent: *parent, nonce, difficulty, timestamp, merkle_root, state_root: H256::default(), beneficiary: Address::default() };
     let content = Content{ content: signed_transactions };
     Block{ header: header, content: content }
 }
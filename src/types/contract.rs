@@ -0,0 +1,124 @@
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::address::Address;
+use super::batch::Payment;
+use super::hash::{Hashable, H256};
+use super::transaction::{sign, Amount, Transfer, Validity};
+use crate::vm::Op;
+
+/// Domain of the hash a contract address is taken from, so it can never be the hash of a key
+const ADDRESS_TAG: &[u8] = b"contract";
+
+/// A deployed contract: its code and what it stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Contract {
+    /// The account that deployed it
+    pub owner: Address,
+    pub code: Vec<Op>,
+    pub storage: BTreeMap<u64, u64>,
+}
+
+impl Hashable for Contract {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+/// The address of the contract `sender` deploys with `nonce`
+pub fn contract_address(sender: &Address, nonce: u32) -> Address {
+    let mut data = ADDRESS_TAG.to_vec();
+    data.extend(sender.as_ref());
+    data.extend(nonce.to_le_bytes());
+    Address::from_public_key_bytes(&data)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ContractAction {
+    Deploy { code: Vec<Op> },
+    Call { contract: Address, args: Vec<u64> },
+}
+
+/// A deploy or call of a contract. It pays no one: the fee covers its size and its gas limit,
+/// and is taken whether or not the code runs to the end.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContractTransaction {
+    pub nonce: u32,
+    pub sender: Address,
+    pub action: ContractAction,
+    pub gas_limit: u64,
+    pub fee: Amount,
+    #[serde(default)]
+    pub validity: Validity,
+}
+
+impl ContractTransaction {
+    /// The contract the transaction deploys or calls
+    pub fn contract(&self) -> Address {
+        match &self.action {
+            ContractAction::Deploy { .. } => contract_address(&self.sender, self.nonce),
+            ContractAction::Call { contract, .. } => *contract,
+        }
+    }
+}
+
+impl Transfer for ContractTransaction {
+    fn sender(&self) -> Address {
+        self.sender
+    }
+
+    fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    fn fee(&self) -> Amount {
+        self.fee
+    }
+
+    fn validity(&self) -> &Validity {
+        &self.validity
+    }
+
+    fn payments(&self) -> Vec<Payment> {
+        Vec::new()
+    }
+
+    fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+
+    fn gas_limit(&self) -> u64 {
+        self.gas_limit
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedContractTransaction {
+    pub transaction: ContractTransaction,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedContractTransaction {
+    pub fn sign(transaction: ContractTransaction, key: &Ed25519KeyPair) -> Self {
+        let signature = sign(&transaction, key);
+        SignedContractTransaction {
+            transaction,
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        }
+    }
+}
+
+impl Hashable for SignedContractTransaction {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
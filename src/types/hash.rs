@@ -1,4 +1,4 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
 #[cfg(any(test, test_utilities))]
 use rand::Rng;
@@ -10,7 +10,7 @@ pub trait Hashable {
 }
 
 /// A SHA256 hash.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
 
 impl Hashable for H256 {
@@ -89,6 +89,39 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    /// Parse a hash from its 64-character hex representation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut buffer: [u8; 32] = [0; 32];
+        hex::decode_to_slice(s.trim_start_matches("0x"), &mut buffer)?;
+        Ok(H256(buffer))
+    }
+}
+
+/// Hex in human-readable formats such as JSON, the raw bytes in bincode.
+impl Serialize for H256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for H256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(H256)
+        }
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -7,3 +7,9 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Generate a random key pair, returned as a PKCS#8 document so that it can be stored.
+pub fn random_pkcs8() -> Vec<u8> {
+    let rng = rand::SystemRandom::new();
+    Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec()
+}
@@ -0,0 +1,506 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use super::address::Address;
+use super::batch::{Payment, SignedBatch};
+use super::block::Block;
+use super::contract::{ContractAction, SignedContractTransaction};
+use super::hash::{Hashable, H256};
+use super::multisig::MultisigTransaction;
+use super::oracle::SignedOracleReport;
+use super::transaction::{verify_signature, Accounts, Amount, SignedTransaction, State, Transfer};
+use super::utxo::{OutPoint, UtxoState, UtxoTransaction};
+use crate::blockchain::spec::{ChainSpec, LedgerModel};
+
+/// The state a chain keeps of balances, under either ledger model. The miner, network worker and
+/// API only go through this, so they work with both.
+pub trait Ledger: Send {
+    fn model(&self) -> LedgerModel;
+
+    /// Apply a block at the given height on top of its parent. A block that commits to the state
+    /// must commit to the state it results in.
+    fn update(&mut self, block: &Block, height: u64) -> Result<(), String>;
+
+    /// Root of the state after applying a block at the given height
+    fn root_after(&mut self, block: &Block, height: u64) -> Result<H256, String>;
+
+    /// Check a transaction before it goes into the mempool: its signatures, and anything else the
+    /// ledger can tell from the tip
+    fn check(&self, tx: &LedgerTransaction) -> Result<(), String>;
+
+    /// The transaction a block at `height` must start with, if any, or why it cannot be made
+    fn coinbase(&self, beneficiary: Address, height: u64) -> Result<Option<LedgerTransaction>, String>;
+
+    /// Of `candidates`, in order, those that can go into one block on top of the tip
+    fn select<'a>(&self, candidates: Vec<&'a LedgerTransaction>) -> Vec<&'a LedgerTransaction>;
+
+    /// Balance of an address at the tip
+    fn balance(&self, address: &Address) -> Amount;
+
+    /// Nonce of an address at the tip; zero for a ledger without nonces
+    fn nonce(&self, address: &Address) -> u32;
+
+    /// Outputs an address can spend at the tip; none for a ledger without outputs
+    fn unspent(&self, address: &Address) -> Vec<(OutPoint, Amount)>;
+
+    /// Nonce and balance of every address after a block, if it has been applied and not pruned
+    /// since. A ledger without nonces gives them as zero.
+    fn accounts_at(&self, hash: &H256) -> Option<Accounts>;
+
+    /// The account ledger, for the code that only works with accounts
+    fn as_accounts(&self) -> Option<&State>;
+
+    /// Prune the state of blocks more than this many blocks below the tip
+    fn set_history_depth(&mut self, depth: Option<u64>);
+}
+
+/// The ledger for the model of a chain spec, before its genesis block
+pub fn new(spec: &ChainSpec) -> Arc<Mutex<dyn Ledger>> {
+    match spec.ledger {
+        LedgerModel::Account => Arc::new(Mutex::new(State::new(spec))),
+        LedgerModel::Utxo => Arc::new(Mutex::new(UtxoState::new(spec))),
+    }
+}
+
+/// A transaction of either ledger model. A chain only takes those of the model in its spec.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerTransaction {
+    Account(SignedTransaction),
+    Utxo(UtxoTransaction),
+    /// A transfer from a multisig account, which the account model takes as well
+    Multisig(MultisigTransaction),
+    /// Payments to several receivers under one signature, which the account model takes as well
+    Batch(SignedBatch),
+    /// A deploy or call of a contract, which only the account model runs
+    Contract(SignedContractTransaction),
+    /// A value an oracle of the chain spec reports for a feed, which only the account model takes
+    Oracle(SignedOracleReport),
+}
+
+/// What the explorer shows of a transaction
+#[derive(Serialize, Debug, Clone)]
+pub struct TransactionSummary {
+    pub hash: String,
+    /// `transfer`, `multisig`, `batch`, `deploy`, `call`, `oracle` or `utxo`
+    pub kind: &'static str,
+    /// The sender of an account transfer, or the oracle of a report; the owners of the outputs a UTXO transaction spends
+    /// are not in it
+    pub sender: Option<Address>,
+    /// The receivers and what each gets: the outputs of a UTXO transaction
+    pub payments: Vec<Payment>,
+    /// The fee of an account transfer. A UTXO transaction leaves what its outputs do not take
+    /// to no one, and an oracle report pays none.
+    pub fee: Option<Amount>,
+    /// Bytes the fee is priced by: the transfer a sender signs, or the whole UTXO transaction
+    pub size: u64,
+    /// The contract a deploy or call runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contract: Option<Address>,
+}
+
+impl LedgerTransaction {
+    pub fn model(&self) -> LedgerModel {
+        match self {
+            LedgerTransaction::Account(_)
+            | LedgerTransaction::Multisig(_)
+            | LedgerTransaction::Batch(_)
+            | LedgerTransaction::Contract(_)
+            | LedgerTransaction::Oracle(_) => LedgerModel::Account,
+            LedgerTransaction::Utxo(_) => LedgerModel::Utxo,
+        }
+    }
+
+    pub fn as_account(&self) -> Option<&SignedTransaction> {
+        match self {
+            LedgerTransaction::Account(tx) => Some(tx),
+            _ => None,
+        }
+    }
+
+    /// The transfer of an account transaction, however it is signed and however many it pays
+    pub fn transfer(&self) -> Option<&dyn Transfer> {
+        match self {
+            LedgerTransaction::Account(tx) => Some(&tx.transaction),
+            LedgerTransaction::Multisig(tx) => Some(&tx.transaction),
+            LedgerTransaction::Batch(tx) => Some(&tx.batch),
+            LedgerTransaction::Contract(tx) => Some(&tx.transaction),
+            LedgerTransaction::Utxo(_) | LedgerTransaction::Oracle(_) => None,
+        }
+    }
+
+    /// Check the signatures, which needs no state
+    pub fn verify_signatures(&self) -> bool {
+        match self {
+            LedgerTransaction::Account(tx) => verify_signature(&tx.transaction, &tx.public_key, &tx.signature),
+            LedgerTransaction::Utxo(tx) => tx.verify_signatures(),
+            LedgerTransaction::Multisig(tx) => tx.verify_signatures(),
+            LedgerTransaction::Batch(tx) => verify_signature(&tx.batch, &tx.public_key, &tx.signature),
+            LedgerTransaction::Contract(tx) => verify_signature(&tx.transaction, &tx.public_key, &tx.signature),
+            LedgerTransaction::Oracle(tx) => tx.verify_signature(),
+        }
+    }
+
+    /// Addresses the transaction pays or is signed by. The owners of the outputs a UTXO
+    /// transaction spends are not in it, so only those of its keys are.
+    pub fn addresses(&self) -> Vec<Address> {
+        match self {
+            LedgerTransaction::Account(tx) => vec![tx.transaction.sender, tx.transaction.receiver],
+            LedgerTransaction::Multisig(tx) => vec![tx.transaction.sender, tx.transaction.receiver],
+            LedgerTransaction::Batch(tx) => std::iter::once(tx.batch.sender)
+                .chain(tx.batch.payments.iter().map(|payment| payment.receiver))
+                .collect(),
+            LedgerTransaction::Contract(tx) => vec![tx.transaction.sender, tx.transaction.contract()],
+            LedgerTransaction::Oracle(tx) => vec![tx.oracle()],
+            LedgerTransaction::Utxo(tx) => tx
+                .inputs
+                .iter()
+                .filter(|input| !input.public_key.is_empty())
+                .map(|input| Address::from_public_key_bytes(&input.public_key))
+                .chain(tx.outputs.iter().map(|output| output.address))
+                .collect(),
+        }
+    }
+
+    /// Hex of the bincode encoding, which starts with the variant tag, as accepted by
+    /// `sendTransaction`
+    pub fn to_hex(&self) -> String {
+        hex::encode(bincode::serialize(self).unwrap())
+    }
+
+    /// JSON keyed by the kind of transaction, as in `{"account": {...}}`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn summary(&self) -> TransactionSummary {
+        let hash = self.hash().to_string();
+        match (self, self.transfer()) {
+            (LedgerTransaction::Utxo(tx), _) => TransactionSummary {
+                hash,
+                kind: "utxo",
+                sender: None,
+                payments: tx
+                    .outputs
+                    .iter()
+                    .map(|output| Payment { receiver: output.address, value: output.value })
+                    .collect(),
+                fee: None,
+                size: bincode::serialized_size(tx).unwrap(),
+                contract: None,
+            },
+            (LedgerTransaction::Oracle(tx), _) => TransactionSummary {
+                hash,
+                kind: "oracle",
+                sender: Some(tx.oracle()),
+                payments: Vec::new(),
+                fee: None,
+                size: bincode::serialized_size(&tx.report).unwrap(),
+                contract: None,
+            },
+            (_, Some(transfer)) => TransactionSummary {
+                hash,
+                kind: match self {
+                    LedgerTransaction::Multisig(_) => "multisig",
+                    LedgerTransaction::Batch(_) => "batch",
+                    LedgerTransaction::Contract(tx) => match tx.transaction.action {
+                        ContractAction::Deploy { .. } => "deploy",
+                        ContractAction::Call { .. } => "call",
+                    },
+                    _ => "transfer",
+                },
+                sender: Some(transfer.sender()),
+                payments: transfer.payments(),
+                fee: Some(transfer.fee()),
+                size: transfer.size(),
+                contract: match self {
+                    LedgerTransaction::Contract(tx) => Some(tx.transaction.contract()),
+                    _ => None,
+                },
+            },
+            (_, None) => unreachable!("only UTXO transactions and oracle reports have no transfer"),
+        }
+    }
+
+    /// Parse a transaction written by `to_hex` or `to_json`, refusing one of another model
+    /// than the chain's. The hex must hold nothing after the transaction.
+    pub fn decode(s: &str, model: LedgerModel) -> Result<Self, String> {
+        let s = s.trim();
+        let tx: Self = if s.starts_with('{') {
+            serde_json::from_str(s).map_err(|e| format!("error decoding JSON transaction: {}", e))?
+        } else {
+            let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|e| format!("error decoding hex: {}", e))?;
+            let tx = bincode::deserialize(&bytes).map_err(|e| format!("error decoding transaction: {}", e))?;
+            if bincode::serialized_size(&tx).unwrap() != bytes.len() as u64 {
+                return Err("error decoding transaction: trailing bytes".to_string());
+            }
+            tx
+        };
+        if tx.model() != model {
+            let kind = match model {
+                LedgerModel::Account => "account",
+                LedgerModel::Utxo => "UTXO",
+            };
+            return Err(format!("this chain only takes {} transactions", kind));
+        }
+        Ok(tx)
+    }
+}
+
+impl Hashable for LedgerTransaction {
+    /// The hash of the transaction itself, so account transactions keep their hashes (and blocks
+    /// their Merkle roots) from before there were two models
+    fn hash(&self) -> H256 {
+        match self {
+            LedgerTransaction::Account(tx) => tx.hash(),
+            LedgerTransaction::Utxo(tx) => tx.hash(),
+            LedgerTransaction::Multisig(tx) => tx.hash(),
+            LedgerTransaction::Batch(tx) => tx.hash(),
+            LedgerTransaction::Contract(tx) => tx.hash(),
+            LedgerTransaction::Oracle(tx) => tx.hash(),
+        }
+    }
+}
+
+impl From<SignedTransaction> for LedgerTransaction {
+    fn from(tx: SignedTransaction) -> Self {
+        LedgerTransaction::Account(tx)
+    }
+}
+
+impl From<UtxoTransaction> for LedgerTransaction {
+    fn from(tx: UtxoTransaction) -> Self {
+        LedgerTransaction::Utxo(tx)
+    }
+}
+
+impl From<MultisigTransaction> for LedgerTransaction {
+    fn from(tx: MultisigTransaction) -> Self {
+        LedgerTransaction::Multisig(tx)
+    }
+}
+
+impl From<SignedBatch> for LedgerTransaction {
+    fn from(tx: SignedBatch) -> Self {
+        LedgerTransaction::Batch(tx)
+    }
+}
+
+impl From<SignedContractTransaction> for LedgerTransaction {
+    fn from(tx: SignedContractTransaction) -> Self {
+        LedgerTransaction::Contract(tx)
+    }
+}
+
+impl From<SignedOracleReport> for LedgerTransaction {
+    fn from(tx: SignedOracleReport) -> Self {
+        LedgerTransaction::Oracle(tx)
+    }
+}
+
+/// How many blocks apart the state history keeps the whole state, rather than what a block changed
+const CHECKPOINT_INTERVAL: u64 = 64;
+
+/// The entries of a state after a block
+struct Snapshot<K, V> {
+    parent: H256,
+    height: u64,
+    /// All entries at heights that are a multiple of `CHECKPOINT_INTERVAL`, otherwise only those
+    /// the block changed, with `None` for those it removed
+    entries: HashMap<K, Option<V>>,
+}
+
+/// Set the entries changed to their new values, removing those set to `None`
+fn apply_changes<K: Eq + Hash + Copy, V: Clone>(state: &mut HashMap<K, V>, changed: &HashMap<K, Option<V>>) {
+    for (k, v) in changed {
+        match v {
+            Some(v) => state.insert(*k, v.clone()),
+            None => state.remove(k),
+        };
+    }
+}
+
+/// A state a block is being applied to, taken from a `History`, which keeps the values of the
+/// entries the block writes from before it, so the state can be given back as it was. Reading
+/// goes through the map the state derefs to.
+pub struct Applying<K, V> {
+    state: HashMap<K, V>,
+    previous: HashMap<K, Option<V>>,
+    /// Whether the state is the one after the tip rather than a copy
+    in_place: bool,
+}
+
+impl<K: Eq + Hash + Copy, V: Clone> Applying<K, V> {
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.record(key);
+        self.state.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.record(*key);
+        self.state.remove(key)
+    }
+
+    fn record(&mut self, key: K) {
+        if !self.previous.contains_key(&key) {
+            self.previous.insert(key, self.state.get(&key).cloned());
+        }
+    }
+}
+
+impl<K, V> Deref for Applying<K, V> {
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &HashMap<K, V> {
+        &self.state
+    }
+}
+
+/// The state of a ledger after every block applied, as the changes each block made with a full
+/// checkpoint every `CHECKPOINT_INTERVAL` blocks, so a fork can be applied to the state of its
+/// parent.
+pub struct History<K, V> {
+    snapshots: HashMap<H256, Snapshot<K, V>>,
+    /// The state before the genesis block
+    initial: HashMap<K, V>,
+    /// The state after the tip, the first block applied at the greatest height
+    current: HashMap<K, V>,
+    /// Hash and height of the tip
+    tip: Option<(H256, u64)>,
+    /// Prune the state after blocks more than this many blocks below the tip. A block on a fork
+    /// from below that cannot be applied any more.
+    pub depth: Option<u64>,
+}
+
+impl<K: Eq + Hash + Copy, V: Clone> History<K, V> {
+    pub fn new(initial: HashMap<K, V>) -> Self {
+        History {
+            snapshots: HashMap::new(),
+            current: initial.clone(),
+            initial,
+            tip: None,
+            depth: None,
+        }
+    }
+
+    pub fn current(&self) -> &HashMap<K, V> {
+        &self.current
+    }
+
+    /// Height of the tip, if a block has been applied
+    pub fn height(&self) -> Option<u64> {
+        self.tip.map(|(_, height)| height)
+    }
+
+    /// The state after a block, if it has been applied and not pruned since
+    pub fn at(&self, hash: &H256) -> Option<HashMap<K, V>> {
+        if let Some((tip, _)) = self.tip {
+            if tip == *hash {
+                return Some(self.current.clone());
+            }
+        }
+        let mut snapshot = self.snapshots.get(hash)?;
+        let mut changes = Vec::new();
+        while !snapshot.height.is_multiple_of(CHECKPOINT_INTERVAL) {
+            changes.push(&snapshot.entries);
+            snapshot = self.snapshots.get(&snapshot.parent)?;
+        }
+        let mut state: HashMap<K, V> = snapshot
+            .entries
+            .iter()
+            .filter_map(|(k, v)| v.clone().map(|v| (*k, v)))
+            .collect();
+        for changed in changes.into_iter().rev() {
+            apply_changes(&mut state, changed);
+        }
+        Some(state)
+    }
+
+    /// The state a block at the given height applies to: the state after its parent, or the
+    /// initial state for the genesis block
+    pub fn before(&self, block: &Block, height: u64) -> Result<HashMap<K, V>, String> {
+        if height == 0 {
+            return Ok(self.initial.clone());
+        }
+        self.at(&block.get_parent())
+            .ok_or_else(|| format!("no state for parent block {}", block.get_parent()))
+    }
+
+    /// Whether a block at the given height goes on top of the tip, or is the genesis block of an
+    /// empty history, so that it applies to `current`
+    fn extends_tip(&self, block: &Block, height: u64) -> bool {
+        match self.tip {
+            Some((tip, _)) => block.get_parent() == tip,
+            None => height == 0,
+        }
+    }
+
+    /// The state a block at the given height applies to, for the block to change: the state after
+    /// the tip itself if the block goes on top of it, so that it is not copied, or else a copy of
+    /// the state after its parent. Give it back with `give_back`.
+    pub fn take(&mut self, block: &Block, height: u64) -> Result<Applying<K, V>, String> {
+        let (state, in_place) = if self.extends_tip(block, height) {
+            (std::mem::take(&mut self.current), true)
+        } else {
+            (self.before(block, height)?, false)
+        };
+        Ok(Applying { state, previous: HashMap::new(), in_place })
+    }
+
+    /// Give back a state taken with `take` as it was, returning the entries the block changed
+    /// with their values after it, `None` for those it removed
+    pub fn give_back(&mut self, applying: Applying<K, V>) -> HashMap<K, Option<V>> {
+        let Applying { mut state, previous, in_place } = applying;
+        let changed = previous.keys().map(|k| (*k, state.get(k).cloned())).collect();
+        if in_place {
+            apply_changes(&mut state, &previous);
+            self.current = state;
+        }
+        changed
+    }
+
+    /// Record the state after a block as the entries it changed
+    pub fn insert(&mut self, block: &Block, height: u64, changed: HashMap<K, Option<V>>) {
+        let hash = block.hash();
+        let tip = Some(height) > self.tip.map(|(_, h)| h);
+        if tip {
+            if self.extends_tip(block, height) {
+                apply_changes(&mut self.current, &changed);
+            } else {
+                self.current = self.after(block, height, &changed);
+            }
+        }
+        let entries = if height.is_multiple_of(CHECKPOINT_INTERVAL) {
+            let state = if tip { self.current.clone() } else { self.after(block, height, &changed) };
+            state.into_iter().map(|(k, v)| (k, Some(v))).collect()
+        } else {
+            changed
+        };
+        self.snapshots.insert(hash, Snapshot { parent: block.get_parent(), height, entries });
+        if tip {
+            self.tip = Some((hash, height));
+            self.prune();
+        }
+    }
+
+    /// The state after a block off the tip, from the state after its parent
+    fn after(&self, block: &Block, height: u64, changed: &HashMap<K, Option<V>>) -> HashMap<K, V> {
+        let mut state = self.before(block, height).expect("a block is applied to a state kept");
+        apply_changes(&mut state, changed);
+        state
+    }
+
+    /// Drop the history below the last checkpoint at least `depth` blocks below the tip, so the
+    /// state after the blocks left can still be found from a checkpoint.
+    fn prune(&mut self) {
+        let (depth, (_, height)) = match (self.depth, self.tip) {
+            (Some(depth), Some(tip)) => (depth, tip),
+            _ => return,
+        };
+        let keep_from = height.saturating_sub(depth) / CHECKPOINT_INTERVAL * CHECKPOINT_INTERVAL;
+        self.snapshots.retain(|_, snapshot| snapshot.height >= keep_from);
+    }
+}
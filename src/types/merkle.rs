@@ -1,4 +1,5 @@
 use ring::digest;
+use serde::{Deserialize, Serialize};
 
 use super::hash::{Hashable, H256};
 
@@ -17,21 +18,42 @@ pub struct MerkleTree {
     root: Vec<Node>, //Option<Box<Node>>, //for none
     leaf_size: usize,
     start_index: usize,
+    scheme: Scheme,
 }
 
+/// How a tree hashes its nodes and handles levels with an odd number of nodes. Blocks record the
+/// scheme they use through their version.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Scheme {
+    /// Leaves and nodes are hashed alike. The last node of an odd level moves up as is on the
+    /// bottom level, and is paired with itself above it, so two transaction lists can share a root.
+    #[default]
+    Legacy,
+    /// Leaves and nodes are hashed with distinct prefixes, and the last node of an odd level always
+    /// moves up as is, so a root commits to a single list.
+    Tagged,
+}
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
 impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self where T: Hashable, {
+        MerkleTree::with_scheme(data, Scheme::Legacy)
+    }
+
+    pub fn with_scheme<T>(data: &[T], scheme: Scheme) -> Self where T: Hashable, {
         let input_len = data.len();
         let mut node_vec: Vec<Node> = Vec::new();
         if input_len == 0 {
-            return MerkleTree {root: node_vec, leaf_size: 0, start_index: 0};
+            return MerkleTree {root: node_vec, leaf_size: 0, start_index: 0, scheme};
         }
         if input_len ==1 {
             let empty_node = Node{val:[0u8; 32].into(), parent: -1, left: -1, right: -1, is_empty: false};
-            let node: Node = Node{val:data[0].hash(), parent: -1, left: -1, right: -1, is_empty: false};
+            let node: Node = Node{val: scheme.leaf(&data[0].hash()), parent: -1, left: -1, right: -1, is_empty: false};
             node_vec.push(empty_node);
             node_vec.push(node);
-            return MerkleTree{root: node_vec, leaf_size: 1, start_index: 1};
+            return MerkleTree{root: node_vec, leaf_size: 1, start_index: 1, scheme};
         }
 
         let mut node_size = 1;
@@ -55,7 +77,7 @@ impl MerkleTree {
         }
 
         for i in start_index .. start_index + input_len {
-            node_vec[i].val = data[i - start_index].hash();
+            node_vec[i].val = scheme.leaf(&data[i - start_index].hash());
             node_vec[i].is_empty = false;
         }
         for i in 1 .. start_index {
@@ -66,15 +88,11 @@ impl MerkleTree {
                 }
                 node_vec[start_index - i].is_empty = false;
                 node_vec[left].parent = (start_index - i) as i32;
-                if node_vec[start_index - i].right != -1 {
+                if node_vec[start_index - i].right != -1 && !(scheme == Scheme::Tagged && node_vec[left + 1].is_empty) {
                     if node_vec[left + 1].is_empty {
                         node_vec[left + 1].val = node_vec[left].val;
                     }
-                    let left_hash = node_vec[left].val.as_ref();
-                    let right_hash = node_vec[left + 1].val.as_ref(); //as_ref to indice
-                    let parent = [&left_hash[..], &right_hash[..]].concat();
-                    let parent_hash_digest = digest::digest(&digest::SHA256, &parent); //degest类型的
-                    let parent_hash = <H256>::from(parent_hash_digest);
+                    let parent_hash = scheme.node(&node_vec[left].val, &node_vec[left + 1].val);
                     node_vec[start_index - i].val = parent_hash;
                     node_vec[left + 1].parent = (start_index - i) as i32;
                 } else {
@@ -82,7 +100,7 @@ impl MerkleTree {
                 }
             }
         }
-        MerkleTree { root: node_vec, leaf_size: input_len, start_index: start_index}
+        MerkleTree { root: node_vec, leaf_size: input_len, start_index, scheme}
     }
 
     pub fn root(&self) -> H256 {
@@ -101,61 +119,209 @@ impl MerkleTree {
             println!("{}, {:?}, {}, {}, {}, {}", i, self.root[i as usize].val, self.root[i as usize].left, self.root[i as usize].right, self.root[i as usize].is_empty, self.root[i as usize].parent);
         }
     }
+    /// Number of leaves the tree was built from
+    pub fn leaf_size(&self) -> usize {
+        self.leaf_size
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
     /// Returns the Merkle Proof of data at index i
     /// return the hash from botttom to ceiling 
+    /// A node that moves up unhashed has no sibling on its level; one paired with itself (in the
+    /// legacy scheme) has its own hash as sibling.
     pub fn proof(&self, index: usize) -> Vec<H256> {
         let mut res_vec: Vec<H256> = Vec::new(); //store result
+        if index >= self.leaf_size {
+            return res_vec;
+        }
         if self.leaf_size == 1 { //directly store the proof
             res_vec.push(self.root[1].val);
             return res_vec;
         }
-        let idx = self.start_index + index; // start_index already add 1
-        let mut child: Node = self.root[idx].clone(); // find the input data
-        while child.parent != -1 { 
-            let father = self.root[child.parent as usize].clone();
-            let brother: Node = if self.root[father.left as usize] == child { //find brother
-                self.root[father.right as usize].clone()
-            }else{
-                self.root[father.left as usize].clone()
-            };
-            res_vec.push(brother.val);
-            child = father.clone();
+        let mut idx = self.start_index + index; // position of the leaf in the heap layout
+        while idx > 1 {
+            let brother = idx ^ 1;
+            // in the legacy scheme an empty right brother holds a copy of the left node
+            if brother < self.root.len() && (self.scheme == Scheme::Legacy || !self.root[brother].is_empty) {
+                res_vec.push(self.root[brother].val);
+            }
+            idx /= 2;
         }
         return res_vec; //the proof not include the root_node
     }
+
+    /// Returns one proof for the data at all of `indices`, sharing the hashes their paths have in
+    /// common. Returns `None` if there are no indices or one is out of range.
+    pub fn multiproof(&self, indices: &[usize]) -> Option<MultiProof> {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() || indices[indices.len() - 1] >= self.leaf_size {
+            return None;
+        }
+        let mut hashes = Vec::new();
+        // positions within the current level whose hash the verifier will know
+        let mut known = indices.clone();
+        let mut level_start = self.start_index;
+        let mut width = self.leaf_size;
+        let mut bottom = true;
+        while width > 1 {
+            let mut next = Vec::new();
+            let mut i = 0;
+            while i < known.len() {
+                let pos = known[i];
+                let promoted = self.scheme.promotes(bottom) && pos == width - 1 && pos.is_multiple_of(2);
+                if !promoted {
+                    let brother = pos ^ 1;
+                    if i + 1 < known.len() && known[i + 1] == brother {
+                        i += 1;
+                    } else if brother < width {
+                        hashes.push(self.root[level_start + brother].val);
+                    }
+                    // otherwise the node is paired with itself
+                }
+                if next.last() != Some(&(pos / 2)) {
+                    next.push(pos / 2);
+                }
+                i += 1;
+            }
+            known = next;
+            level_start /= 2;
+            width = width.div_ceil(2);
+            bottom = false;
+        }
+        Some(MultiProof {
+            leaf_count: self.leaf_size,
+            indices,
+            hashes,
+        })
+    }
+}
+
+/// Proof that several data are in a Merkle tree. Hashes that can be computed from the proven data
+/// are left out, so it is smaller than one proof per datum.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    pub leaf_count: usize,
+    /// Positions of the proven data, ascending
+    pub indices: Vec<usize>,
+    /// The other hashes needed, level by level from the bottom, left to right
+    pub hashes: Vec<H256>,
 }
 
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
-/// 
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
-    let mut data = *datum;
-    let mut idx = index + 1; // to determine left and right
-    if leaf_size == 1 {
-        return *root == proof[0];
-    }
-    for i in 0..proof.len() { //concat from the first hash (bottom) to the last hash
-        let proof_to_concat = proof[i].as_ref();
-        let data_to_concat = data.as_ref();
-        if idx % 2 == 1 { // total input = 6, idx = 5, so left concat
-            let right = proof_to_concat;
-            let left = data_to_concat;
-            let parent_concat = [&left[..], &right[..]].concat();
-            let parent_hash = <H256>::from(digest::digest(&digest::SHA256, &parent_concat));
-            idx = idx/2+1; //
-            data = parent_hash;
-        }
-        else { // total input = 6, idex = 4, left = proof
-            let right = data_to_concat;
-            let left = proof_to_concat;
-            let parent_concat = [&left[..], &right[..]].concat();
-            let parent_hash = <H256>::from(digest::digest(&digest::SHA256, &parent_concat));
-            idx = idx/2;
-            data = parent_hash;
-        }
-    }    
-    return data == *root;
+    Scheme::Legacy.verify(root, datum, proof, index, leaf_size)
 }
+
+/// Verify that the data hashes, given in the order of `proof.indices`, are all in the tree with
+/// this Merkle root. The tree is rebuilt the same way as `MerkleTree::new` does.
+pub fn verify_multi(root: &H256, data: &[H256], proof: &MultiProof) -> bool {
+    Scheme::Legacy.verify_multi(root, data, proof)
+}
+
+impl Scheme {
+    /// The value a datum's hash takes in the tree
+    pub fn leaf(&self, datum: &H256) -> H256 {
+        match self {
+            Scheme::Legacy => *datum,
+            Scheme::Tagged => digest::digest(&digest::SHA256, &[&[LEAF_PREFIX][..], datum.as_ref()].concat()).into(),
+        }
+    }
+
+    pub fn node(&self, left: &H256, right: &H256) -> H256 {
+        let prefix: &[u8] = match self {
+            Scheme::Legacy => &[],
+            Scheme::Tagged => &[NODE_PREFIX],
+        };
+        digest::digest(&digest::SHA256, &[prefix, left.as_ref(), right.as_ref()].concat()).into()
+    }
+
+    /// Whether the last node of an odd level moves up unhashed, rather than being paired with itself
+    fn promotes(&self, bottom: bool) -> bool {
+        bottom || *self == Scheme::Tagged
+    }
+
+    /// `verify` for a tree built with this scheme
+    pub fn verify(&self, root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+        if index >= leaf_size {
+            return false;
+        }
+        let mut data = self.leaf(datum);
+        if leaf_size == 1 {
+            return proof == [*root] && data == *root;
+        }
+        let mut idx = index;
+        let mut width = leaf_size;
+        let mut proof = proof.iter();
+        let mut bottom = true;
+        while width > 1 {
+            if !(self.promotes(bottom) && idx == width - 1 && idx.is_multiple_of(2)) {
+                let brother = match proof.next() {
+                    Some(h) => h,
+                    None => return false,
+                };
+                data = if idx.is_multiple_of(2) { self.node(&data, brother) } else { self.node(brother, &data) };
+            }
+            idx /= 2;
+            width = width.div_ceil(2);
+            bottom = false;
+        }
+        proof.next().is_none() && data == *root
+    }
+
+    /// `verify_multi` for a tree built with this scheme
+    pub fn verify_multi(&self, root: &H256, data: &[H256], proof: &MultiProof) -> bool {
+        let indices = &proof.indices;
+        if indices.is_empty() || data.len() != indices.len() {
+            return false;
+        }
+        if indices.windows(2).any(|w| w[0] >= w[1]) || indices[indices.len() - 1] >= proof.leaf_count {
+            return false;
+        }
+        let mut known: Vec<(usize, H256)> = indices.iter().cloned().zip(data.iter().map(|d| self.leaf(d))).collect();
+        let mut hashes = proof.hashes.iter();
+        let mut width = proof.leaf_count;
+        let mut bottom = true;
+        while width > 1 {
+            let mut next: Vec<(usize, H256)> = Vec::new();
+            let mut i = 0;
+            while i < known.len() {
+                let (pos, hash) = known[i];
+                let parent = if self.promotes(bottom) && pos == width - 1 && pos.is_multiple_of(2) {
+                    hash
+                } else if !pos.is_multiple_of(2) {
+                    match hashes.next() {
+                        Some(left) => self.node(left, &hash),
+                        None => return false,
+                    }
+                } else if i + 1 < known.len() && known[i + 1].0 == pos + 1 {
+                    i += 1;
+                    self.node(&hash, &known[i].1)
+                } else if pos + 1 < width {
+                    match hashes.next() {
+                        Some(right) => self.node(&hash, right),
+                        None => return false,
+                    }
+                } else {
+                    // the last node of an odd level is paired with itself
+                    self.node(&hash, &hash)
+                };
+                next.push((pos / 2, parent));
+                i += 1;
+            }
+            known = next;
+            width = width.div_ceil(2);
+            bottom = false;
+        }
+        hashes.next().is_none() && known[0].1 == *root
+    }
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -210,6 +376,77 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn proofs_for_every_size() {
+        for scheme in [Scheme::Legacy, Scheme::Tagged] {
+            for size in 1..20u8 {
+                let input_data: Vec<H256> = (0..size).map(|i| [i; 32].into()).collect();
+                let merkle_tree = MerkleTree::with_scheme(&input_data, scheme);
+                for (index, datum) in input_data.iter().enumerate() {
+                    let proof = merkle_tree.proof(index);
+                    if size == 1 {
+                        assert_eq!(proof, vec![merkle_tree.root()]);
+                    }
+                    assert!(scheme.verify(&merkle_tree.root(), &datum.hash(), &proof, index, input_data.len()));
+                    assert!(!scheme.verify(&merkle_tree.root(), &datum.hash(), &proof, (index + 1) % 20, input_data.len()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tagged_roots_commit_to_the_list() {
+        let input_data: Vec<H256> = (0..6u8).map(|i| [i; 32].into()).collect();
+        // repeating the last pair gives the same nodes as pairing the last node with itself
+        let mut duplicated = input_data.clone();
+        duplicated.extend_from_slice(&input_data[4..]);
+        assert_eq!(MerkleTree::new(&input_data).root(), MerkleTree::new(&duplicated).root());
+        for n in 1..=duplicated.len() {
+            if n != input_data.len() {
+                assert_ne!(
+                    MerkleTree::with_scheme(&input_data, Scheme::Tagged).root(),
+                    MerkleTree::with_scheme(&duplicated[..n], Scheme::Tagged).root()
+                );
+            }
+        }
+        // an inner node cannot pass for a leaf
+        let tree = MerkleTree::with_scheme(&input_data[..2], Scheme::Tagged);
+        let inner = Scheme::Tagged.node(&Scheme::Tagged.leaf(&input_data[0].hash()), &Scheme::Tagged.leaf(&input_data[1].hash()));
+        assert_eq!(tree.root(), inner);
+        assert_ne!(MerkleTree::with_scheme(&[inner], Scheme::Tagged).root(), inner);
+    }
+
+    #[test]
+    fn multiproofs() {
+        for (scheme, size) in [Scheme::Legacy, Scheme::Tagged].iter().flat_map(|s| (1..20u8).map(move |n| (*s, n))) {
+            let input_data: Vec<H256> = (0..size).map(|i| [i; 32].into()).collect();
+            let merkle_tree = MerkleTree::with_scheme(&input_data, scheme);
+            let root = merkle_tree.root();
+            // every subset of the first leaves, plus some spread out ones
+            let mut subsets: Vec<Vec<usize>> = (1..=size as usize).map(|n| (0..n).collect()).collect();
+            subsets.push((0..size as usize).step_by(3).collect());
+            subsets.push(vec![size as usize - 1]);
+            for indices in subsets {
+                let proof = merkle_tree.multiproof(&indices).unwrap();
+                let data: Vec<H256> = indices.iter().map(|i| input_data[*i].hash()).collect();
+                assert!(scheme.verify_multi(&root, &data, &proof), "{:?} size {} indices {:?}", scheme, size, indices);
+                let single: usize = indices.iter().map(|i| merkle_tree.proof(*i).len()).sum();
+                assert!(proof.hashes.len() <= single);
+
+                let json = serde_json::to_string(&proof).unwrap();
+                assert_eq!(serde_json::from_str::<MultiProof>(&json).unwrap(), proof);
+                if size > 1 {
+                    let mut wrong = data.clone();
+                    wrong[0] = [0xff; 32].into();
+                    assert!(!scheme.verify_multi(&root, &wrong, &proof));
+                }
+            }
+        }
+        let merkle_tree = MerkleTree::new(&[H256::from([1; 32])]);
+        assert!(merkle_tree.multiproof(&[]).is_none());
+        assert!(merkle_tree.multiproof(&[1]).is_none());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,6 +1,14 @@
 pub mod address;
+pub mod batch;
 pub mod block;
+pub mod contract;
 pub mod hash;
+pub mod hex_bytes;
 pub mod merkle;
+pub mod multisig;
+pub mod oracle;
 pub mod key_pair;
+pub mod state_tree;
+pub mod ledger;
 pub mod transaction;
+pub mod utxo;
@@ -0,0 +1,254 @@
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+use super::address::Address;
+use super::hash::{Hashable, H256};
+use super::ledger::LedgerTransaction;
+use crate::blockchain::spec::LedgerModel;
+use super::transaction::{sign, Transaction};
+
+/// Most keys a multisig account can have
+pub const MAX_KEYS: usize = 16;
+
+/// Domain of the hash a multisig address is taken from, so it can never be the hash of a key
+const ADDRESS_TAG: &[u8] = b"multisig";
+
+/// An M-of-N account: any `threshold` of its keys sign for it. Its address is derived from the
+/// policy, so the account needs nothing on chain before it is paid, and every transaction
+/// spending from it carries the policy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub threshold: u32,
+    /// Ed25519 public keys, sorted so the address does not depend on the order they are given in
+    #[serde(with = "crate::types::hex_bytes::vec")]
+    pub public_keys: Vec<Vec<u8>>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u32, mut public_keys: Vec<Vec<u8>>) -> Result<Self, String> {
+        public_keys.sort();
+        let policy = MultisigPolicy { threshold, public_keys };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Check the policy is one `new` makes: between 1 and `MAX_KEYS` distinct keys in order, and a
+    /// threshold of at least one that the keys can reach
+    pub fn validate(&self) -> Result<(), String> {
+        let n = self.public_keys.len();
+        if n == 0 || n > MAX_KEYS {
+            return Err(format!("a multisig account has 1 to {} keys, not {}", MAX_KEYS, n));
+        }
+        if self.threshold == 0 || self.threshold as usize > n {
+            return Err(format!("the threshold must be between 1 and {}, not {}", n, self.threshold));
+        }
+        if let Some(key) = self.public_keys.iter().find(|key| key.len() != 32) {
+            return Err(format!("{} is not an Ed25519 public key", hex::encode(key)));
+        }
+        if self.public_keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("the public keys must be distinct and sorted".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn address(&self) -> Address {
+        let mut data = ADDRESS_TAG.to_vec();
+        data.extend(bincode::serialize(self).unwrap());
+        Address::from_public_key_bytes(&data)
+    }
+}
+
+/// A signature of a transfer by one of the keys of a multisig account
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartialSignature {
+    #[serde(with = "crate::types::hex_bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl PartialSignature {
+    pub fn sign(tx: &Transaction, key: &Ed25519KeyPair) -> Self {
+        PartialSignature {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: sign(tx, key).as_ref().to_vec(),
+        }
+    }
+
+    fn verify(&self, tx: &Transaction) -> bool {
+        let bytes = bincode::serialize(tx).unwrap();
+        UnparsedPublicKey::new(&ED25519, &self.public_key)
+            .verify(&bytes, &self.signature)
+            .is_ok()
+    }
+}
+
+/// A transfer from a multisig account. Its cosigners pass it around to add their signatures,
+/// and it is valid once it has `threshold` of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultisigTransaction {
+    pub transaction: Transaction,
+    pub policy: MultisigPolicy,
+    pub signatures: Vec<PartialSignature>,
+}
+
+impl MultisigTransaction {
+    /// A transfer with no signatures yet, from the account of `policy`
+    pub fn unsigned(transaction: Transaction, policy: MultisigPolicy) -> Result<Self, String> {
+        policy.validate()?;
+        if policy.address() != transaction.sender {
+            return Err(format!("the sender is not {}, the address of the policy", policy.address()));
+        }
+        Ok(MultisigTransaction { transaction, policy, signatures: Vec::new() })
+    }
+
+    /// Add the signature of a key of the policy, keeping the signatures in the order of the keys
+    pub fn add_signature(&mut self, signature: PartialSignature) -> Result<(), String> {
+        let key = hex::encode(&signature.public_key);
+        if !self.policy.public_keys.contains(&signature.public_key) {
+            return Err(format!("{} is not a key of {}", key, self.policy.address()));
+        }
+        if !signature.verify(&self.transaction) {
+            return Err(format!("the signature of {} is invalid", key));
+        }
+        match self.signatures.binary_search_by(|s| s.public_key.cmp(&signature.public_key)) {
+            Ok(_) => Err(format!("{} has signed already", key)),
+            Err(i) => {
+                self.signatures.insert(i, signature);
+                Ok(())
+            }
+        }
+    }
+
+    /// Add the signatures another copy of the same transfer has collected and this one has not
+    pub fn combine(&mut self, other: &MultisigTransaction) -> Result<(), String> {
+        if other.hash_unsigned() != self.hash_unsigned() {
+            return Err("the transactions have different transfers or policies".to_string());
+        }
+        for signature in &other.signatures {
+            if !self.signatures.iter().any(|s| s.public_key == signature.public_key) {
+                self.add_signature(signature.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many more signatures the transfer needs
+    pub fn missing_signatures(&self) -> usize {
+        (self.policy.threshold as usize).saturating_sub(self.signatures.len())
+    }
+
+    /// Check that the policy owns the sender address and that enough of its keys signed, each
+    /// once and in the order of the keys
+    pub fn check_signatures(&self) -> Result<(), String> {
+        self.policy.validate()?;
+        if self.policy.address() != self.transaction.sender {
+            return Err(format!("the sender is not {}, the address of the policy", self.policy.address()));
+        }
+        let mut keys = self.policy.public_keys.iter();
+        for signature in &self.signatures {
+            if !keys.any(|key| *key == signature.public_key) {
+                return Err(format!(
+                    "{} is not a key of the policy, or is out of order",
+                    hex::encode(&signature.public_key)
+                ));
+            }
+            if !signature.verify(&self.transaction) {
+                return Err(format!("the signature of {} is invalid", hex::encode(&signature.public_key)));
+            }
+        }
+        if self.missing_signatures() > 0 {
+            return Err(format!(
+                "{} of the {} signatures needed",
+                self.signatures.len(),
+                self.policy.threshold
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn verify_signatures(&self) -> bool {
+        self.check_signatures().is_ok()
+    }
+
+    /// Parse a multisig transaction written by `LedgerTransaction::to_hex` or `to_json`, as
+    /// the cosigners pass it around to sign
+    pub fn decode(s: &str) -> Result<Self, String> {
+        match LedgerTransaction::decode(s, LedgerModel::Account)? {
+            LedgerTransaction::Multisig(tx) => Ok(tx),
+            _ => Err("not a multisig transaction".to_string()),
+        }
+    }
+
+    /// Hash of the transfer and policy, which the signatures do not change
+    fn hash_unsigned(&self) -> H256 {
+        let data = bincode::serialize(&(&self.transaction, &self.policy)).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+impl Hashable for MultisigTransaction {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::ledger::LedgerTransaction;
+    use crate::blockchain::spec::LedgerModel;
+    use crate::types::transaction::generate_address;
+
+    #[test]
+    fn two_of_three() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_ref().to_vec()).collect();
+        let policy = MultisigPolicy::new(2, public_keys.clone()).unwrap();
+        let mut reversed = public_keys.clone();
+        reversed.reverse();
+        assert_eq!(MultisigPolicy::new(2, reversed).unwrap().address(), policy.address());
+        assert!(MultisigPolicy::new(4, public_keys.clone()).is_err());
+        assert!(MultisigPolicy::new(1, vec![public_keys[0].clone(), public_keys[0].clone()]).is_err());
+
+        let transfer = Transaction { nonce: 1, sender: policy.address(), receiver: generate_address(), value: 5, ..Default::default() };
+        assert!(MultisigTransaction::unsigned(
+            Transaction { sender: generate_address(), ..transfer.clone() },
+            policy.clone()
+        )
+        .is_err());
+        let mut tx = MultisigTransaction::unsigned(transfer.clone(), policy.clone()).unwrap();
+        let mut other = tx.clone();
+        tx.add_signature(PartialSignature::sign(&transfer, &keys[2])).unwrap();
+        assert!(tx.add_signature(PartialSignature::sign(&transfer, &keys[2])).is_err());
+        assert!(tx.add_signature(PartialSignature::sign(&transfer, &key_pair::random())).is_err());
+        assert_eq!(tx.missing_signatures(), 1);
+        assert!(!tx.verify_signatures());
+
+        // a cosigner signing another copy in parallel
+        other.add_signature(PartialSignature::sign(&transfer, &keys[0])).unwrap();
+        tx.combine(&other).unwrap();
+        tx.check_signatures().unwrap();
+
+        // a signature of another transfer does not count
+        let mut forged = tx.clone();
+        forged.transaction.value = 6;
+        assert!(!forged.verify_signatures());
+        let mut duplicated = tx.clone();
+        duplicated.signatures[1] = duplicated.signatures[0].clone();
+        assert!(!duplicated.verify_signatures());
+
+        // both encodings come back as multisig transactions of an account chain
+        let tx = LedgerTransaction::from(tx);
+        for encoded in [tx.to_hex(), tx.to_json()] {
+            let decoded = LedgerTransaction::decode(&encoded, LedgerModel::Account).unwrap();
+            assert!(matches!(decoded, LedgerTransaction::Multisig(_)));
+            assert_eq!(decoded.hash(), tx.hash());
+            assert!(decoded.verify_signatures());
+            assert!(MultisigTransaction::decode(&encoded).is_ok());
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::address::Address;
+use super::hash::{Hashable, H256};
+use super::transaction::sign;
+
+/// Domain of the hash a feed address is taken from, so it can never be the hash of a key
+const ADDRESS_TAG: &[u8] = b"oracle";
+/// Longest feed ID
+pub const MAX_FEED_ID: usize = 32;
+
+/// The latest round of a feed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    pub id: String,
+    pub round: u64,
+    /// Median of the values reported for the round
+    pub value: u64,
+    /// Median of the times the values were observed, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// How many oracles reported the round
+    pub reports: u32,
+}
+
+impl Hashable for Feed {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+/// The address a feed is kept under in the state, whose leaf in the state tree commits to it
+pub fn feed_address(id: &str) -> Address {
+    let mut data = ADDRESS_TAG.to_vec();
+    data.extend(id.as_bytes());
+    Address::from_public_key_bytes(&data)
+}
+
+/// Check that a feed ID is 1 to `MAX_FEED_ID` letters, digits, dots, dashes or underscores, so it
+/// can go in a URL path as it is
+pub fn check_feed_id(id: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_');
+    if id.is_empty() || id.len() > MAX_FEED_ID || !id.chars().all(valid) {
+        return Err(format!(
+            "feed ID {:?} must be 1 to {} letters, digits, dots, dashes or underscores",
+            id, MAX_FEED_ID
+        ));
+    }
+    Ok(())
+}
+
+/// A value an oracle observed for a round of a feed. It pays no fee and has no nonce: only the
+/// oracles of the chain spec can report, and a round not above the latest of its feed is skipped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OracleReport {
+    pub feed: String,
+    pub round: u64,
+    pub value: u64,
+    /// When the value was observed, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedOracleReport {
+    pub report: OracleReport,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedOracleReport {
+    pub fn sign(report: OracleReport, key: &Ed25519KeyPair) -> Self {
+        let signature = sign(&report, key);
+        SignedOracleReport {
+            report,
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        }
+    }
+
+    /// The address of the oracle that signed the report
+    pub fn oracle(&self) -> Address {
+        Address::from_public_key_bytes(&self.public_key)
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        let bytes = bincode::serialize(&self.report).unwrap();
+        UnparsedPublicKey::new(&ED25519, &self.public_key).verify(&bytes, &self.signature).is_ok()
+    }
+}
+
+impl Hashable for SignedOracleReport {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+/// The value and timestamp each oracle reported for a round
+type Reports = HashMap<Address, (u64, u64)>;
+
+/// The reports a block takes: for each feed, those of the highest round reported, the first of
+/// each oracle
+#[derive(Default)]
+pub struct Rounds {
+    /// Round and its reports, by feed ID
+    rounds: HashMap<String, (u64, Reports)>,
+}
+
+impl Rounds {
+    pub fn add(&mut self, oracle: Address, report: &OracleReport) {
+        let (round, reports) = self.rounds.entry(report.feed.clone()).or_insert((report.round, HashMap::new()));
+        if report.round > *round {
+            *round = report.round;
+            reports.clear();
+        }
+        if report.round == *round {
+            reports.entry(oracle).or_insert((report.value, report.timestamp));
+        }
+    }
+
+    /// The feeds after the block, each with the medians of the values and timestamps reported
+    pub fn feeds(self) -> impl Iterator<Item = Feed> {
+        self.rounds.into_iter().map(|(id, (round, reports))| {
+            let (mut values, mut timestamps): (Vec<u64>, Vec<u64>) = reports.values().copied().unzip();
+            Feed {
+                id,
+                round,
+                value: median(&mut values),
+                timestamp: median(&mut timestamps),
+                reports: reports.len() as u32,
+            }
+        })
+    }
+}
+
+/// The middle value, or halfway between the two middle ones rounded down. There must be one.
+fn median(values: &mut [u64]) -> u64 {
+    values.sort_unstable();
+    let upper = values[values.len() / 2];
+    if values.len() % 2 == 1 {
+        return upper;
+    }
+    let lower = values[values.len() / 2 - 1];
+    lower + (upper - lower) / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::{ChainSpec, LedgerModel};
+    use crate::types::block::generate_random_block;
+    use crate::types::key_pair;
+    use crate::types::ledger::{Ledger, LedgerTransaction};
+    use crate::types::state_tree::StateTree;
+    use crate::types::transaction::{State, TransactionMemopool};
+
+    #[test]
+    fn oracle_rounds() {
+        let oracles: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let address = |key: &Ed25519KeyPair| Address::from_public_key_bytes(key.public_key().as_ref());
+        let spec = ChainSpec { oracles: oracles.iter().map(address).collect(), ..Default::default() };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+        let report = |key: &Ed25519KeyPair, feed: &str, round, value, timestamp| {
+            let report = OracleReport { feed: feed.to_string(), round, value, timestamp };
+            LedgerTransaction::from(SignedOracleReport::sign(report, key))
+        };
+
+        let outsider = key_pair::random();
+        assert!(state.check(&report(&outsider, "eth-usd", 1, 1, 0)).unwrap_err().contains("not an oracle"));
+        assert!(state.check(&report(&oracles[0], "eth/usd", 1, 1, 0)).is_err());
+        let first = report(&oracles[0], "eth-usd", 1, 100, 900);
+        state.check(&first).unwrap();
+        for encoded in [first.to_hex(), first.to_json()] {
+            assert_eq!(LedgerTransaction::decode(&encoded, LedgerModel::Account).unwrap().hash(), first.hash());
+        }
+
+        // the median of one report per oracle, leaving out outsiders and reports after the block
+        let mut block = generate_random_block(&genesis.hash());
+        block.header.timestamp = 1000;
+        block.content.content = vec![
+            first,
+            report(&oracles[1], "eth-usd", 1, 300, 950),
+            report(&oracles[2], "eth-usd", 1, 200, 1000),
+            report(&oracles[2], "eth-usd", 1, 10_000, 1000),
+            report(&outsider, "eth-usd", 1, 1, 1000),
+            report(&oracles[0], "btc-usd", 1, 1, 2000),
+        ];
+        block.header.state_root = state.root_after(&block, 1).unwrap();
+        state.update(&block, 1).unwrap();
+        let feed = Feed { id: "eth-usd".to_string(), round: 1, value: 200, timestamp: 950, reports: 3 };
+        assert_eq!(state.feed("eth-usd"), Some(&feed));
+        assert!(state.feed("btc-usd").is_none());
+        assert!(state.check(&report(&oracles[1], "eth-usd", 1, 5, 0)).unwrap_err().contains("already at round 1"));
+
+        // only the highest round of a block counts, and a round not above the latest is skipped
+        let stale = report(&oracles[0], "eth-usd", 1, 5, 1000);
+        let later = report(&oracles[0], "eth-usd", 4, 5, 1000);
+        let mut mempool = TransactionMemopool::new();
+        let candidates = [
+            report(&oracles[0], "eth-usd", 2, 110, 1000),
+            report(&oracles[1], "eth-usd", 3, 130, 1100),
+        ];
+        let mut next = generate_random_block(&block.hash());
+        next.header.timestamp = 2000;
+        next.content.content = state
+            .select(vec![&stale, &candidates[0], &candidates[1]])
+            .into_iter()
+            .cloned()
+            .chain([report(&oracles[2], "eth-usd", 3, 150, 1300)])
+            .collect();
+        assert_eq!(next.content.content.len(), 3);
+        next.header.state_root = state.root_after(&next, 2).unwrap();
+        state.update(&next, 2).unwrap();
+        let feed = Feed { id: "eth-usd".to_string(), round: 3, value: 140, timestamp: 1200, reports: 2 };
+        assert_eq!(state.feed("eth-usd"), Some(&feed));
+        assert_ne!(next.header.state_root, StateTree::new(state.accounts()).root());
+
+        // the mempool drops the reports a block made stale
+        for tx in [stale, later.clone()] {
+            mempool.insert(tx).unwrap();
+        }
+        mempool.remove_block(&next, 2);
+        assert_eq!(mempool.trans_map.keys().collect::<Vec<_>>(), vec![&later.hash()]);
+    }
+}
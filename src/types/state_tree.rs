@@ -0,0 +1,186 @@
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::address::Address;
+use super::hash::H256;
+use super::transaction::Amount;
+
+/// One level per bit of an address
+const DEPTH: usize = 160;
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Sparse Merkle tree of the accounts of a state, with a leaf for every possible address. A leaf
+/// commits to the nonce and balance of its account, and the leaf of a contract to the hash of the
+/// contract as well; leaves without an account and subtrees with none hash to zero, so only the
+/// paths to existing accounts are computed.
+pub struct StateTree {
+    /// Leaf hashes of the existing accounts, sorted by address
+    leaves: Vec<(Address, H256)>,
+}
+
+/// The siblings on the path from an address to the root, from the root down. Siblings that are
+/// empty subtrees are left out, and their bit in `bitmap` is clear.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateProof {
+    #[serde(with = "crate::types::hex_bytes")]
+    pub bitmap: Vec<u8>,
+    pub siblings: Vec<H256>,
+}
+
+impl StateTree {
+    pub fn new(accounts: &HashMap<Address, (u32, Amount)>) -> Self {
+        Self::with_contracts(accounts, &HashMap::new())
+    }
+
+    /// A tree of accounts some of which are contracts, given by their hashes
+    pub fn with_contracts(accounts: &HashMap<Address, (u32, Amount)>, contracts: &HashMap<Address, H256>) -> Self {
+        let mut leaves: Vec<(Address, H256)> = accounts
+            .iter()
+            .map(|(address, account)| (*address, leaf(address, *account, contracts.get(address))))
+            .collect();
+        leaves.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+        StateTree { leaves }
+    }
+
+    pub fn root(&self) -> H256 {
+        subtree(&self.leaves, 0)
+    }
+
+    /// Proof of the account of `address`, or of its absence if there is none
+    pub fn proof(&self, address: &Address) -> StateProof {
+        let mut bitmap = vec![0u8; DEPTH / 8];
+        let mut siblings = Vec::new();
+        let mut leaves = &self.leaves[..];
+        for depth in 0..DEPTH {
+            let split = leaves.partition_point(|(a, _)| !bit(a.as_ref(), depth));
+            let (left, right) = leaves.split_at(split);
+            let (path, other) = if bit(address.as_ref(), depth) { (right, left) } else { (left, right) };
+            let sibling = subtree(other, depth + 1);
+            if sibling != H256::default() {
+                bitmap[depth / 8] |= 0x80 >> (depth % 8);
+                siblings.push(sibling);
+            }
+            leaves = path;
+        }
+        StateProof { bitmap, siblings }
+    }
+}
+
+/// Check that `account` is the nonce and balance of `address` in the state with root `root`.
+/// An `account` of `None` checks that the address has no account. The account of a contract only
+/// checks with the hash of the contract.
+pub fn verify(
+    root: &H256,
+    address: &Address,
+    account: Option<(u32, Amount)>,
+    contract: Option<&H256>,
+    proof: &StateProof,
+) -> bool {
+    if proof.bitmap.len() != DEPTH / 8 {
+        return false;
+    }
+    let present = (0..DEPTH).filter(|d| bit(&proof.bitmap, *d)).count();
+    if present != proof.siblings.len() {
+        return false;
+    }
+    let mut hash = match account {
+        Some(account) => leaf(address, account, contract),
+        None => H256::default(),
+    };
+    let mut next = proof.siblings.len();
+    for depth in (0..DEPTH).rev() {
+        let sibling = if bit(&proof.bitmap, depth) {
+            next -= 1;
+            proof.siblings[next]
+        } else {
+            H256::default()
+        };
+        hash = if bit(address.as_ref(), depth) { node(&sibling, &hash) } else { node(&hash, &sibling) };
+    }
+    hash == *root
+}
+
+/// Root of the subtree holding `leaves`, whose addresses share their first `depth` bits
+fn subtree(leaves: &[(Address, H256)], depth: usize) -> H256 {
+    if leaves.is_empty() {
+        return H256::default();
+    }
+    if depth == DEPTH {
+        return leaves[0].1;
+    }
+    let split = leaves.partition_point(|(a, _)| !bit(a.as_ref(), depth));
+    node(&subtree(&leaves[..split], depth + 1), &subtree(&leaves[split..], depth + 1))
+}
+
+/// Bit `index` of `bytes`, counting from the most significant bit of the first byte
+fn bit(bytes: &[u8], index: usize) -> bool {
+    bytes[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+fn leaf(address: &Address, (nonce, balance): (u32, Amount), contract: Option<&H256>) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[LEAF_PREFIX]);
+    ctx.update(address.as_ref());
+    ctx.update(&nonce.to_le_bytes());
+    ctx.update(&balance.to_le_bytes());
+    if let Some(contract) = contract {
+        ctx.update(contract.as_ref());
+    }
+    ctx.finish().into()
+}
+
+fn node(left: &H256, right: &H256) -> H256 {
+    if *left == H256::default() && *right == H256::default() {
+        return H256::default();
+    }
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[NODE_PREFIX]);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    ctx.finish().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::generate_address;
+
+    #[test]
+    fn roots_and_proofs() {
+        assert_eq!(StateTree::new(&HashMap::new()).root(), H256::default());
+
+        let mut accounts = HashMap::new();
+        for i in 0..20 {
+            accounts.insert(generate_address(), (i, 100 * i as Amount));
+        }
+        // neighbours that only differ in the last bit
+        accounts.insert([0xaa; 20].into(), (1, 5));
+        let mut close = [0xaa; 20];
+        close[19] = 0xab;
+        accounts.insert(close.into(), (2, 6));
+        let tree = StateTree::new(&accounts);
+        let root = tree.root();
+
+        for (address, account) in &accounts {
+            let proof = tree.proof(address);
+            assert!(verify(&root, address, Some(*account), None, &proof));
+            assert!(!verify(&root, address, Some((account.0, account.1 + 1)), None, &proof));
+            assert!(!verify(&root, address, None, None, &proof));
+        }
+        let missing = generate_address();
+        let proof = tree.proof(&missing);
+        assert!(verify(&root, &missing, None, None, &proof));
+        assert!(!verify(&root, &missing, Some((0, 0)), None, &proof));
+
+        // any change to an account changes the root
+        let mut changed = accounts.clone();
+        changed.insert(close.into(), (2, 7));
+        assert_ne!(StateTree::new(&changed).root(), root);
+        changed.insert(close.into(), (2, 6));
+        assert_eq!(StateTree::new(&changed).root(), root);
+        changed.insert(missing, (0, 0));
+        assert_ne!(StateTree::new(&changed).root(), root);
+    }
+}
@@ -1,28 +1,156 @@
 use serde::{Serialize,Deserialize};
 use crate::types::key_pair;
-use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
+use ring::signature::{Ed25519KeyPair, Signature, KeyPair, UnparsedPublicKey, ED25519};
 use rand::{Rng, prelude::SliceRandom, thread_rng};
 use ring::digest;
 //use crate::types::{H256, Hashable};
 use super::{address::{Address, self}, hash::{Hashable, H256}};
-use std::{collections::{HashMap, HashSet}, ops::Add};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use crate::types::block::*;
+use crate::types::state_tree::StateTree;
+use crate::types::ledger::{Applying, History, Ledger, LedgerTransaction};
+use crate::types::utxo::OutPoint;
+use crate::types::batch::Payment;
+use crate::types::contract::{Contract, ContractAction, ContractTransaction};
+use crate::types::oracle::{self, feed_address, Feed, Rounds, SignedOracleReport};
+use crate::vm::{self, Context, Outcome, DEPLOY_GAS, MAX_ARGS, MAX_CODE, MAX_GAS};
+use crate::blockchain::spec::{ChainSpec, LedgerModel, RewardSchedule};
+use crate::metrics::METRICS;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// An amount of coins, in base units
+pub type Amount = u64;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
-    nonce: u32, // the nonce after transaction
-    sender: Address,
-    receiver: Address,
-    value: i32,
+    pub nonce: u32, // the nonce after transaction
+    pub sender: Address,
+    pub receiver: Address,
+    pub value: Amount,
+    /// Paid to the beneficiary of the block, on top of the value
+    #[serde(default)]
+    pub fee: Amount,
+    #[serde(default)]
+    pub validity: Validity,
+}
+
+/// What the account ledger needs of a transfer, whether it pays one receiver or a batch of them
+pub trait Transfer {
+    fn sender(&self) -> Address;
+
+    /// The nonce of the sender after the transfer
+    fn nonce(&self) -> u32;
+
+    fn fee(&self) -> Amount;
+
+    fn validity(&self) -> &Validity;
+
+    /// The receivers and what each gets, in order
+    fn payments(&self) -> Vec<Payment>;
+
+    /// Bincode size of the transfer the sender signs, which its fee is priced by. The fee has a
+    /// fixed size, so setting it does not change this.
+    fn size(&self) -> u64;
+
+    /// Most gas the transfer can use running a contract, which its fee is also priced by
+    fn gas_limit(&self) -> u64 {
+        0
+    }
+
+    /// Whether this is a cancel: a transfer of nothing to the sender, which only takes its nonce
+    /// and fee, so it can replace a pending transfer with the same nonce
+    fn is_cancel(&self) -> bool {
+        matches!(self.payments()[..], [payment] if payment.receiver == self.sender() && payment.value == 0)
+    }
+}
+
+impl Transfer for Transaction {
+    fn sender(&self) -> Address {
+        self.sender
+    }
+
+    fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    fn fee(&self) -> Amount {
+        self.fee
+    }
+
+    fn validity(&self) -> &Validity {
+        &self.validity
+    }
+
+    fn payments(&self) -> Vec<Payment> {
+        vec![Payment { receiver: self.receiver, value: self.value }]
+    }
+
+    fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+}
+
+/// The blocks a transfer can go into, by their height and their timestamp (in milliseconds since
+/// the Unix epoch, as in the header). Every bound is optional. A transfer waiting for its window
+/// holds up the later nonces of its sender.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    /// Only in blocks above this height
+    pub valid_after_height: Option<u64>,
+    /// Only in blocks with a later timestamp
+    pub valid_after_timestamp: Option<u64>,
+    /// Only in blocks below this height
+    pub expires_at_height: Option<u64>,
+    /// Only in blocks with an earlier timestamp
+    pub expires_at_timestamp: Option<u64>,
+}
+
+impl Validity {
+    /// Check that a block at `height` with `timestamp` is in the window
+    pub fn check(&self, height: u64, timestamp: u128) -> Result<(), String> {
+        if let Some(after) = self.valid_after_height {
+            if height <= after {
+                return Err(format!("only valid above height {}", after));
+            }
+        }
+        if let Some(after) = self.valid_after_timestamp {
+            if timestamp <= after as u128 {
+                return Err(format!("only valid after timestamp {}", after));
+            }
+        }
+        if let Some(expiry) = self.expires_at_height {
+            if height >= expiry {
+                return Err(format!("expired at height {}", expiry));
+            }
+        }
+        if let Some(expiry) = self.expires_at_timestamp {
+            if timestamp >= expiry as u128 {
+                return Err(format!("expired at timestamp {}", expiry));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether no block after one at `height` with `timestamp` can take the transfer, as long as
+    /// timestamps do not go back
+    pub fn expired(&self, height: u64, timestamp: u128) -> bool {
+        self.expires_at_height.is_some_and(|h| height.saturating_add(1) >= h)
+            || self.expires_at_timestamp.is_some_and(|t| timestamp >= t as u128)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
+    #[serde(with = "crate::types::hex_bytes")]
     pub public_key: Vec<u8>,
+    #[serde(with = "crate::types::hex_bytes")]
     pub signature: Vec<u8>,
 }
 
+impl SignedTransaction {
+}
+
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         let data = bincode::serialize(&self).unwrap();
@@ -30,9 +158,13 @@ impl Hashable for SignedTransaction {
     }
 }
 
-pub fn generate_random_signed_transaction(sender: Address, receiver: Address, value: i32, nonce: u32, key: &Ed25519KeyPair) -> SignedTransaction {
+pub fn generate_random_signed_transaction(sender: Address, receiver: Address, value: Amount, nonce: u32, key: &Ed25519KeyPair) -> SignedTransaction {
     let transaction = generate_random_transaction(sender, receiver, value, nonce);
     // let key = key_pair::random();
+    sign_transaction(transaction, key)
+}
+
+pub fn sign_transaction(transaction: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
     let signature = sign(&transaction, &key);
     SignedTransaction {
         transaction: transaction, 
@@ -42,44 +174,146 @@ pub fn generate_random_signed_transaction(sender: Address, receiver: Address, va
 }
 
 /// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+pub fn sign<T: Serialize>(t: &T, key: &Ed25519KeyPair) -> Signature {
     let bytes = bincode::serialize(t).unwrap();
     key.sign(&bytes)
 }
 
-/// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(tx: &Transaction, public_key: &[u8], signature: &[u8], state: &State) -> bool {
-    // let public_key =
-    //     ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519,
-    //                                   &public_key);
-    // let transaction_key = bincode::serialize(&tx).unwrap();            
-    // let check1 = public_key.verify(&transaction_key, signature.as_ref()).is_ok();
-
-    // let (ac_nonce,balance) = state.accounts[&tx.sender]; 
-    // let check2 = (balance-tx.value)>0;
-    // let check3 = ac_nonce == tx.nonce + 1;
-    // return check1 && check2 && check3
-    
-    // todo: implement verify function
-    return true
-    
+/// Check that `signature` is a signature of `tx` by `public_key`, and that the key owns the sender
+/// address. This needs no state, so it can run offline.
+pub fn verify_signature<T: Transfer + Serialize>(tx: &T, public_key: &[u8], signature: &[u8]) -> bool {
+    if Address::from_public_key_bytes(public_key) != tx.sender() {
+        return false;
+    }
+    let public_key = UnparsedPublicKey::new(&ED25519, public_key);
+    let bytes = bincode::serialize(tx).unwrap();
+    public_key.verify(&bytes, signature).is_ok()
 }
 
 pub struct TransactionMemopool {
-    pub trans_map: HashMap<H256, SignedTransaction>,
+    pub trans_map: HashMap<H256, LedgerTransaction>,
 }
 
 impl TransactionMemopool {
     pub fn new() -> Self {
-        let mut trans_map: HashMap<H256, SignedTransaction> = HashMap::new();
+        let trans_map: HashMap<H256, LedgerTransaction> = HashMap::new();
         TransactionMemopool {
             trans_map: trans_map, 
         }
     }
+
+    /// The transactions a block at `height` with `timestamp` can take. Those waiting for their
+    /// window of validity stay in the pool.
+    pub fn ready(&self, height: u64, timestamp: u128) -> Vec<&LedgerTransaction> {
+        self.trans_map
+            .values()
+            .filter(|tx| tx.transfer().is_none_or(|t| t.validity().check(height, timestamp).is_ok()))
+            .collect()
+    }
+
+    /// The account transfer in the pool from `sender` with `nonce`, if any
+    pub fn pending(&self, sender: &Address, nonce: u32) -> Option<(&H256, &LedgerTransaction)> {
+        self.trans_map
+            .iter()
+            .find(|(_, tx)| tx.transfer().is_some_and(|t| t.sender() == *sender && t.nonce() == nonce))
+    }
+
+    /// Add a transaction, returning whether it was not in the pool yet. An account transfer
+    /// with the sender and nonce of one in the pool replaces it if it pays a higher fee, and is
+    /// refused otherwise, so only one of them can go into a block.
+    pub fn insert(&mut self, tx: LedgerTransaction) -> Result<bool, String> {
+        let hash = tx.hash();
+        if self.trans_map.contains_key(&hash) {
+            return Ok(false);
+        }
+        if let Some(transfer) = tx.transfer() {
+            if let Some((pending_hash, pending)) = self.pending(&transfer.sender(), transfer.nonce()) {
+                let pending_fee = pending.transfer().unwrap().fee();
+                if transfer.fee() <= pending_fee {
+                    return Err(format!(
+                        "transaction {} already takes nonce {} of {} with a fee of {}, which a replacement must exceed",
+                        pending_hash,
+                        transfer.nonce(),
+                        transfer.sender(),
+                        pending_fee
+                    ));
+                }
+                let pending_hash = *pending_hash;
+                self.trans_map.remove(&pending_hash);
+            }
+        }
+        self.trans_map.insert(hash, tx);
+        Ok(true)
+    }
+
+    /// Remove the transactions of a block added to the chain at `height`, and those no block
+    /// after it can take any more: expired transfers, and oracle reports for rounds no later
+    /// than one the block reports for their feed
+    pub fn remove_block(&mut self, block: &Block, height: u64) {
+        let mut rounds: HashMap<&str, u64> = HashMap::new();
+        for tx in &block.content.content {
+            self.trans_map.remove(&tx.hash());
+            if let LedgerTransaction::Oracle(tx) = tx {
+                let round = rounds.entry(&tx.report.feed).or_default();
+                *round = tx.report.round.max(*round);
+            }
+        }
+        let timestamp = block.header.timestamp;
+        self.trans_map.retain(|_, tx| match tx {
+            LedgerTransaction::Oracle(tx) => {
+                rounds.get(tx.report.feed.as_str()).is_none_or(|round| tx.report.round > *round)
+            }
+            _ => !tx.transfer().is_some_and(|t| t.validity().expired(height, timestamp)),
+        });
+    }
+}
+/// Nonce and balance of each account
+pub type Accounts = HashMap<Address, (u32, Amount)>;
+
+/// Code and storage of each contract, by the address of its account
+pub type Contracts = HashMap<Address, Contract>;
+
+/// The latest round of each oracle feed, by the address of the feed
+pub type Feeds = HashMap<Address, Feed>;
+
+/// The accounts, contracts and feeds a block changed, with their values after it
+struct Applied {
+    accounts: HashMap<Address, Option<(u32, Amount)>>,
+    contracts: HashMap<Address, Option<Contract>>,
+    feeds: HashMap<Address, Option<Feed>>,
 }
+
 pub struct State {
-    pub accounts: HashMap<Address, (u32, i32)>, //address, (nonce, balance)
-    pub history: HashMap<H256, HashMap<Address, (u32, i32)>>,
+    /// Accounts after each block, as (nonce, balance) by address
+    history: History<Address, (u32, Amount)>,
+    /// Contracts after each block. A contract also has an account, which its leaf in the state
+    /// tree commits to together with the contract.
+    contracts: History<Address, Contract>,
+    /// Oracle feeds after each block
+    feeds: History<Address, Feed>,
+    pub reward: RewardSchedule,
+    /// Least fee a transfer pays per byte of its size to get into the mempool
+    pub fee_per_byte: Amount,
+    /// Least fee a contract transaction pays per unit of its gas limit, on top of that for its size
+    pub fee_per_gas: Amount,
+    /// Addresses of the keys whose oracle reports are taken
+    pub oracles: HashSet<Address>,
+}
+
+/// The state tree of accounts some of which are contracts, with a leaf for each feed at its
+/// address as an empty account with the hash of the feed
+pub fn state_tree(accounts: &Accounts, contracts: &Contracts, feeds: &Feeds) -> StateTree {
+    let mut hashes: HashMap<Address, H256> =
+        contracts.iter().map(|(address, contract)| (*address, contract.hash())).collect();
+    if feeds.is_empty() {
+        return StateTree::with_contracts(accounts, &hashes);
+    }
+    let mut leaves = accounts.clone();
+    for (address, feed) in feeds {
+        leaves.insert(*address, (0, 0));
+        hashes.insert(*address, feed.hash());
+    }
+    StateTree::with_contracts(&leaves, &hashes)
 }
 
 pub fn generate_address() -> Address {
@@ -99,97 +333,427 @@ pub fn generate_address() -> Address {
 }
 
 impl State {
-    pub fn new(addr: Address) -> Self {
-        let mut accounts: HashMap<Address, (u32,i32)> = HashMap::new();
-        let mut history: HashMap<H256, HashMap<Address, (u32, i32)>> = HashMap::new();
-        let nonce = 0;
-        let balance = 1000;
-        accounts.insert(addr, (nonce, balance));
+    /// The state before the genesis block: the allocations of the chain spec
+    pub fn new(spec: &ChainSpec) -> Self {
+        let mut accounts: HashMap<Address, (u32, Amount)> = HashMap::new();
+        for allocation in &spec.allocations {
+            accounts.insert(allocation.address, (0, allocation.balance));
+        }
         State{
-            accounts: accounts,
-            history: history,
-        }
-    }
-    // change 
-    pub fn update(&mut self, block: &Block) -> bool {
-        let mut accounts = self.accounts.clone();
-        let mut isValid = true;
-        for signedTrans in &block.content.content {
-            // todo: add check here: trans verify
-            let check1 = false;
-            let trans = signedTrans.transaction.clone();
-            let receiver = trans.receiver;
-            let sender = trans.sender;
-            let nonce = trans.nonce;
-            let value = trans.value;
-            if receiver == sender {
-                if !accounts.contains_key(&receiver) {
-                    accounts.insert(receiver, (nonce, value));
-                    // println!("account {:?} value {:?} nonce {:?}", receiver, value, nonce);
+            history: History::new(accounts),
+            contracts: History::new(HashMap::new()),
+            feeds: History::new(HashMap::new()),
+            reward: spec.block_reward.clone(),
+            fee_per_byte: spec.fee_per_byte,
+            fee_per_gas: spec.fee_per_gas,
+            oracles: spec.oracles.iter().copied().collect(),
+        }
+    }
+
+    /// The least fee the mempool takes for a transfer, priced by its size and its gas limit
+    pub fn min_fee(&self, transfer: &dyn Transfer) -> Amount {
+        self.fee_per_byte
+            .saturating_mul(transfer.size())
+            .saturating_add(self.fee_per_gas.saturating_mul(transfer.gas_limit()))
+    }
+
+    /// Accounts after the tip
+    pub fn accounts(&self) -> &Accounts {
+        self.history.current()
+    }
+
+    /// A contract at the tip
+    pub fn contract(&self, address: &Address) -> Option<&Contract> {
+        self.contracts.current().get(address)
+    }
+
+    /// Contracts after a block, if it has been applied and not pruned since
+    pub fn contracts_at(&self, hash: &H256) -> Option<Contracts> {
+        self.contracts.at(hash)
+    }
+
+    /// The latest round of a feed at the tip
+    pub fn feed(&self, id: &str) -> Option<&Feed> {
+        self.feeds.current().get(&feed_address(id))
+    }
+
+    /// Feeds after a block, if it has been applied and not pruned since
+    pub fn feeds_at(&self, hash: &H256) -> Option<Feeds> {
+        self.feeds.at(hash)
+    }
+
+    /// Whether a report can update the feeds after the tip: signed by an oracle of the chain,
+    /// for a round above the latest of its feed
+    fn takes_report(&self, feeds: &Feeds, tx: &SignedOracleReport) -> bool {
+        let latest = feeds.get(&feed_address(&tx.report.feed)).map_or(0, |feed| feed.round);
+        tx.report.round > latest && self.oracles.contains(&tx.oracle()) && tx.verify_signature()
+    }
+
+    /// What the mempool checks of an oracle report
+    fn check_report(&self, tx: &SignedOracleReport) -> Result<(), String> {
+        oracle::check_feed_id(&tx.report.feed)?;
+        if !self.oracles.contains(&tx.oracle()) {
+            return Err(format!("{} is not an oracle of this chain", tx.oracle()));
+        }
+        if !tx.verify_signature() {
+            return Err("invalid signature".to_string());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if tx.report.timestamp as u128 > now {
+            return Err("the report is from the future".to_string());
+        }
+        match self.feed(&tx.report.feed) {
+            Some(feed) if tx.report.round <= feed.round => {
+                Err(format!("feed {} is already at round {}", feed.id, feed.round))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Call a contract at the tip without a transaction, as if by `caller` in the next block, and
+    /// keep none of what it stores
+    pub fn call(&self, address: &Address, caller: Address, args: &[u64]) -> Result<Outcome, String> {
+        let contract = self.contract(address).ok_or_else(|| format!("{} is not a contract", address))?;
+        if args.len() > MAX_ARGS {
+            return Err(format!("a call takes at most {} arguments", MAX_ARGS));
+        }
+        let context = Context {
+            caller,
+            args,
+            height: self.history.height().map_or(0, |height| height + 1),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+        };
+        vm::execute(&contract.code, &mut contract.storage.clone(), &context, MAX_GAS)
+    }
+
+    /// What the mempool checks of a contract transaction besides its fee. A call must be to a
+    /// contract at the tip, so it waits for the deploy of its contract to be mined.
+    pub fn check_contract(&self, tx: &ContractTransaction) -> Result<(), String> {
+        if tx.gas_limit > MAX_GAS {
+            return Err(format!("the gas limit must be at most {}", MAX_GAS));
+        }
+        match &tx.action {
+            ContractAction::Deploy { code } => {
+                if code.is_empty() || code.len() > MAX_CODE {
+                    return Err(format!("the code must have between 1 and {} instructions", MAX_CODE));
                 }
-            } else {
-                // todo: add other cases
-                let mut old_receiver_account = accounts.get(&receiver);
-                let mut receiver_account = match old_receiver_account  {
-                    Some(v) => v,
-                    None => {
-                        continue;
-                    }
-                };
-                let mut old_sender_account = accounts.get(&sender);
-                let mut sender_account = match old_sender_account  {
-                    Some(v) => v,
-                    None => {
-                        continue;
-                    }
-                };
-                
-                if sender_account.0 + 1 != nonce {
-                    continue;
+                let gas = code.len() as u64 * DEPLOY_GAS;
+                if gas > tx.gas_limit {
+                    return Err(format!(
+                        "deploying {} instructions takes {} gas, over the gas limit of {}",
+                        code.len(),
+                        gas,
+                        tx.gas_limit
+                    ));
+                }
+                if self.accounts().contains_key(&tx.contract()) {
+                    return Err(format!("the contract address {} already has an account", tx.contract()));
+                }
+            }
+            ContractAction::Call { contract, args } => {
+                if args.len() > MAX_ARGS {
+                    return Err(format!("a call takes at most {} arguments", MAX_ARGS));
+                }
+                if self.contract(contract).is_none() {
+                    return Err(format!("{} is not a contract", contract));
                 }
-                if sender_account.1 < value {
-                    continue;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a block to the accounts, contracts and feeds after its parent, and look at them with
+    /// `f` if it applies, returning what `f` returns with what the block changed. The state is
+    /// left as it was either way.
+    fn apply<R>(
+        &mut self,
+        block: &Block,
+        height: u64,
+        f: impl FnOnce(&Accounts, &Contracts, &Feeds) -> R,
+    ) -> Result<(R, Applied), String> {
+        let mut accounts = self.history.take(block, height)?;
+        // the three histories have the same blocks, so the others have the parent too
+        let mut contracts = self.contracts.take(block, height).expect("contracts kept with the accounts");
+        let mut feeds = self.feeds.take(block, height).expect("feeds kept with the accounts");
+        let result = self
+            .apply_to(block, height, &mut accounts, &mut contracts, &mut feeds)
+            .map(|()| f(&accounts, &contracts, &feeds));
+        let applied = Applied {
+            accounts: self.history.give_back(accounts),
+            contracts: self.contracts.give_back(contracts),
+            feeds: self.feeds.give_back(feeds),
+        };
+        result.map(|result| (result, applied))
+    }
+
+    /// Apply a block to the accounts, contracts and feeds after its parent. The genesis block
+    /// applies to the allocations. A block with a transfer outside its window of validity is
+    /// invalid. The beneficiary gets the fees of the transfers made on top of the block reward.
+    /// Each feed gets the medians of the reports for the highest of its rounds reported, skipping
+    /// reports the state does not take or observed after the block.
+    fn apply_to(
+        &self,
+        block: &Block,
+        height: u64,
+        accounts: &mut Applying<Address, (u32, Amount)>,
+        contracts: &mut Applying<Address, Contract>,
+        feeds: &mut Applying<Address, Feed>,
+    ) -> Result<(), String> {
+        let mut rounds = Rounds::default();
+        let mut fees: Amount = 0;
+        for tx in &block.content.content {
+            if let LedgerTransaction::Oracle(tx) = tx {
+                if tx.report.timestamp as u128 <= block.header.timestamp && self.takes_report(feeds, tx) {
+                    rounds.add(tx.oracle(), &tx.report);
+                }
+                continue;
+            }
+            let transfer = tx
+                .transfer()
+                .ok_or_else(|| format!("transaction {} is not an account transaction", tx.hash()))?;
+            transfer
+                .validity()
+                .check(height, block.header.timestamp)
+                .map_err(|e| format!("transaction {} is {}", tx.hash(), e))?;
+            // a transfer not signed by the sender, or not by enough keys of a multisig sender, is
+            // skipped like one the sender cannot pay for
+            if !tx.verify_signatures() {
+                continue;
+            }
+            let paid = match tx {
+                LedgerTransaction::Contract(tx) => {
+                    run(accounts, contracts, &tx.transaction, height, block.header.timestamp as u64)
+                }
+                _ => pay(accounts, transfer),
+            };
+            if let Some(fee) = paid {
+                fees = fees.checked_add(fee).ok_or("the fees of the block overflow")?;
+            }
+        }
+        let payout = self.reward.at(height).checked_add(fees).ok_or("the fees of the block overflow")?;
+        if payout > 0 {
+            let (nonce, balance) = accounts.get(&block.header.beneficiary).copied().unwrap_or((0, 0));
+            let balance = balance
+                .checked_add(payout)
+                .ok_or_else(|| format!("the reward overflows the balance of {}", block.header.beneficiary))?;
+            accounts.insert(block.header.beneficiary, (nonce, balance));
+        }
+        for feed in rounds.feeds() {
+            feeds.insert(feed_address(&feed.id), feed);
+        }
+        Ok(())
+    }
+}
+
+/// Make the payments of a transfer and take its fee, returning the fee, or skip the transfer if
+/// the sender cannot make all of them: a nonce out of turn, an overdrawn sender or an overflowing
+/// receiver. A payment to an address without an account opens one. A cancel only takes the nonce
+/// and the fee.
+fn pay(accounts: &mut Applying<Address, (u32, Amount)>, transfer: &dyn Transfer) -> Option<Amount> {
+    let sender = transfer.sender();
+    let payments = transfer.payments();
+    let cancel = transfer.is_cancel();
+    if payments.is_empty() || (!cancel && payments.iter().any(|payment| payment.value == 0)) {
+        return None;
+    }
+    let (nonce, balance) = *accounts.get(&sender)?;
+    if nonce.checked_add(1) != Some(transfer.nonce()) {
+        return None;
+    }
+    let total = payments
+        .iter()
+        .try_fold(transfer.fee(), |total, payment| total.checked_add(payment.value))?;
+    // the accounts the transfer changes, kept apart until every payment is known to go through
+    let mut paid = HashMap::new();
+    paid.insert(sender, (transfer.nonce(), balance.checked_sub(total)?));
+    for payment in &payments {
+        let (nonce, balance) = match paid.get(&payment.receiver) {
+            Some(account) => *account,
+            None => accounts.get(&payment.receiver).copied().unwrap_or((0, 0)),
+        };
+        paid.insert(payment.receiver, (nonce, balance.checked_add(payment.value)?));
+    }
+    for (address, account) in paid {
+        accounts.insert(address, account);
+    }
+    Some(transfer.fee())
+}
+
+/// Run a deploy or call and take its fee, returning the fee, or skip the transaction if its nonce
+/// is out of turn, its gas limit is over `MAX_GAS` or the sender cannot pay the fee. A deploy that
+/// does not fit its gas limit or whose address has an account, a call to an address without a
+/// contract and a call that fails change no contract but still take the nonce and the fee.
+fn run(
+    accounts: &mut Applying<Address, (u32, Amount)>,
+    contracts: &mut Applying<Address, Contract>,
+    tx: &ContractTransaction,
+    height: u64,
+    timestamp: u64,
+) -> Option<Amount> {
+    if tx.gas_limit > MAX_GAS {
+        return None;
+    }
+    let (nonce, balance) = *accounts.get(&tx.sender)?;
+    if nonce.checked_add(1) != Some(tx.nonce) {
+        return None;
+    }
+    accounts.insert(tx.sender, (tx.nonce, balance.checked_sub(tx.fee)?));
+    let address = tx.contract();
+    match &tx.action {
+        ContractAction::Deploy { code } => {
+            let gas = (code.len() as u64).saturating_mul(DEPLOY_GAS);
+            if !code.is_empty() && code.len() <= MAX_CODE && gas <= tx.gas_limit && !accounts.contains_key(&address) {
+                accounts.insert(address, (0, 0));
+                contracts.insert(address, Contract { owner: tx.sender, code: code.clone(), storage: BTreeMap::new() });
+            }
+        }
+        ContractAction::Call { args, .. } => {
+            if let Some(contract) = contracts.get(&address) {
+                let context = Context { caller: tx.sender, args, height, timestamp };
+                // the call runs on a copy of the storage, kept only if the call does not fail
+                let mut storage = contract.storage.clone();
+                if args.len() <= MAX_ARGS && vm::execute(&contract.code, &mut storage, &context, tx.gas_limit).is_ok() {
+                    let contract = Contract { storage, ..contract.clone() };
+                    contracts.insert(address, contract);
                 }
-                let mut sender_account = *sender_account;
-                sender_account.0 = sender_account.0 + 1;
-                sender_account.1 -= value;
-                let mut receiver_account = *receiver_account;
-                receiver_account.1 += value;
-                // println!("sender {:?} nonce {:?} value {:?} receiver {:?} value {:?} tx_value {:?}", sender, sender_account.0, sender_account.1, receiver, receiver_account.1, value);
-                accounts.insert(sender, sender_account.clone());
-                accounts.insert(receiver, receiver_account.clone());
             }
         }
-        if isValid {
-            self.history.insert(block.hash(), accounts.clone());
-            self.accounts = accounts;
+    }
+    Some(tx.fee)
+}
+
+impl Ledger for State {
+    fn model(&self) -> LedgerModel {
+        LedgerModel::Account
+    }
+
+    /// Apply a block at the given height on top of its parent, paying its beneficiary the block
+    /// reward. A block that commits to the state must commit to the accounts it results in.
+    fn update(&mut self, block: &Block, height: u64) -> Result<(), String> {
+        let start = Instant::now();
+        let (root, applied) = self.apply(block, height, |accounts, contracts, feeds| {
+            block.header.has_state_root().then(|| state_tree(accounts, contracts, feeds).root())
+        })?;
+        if let Some(root) = root {
+            if root != block.header.state_root {
+                return Err(format!("state root {} does not match the accounts {}", block.header.state_root, root));
+            }
+        }
+        self.history.insert(block, height, applied.accounts);
+        self.contracts.insert(block, height, applied.contracts);
+        self.feeds.insert(block, height, applied.feeds);
+        METRICS.state_update.observe(start.elapsed());
+        Ok(())
+    }
+
+    fn root_after(&mut self, block: &Block, height: u64) -> Result<H256, String> {
+        let (root, _) = self.apply(block, height, |accounts, contracts, feeds| state_tree(accounts, contracts, feeds).root())?;
+        Ok(root)
+    }
+
+    /// Balance and nonce are checked when the transaction is applied to the state, and the
+    /// start of its window of validity when it is mined
+    fn check(&self, tx: &LedgerTransaction) -> Result<(), String> {
+        if let LedgerTransaction::Oracle(tx) = tx {
+            return self.check_report(tx);
+        }
+        let transfer = tx.transfer().ok_or("this chain only takes account transactions")?;
+        let payments = transfer.payments();
+        match tx {
+            LedgerTransaction::Contract(tx) => self.check_contract(&tx.transaction)?,
+            _ if payments.is_empty() => return Err("a batch needs at least one payment".to_string()),
+            _ if !transfer.is_cancel() && payments.iter().any(|payment| payment.value == 0) => {
+                return Err("the value must be positive".to_string());
+            }
+            _ => {}
+        }
+        if transfer.fee() < self.min_fee(transfer) {
+            let mut error = format!(
+                "the fee must be at least {}, {} per byte of the {} bytes of the transfer",
+                self.min_fee(transfer),
+                self.fee_per_byte,
+                transfer.size()
+            );
+            if transfer.gas_limit() > 0 {
+                error += &format!(" and {} per unit of its gas limit of {}", self.fee_per_gas, transfer.gas_limit());
+            }
+            return Err(error);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if transfer.validity().expired(self.history.height().unwrap_or(0), now) {
+            return Err("the transaction has expired".to_string());
+        }
+        match tx {
+            LedgerTransaction::Multisig(tx) => tx.check_signatures(),
+            _ if tx.verify_signatures() => Ok(()),
+            _ => Err("invalid signature".to_string()),
         }
-        return isValid;
+    }
 
+    /// Block rewards are paid to the beneficiary in the header
+    fn coinbase(&self, _beneficiary: Address, _height: u64) -> Result<Option<LedgerTransaction>, String> {
+        Ok(None)
+    }
 
+    /// The oracle reports the tip takes, which pay no fee, then the highest fees per byte, with
+    /// the transfers of each sender in the order of their nonces, so none of them is skipped for
+    /// coming before the one it follows
+    fn select<'a>(&self, candidates: Vec<&'a LedgerTransaction>) -> Vec<&'a LedgerTransaction> {
+        let feeds = self.feeds.current();
+        let reports: Vec<&LedgerTransaction> = candidates
+            .iter()
+            .copied()
+            .filter(|tx| matches!(tx, LedgerTransaction::Oracle(report) if self.takes_report(feeds, report)))
+            .collect();
+        let mut selected: Vec<(&dyn Transfer, &LedgerTransaction)> =
+            candidates.into_iter().filter_map(|tx| tx.transfer().map(|t| (t, tx))).collect();
+        selected.sort_by(|(a, _), (b, _)| {
+            (b.fee() as u128 * a.size() as u128).cmp(&(a.fee() as u128 * b.size() as u128))
+        });
+        // the places the sort gave the transfers of a sender are filled in the order of nonces
+        let mut by_sender: HashMap<Address, Vec<(u32, &LedgerTransaction)>> = HashMap::new();
+        for (transfer, tx) in &selected {
+            by_sender.entry(transfer.sender()).or_default().push((transfer.nonce(), *tx));
+        }
+        for txs in by_sender.values_mut() {
+            txs.sort_by_key(|(nonce, _)| std::cmp::Reverse(*nonce));
+        }
+        let transfers = selected
+            .iter()
+            .map(|(transfer, _)| by_sender.get_mut(&transfer.sender()).unwrap().pop().unwrap().1);
+        reports.into_iter().chain(transfers).collect()
+    }
 
-        // // let tx = signedT.transaction.clone();
-        // let mut res = true;
-        // let mut ac_nonce = self.accounts[&tx.sender].0;
-        // let balance = self.accounts[&tx.sender].1;
-        // let new_balance = balance - tx.value;    
-        // if new_balance >=0 {
-        //     ac_nonce -= 1;
-        //     self.accounts.insert(tx.sender, (ac_nonce,new_balance));
-        //     // update reverver balance
-        //     let (r_nonce, r_balance) = self.accounts[&tx.receiver];
-        //     let r_new_balance = r_balance + tx.value;
-        //     self.accounts.insert(tx.receiver, (r_nonce, r_new_balance));
-        // }else{
-        //     res = false;
-        // }
-        // return res;
+    fn balance(&self, address: &Address) -> Amount {
+        self.accounts().get(address).map_or(0, |a| a.1)
+    }
+
+    fn nonce(&self, address: &Address) -> u32 {
+        self.accounts().get(address).map_or(0, |a| a.0)
+    }
+
+    fn unspent(&self, _address: &Address) -> Vec<(OutPoint, Amount)> {
+        Vec::new()
+    }
+
+    fn accounts_at(&self, hash: &H256) -> Option<Accounts> {
+        self.history.at(hash)
+    }
+
+    fn as_accounts(&self) -> Option<&State> {
+        Some(self)
+    }
+
+    fn set_history_depth(&mut self, depth: Option<u64>) {
+        self.history.depth = depth;
+        self.contracts.depth = depth;
+        self.feeds.depth = depth;
     }
 }
 
 // #[cfg(any(test, test_utilities))]
-pub fn generate_random_transaction(sender: Address, receiver: Address, value: i32, nonce: u32) -> Transaction {
-    return Transaction{nonce, sender, receiver, value};
+pub fn generate_random_transaction(sender: Address, receiver: Address, value: Amount, nonce: u32) -> Transaction {
+    Transaction{nonce, sender, receiver, value, fee: 0, validity: Validity::default()}
     
     
     // let mut rng = rand::thread_rng();
@@ -229,32 +793,312 @@ pub fn generate_random_transaction(sender: Address, receiver: Address, value: i3
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use crate::types::key_pair;
-//     use ring::signature::KeyPair;
-
-
-//     #[test]
-//     fn sign_verify() {
-//         let t = generate_random_transaction();
-//         let key = key_pair::random();
-//         let signature = sign(&t, &key);
-//         let result = verify(&t, key.public_key().as_ref(), signature.as_ref());
-//         // println!("{:?}", result);
-//         assert!(verify(&t, key.public_key().as_ref(), signature.as_ref()));
-//     }
-//     #[test]
-//     fn sign_verify_two() {
-//         let t = generate_random_transaction();
-//         let key = key_pair::random();
-//         let signature = sign(&t, &key);
-//         let key_2 = key_pair::random();
-//         let t_2 = generate_random_transaction();
-//         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
-//         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::Allocation;
+    use crate::types::block::generate_random_block;
+    use crate::types::key_pair;
+    use crate::types::multisig::MultisigTransaction;
+
+    fn signed_transfer(key: &Ed25519KeyPair) -> SignedTransaction {
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        generate_random_signed_transaction(sender, generate_address(), 10, 1, key)
+    }
+
+    #[test]
+    fn sign_verify() {
+        let key = key_pair::random();
+        let t = signed_transfer(&key);
+        assert!(verify_signature(&t.transaction, &t.public_key, &t.signature));
+
+        // someone else's key, or a changed transaction
+        let other = key_pair::random();
+        let forged = sign(&t.transaction, &other);
+        assert!(!verify_signature(&t.transaction, other.public_key().as_ref(), forged.as_ref()));
+        let mut changed = t.transaction.clone();
+        changed.value = 11;
+        assert!(!verify_signature(&changed, &t.public_key, &t.signature));
+    }
+
+    #[test]
+    fn encodings() {
+        let signed = signed_transfer(&key_pair::random());
+        let t = LedgerTransaction::from(signed.clone());
+        for encoded in [t.to_hex(), t.to_json()] {
+            let decoded = LedgerTransaction::decode(&encoded, LedgerModel::Account).unwrap();
+            assert!(matches!(decoded, LedgerTransaction::Account(_)));
+            assert_eq!(decoded.hash(), t.hash());
+            // the variant decides the kind, so no other kind is tried
+            assert!(MultisigTransaction::decode(&encoded).is_err());
+            assert!(LedgerTransaction::decode(&encoded, LedgerModel::Utxo).is_err());
+        }
+        assert!(t.to_json().contains(&signed.transaction.sender.to_string()));
+        assert!(LedgerTransaction::decode(&format!("{}00", t.to_hex()), LedgerModel::Account).is_err());
+        assert!(LedgerTransaction::decode("zz", LedgerModel::Account).is_err());
+    }
+
+    /// A block on top of `parent` holding `content`, committing to the state after it
+    fn state_block(state: &mut State, parent: &H256, height: u64, content: Vec<LedgerTransaction>) -> Block {
+        let mut block = generate_random_block(parent);
+        block.content.content = content;
+        block.header.state_root = state.root_after(&block, height).unwrap();
+        block
+    }
+
+    /// Small amounts, which add up, and any amounts, which overflow
+    fn amount() -> impl proptest::strategy::Strategy<Value = Amount> {
+        proptest::prop_oneof![0..1000 as Amount, proptest::num::u64::ANY]
+    }
+
+    proptest::proptest! {
+        /// A transfer moves exactly its value, or nothing if it is zero, overdraws the sender or
+        /// overflows the receiver
+        #[test]
+        fn transfers_conserve_value(sender_balance in amount(), receiver_balance in amount(), value in amount()) {
+            let key = key_pair::random();
+            let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+            let receiver = generate_address();
+            let spec = ChainSpec {
+                allocations: vec![
+                    Allocation { address: sender, balance: sender_balance },
+                    Allocation { address: receiver, balance: receiver_balance },
+                ],
+                ..Default::default()
+            };
+            let mut state = State::new(&spec);
+            let genesis = spec.genesis();
+            state.update(&genesis, 0).unwrap();
+            let tx = generate_random_signed_transaction(sender, receiver, value, 1, &key);
+            proptest::prop_assert_eq!(state.check(&tx.clone().into()).is_ok(), value > 0);
+
+            let block = state_block(&mut state, &genesis.hash(), 1, vec![tx.into()]);
+            state.update(&block, 1).unwrap();
+            let accounts = state.accounts();
+            let applies = value > 0 && value <= sender_balance && receiver_balance.checked_add(value).is_some();
+            if applies {
+                proptest::prop_assert_eq!(accounts[&sender], (1, sender_balance - value));
+                proptest::prop_assert_eq!(accounts[&receiver], (0, receiver_balance + value));
+            } else {
+                proptest::prop_assert_eq!(accounts[&sender], (0, sender_balance));
+                proptest::prop_assert_eq!(accounts[&receiver], (0, receiver_balance));
+            }
+        }
+
+        /// A transfer to itself from an address without an account opens no account
+        #[test]
+        fn self_transfers_open_no_account(value in amount(), nonce in proptest::num::u32::ANY) {
+            let key = key_pair::random();
+            let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+            let spec = ChainSpec::default();
+            let mut state = State::new(&spec);
+            let genesis = spec.genesis();
+            state.update(&genesis, 0).unwrap();
+            let tx = generate_random_signed_transaction(sender, sender, value, nonce, &key);
+            let block = state_block(&mut state, &genesis.hash(), 1, vec![tx.into()]);
+            state.update(&block, 1).unwrap();
+            proptest::prop_assert!(!state.accounts().contains_key(&sender));
+        }
+    }
+
+    #[test]
+    fn multisig_transfers() {
+        use crate::types::multisig::{MultisigPolicy, PartialSignature};
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let policy = MultisigPolicy::new(2, keys.iter().map(|k| k.public_key().as_ref().to_vec()).collect()).unwrap();
+        let treasury = policy.address();
+        let receiver = generate_address();
+        let spec = ChainSpec {
+            allocations: vec![
+                Allocation { address: treasury, balance: 100 },
+                Allocation { address: receiver, balance: 0 },
+            ],
+            ..Default::default()
+        };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+
+        let transfer = Transaction { nonce: 1, sender: treasury, receiver, value: 30, ..Default::default() };
+        let mut tx = MultisigTransaction::unsigned(transfer.clone(), policy).unwrap();
+        tx.add_signature(PartialSignature::sign(&transfer, &keys[1])).unwrap();
+        let under_signed: LedgerTransaction = tx.clone().into();
+        assert!(state.check(&under_signed).unwrap_err().contains("1 of the 2"));
+        tx.add_signature(PartialSignature::sign(&transfer, &keys[2])).unwrap();
+        let signed: LedgerTransaction = tx.into();
+        state.check(&signed).unwrap();
+        // one of the keys signing alone, as if it owned the account
+        let single = SignedTransaction {
+            transaction: transfer.clone(),
+            public_key: keys[0].public_key().as_ref().to_vec(),
+            signature: sign(&transfer, &keys[0]).as_ref().to_vec(),
+        };
+
+        // only the transfer with enough signatures applies
+        let block = state_block(&mut state, &genesis.hash(), 1, vec![under_signed, single.into(), signed]);
+        state.update(&block, 1).unwrap();
+        assert_eq!(state.accounts()[&treasury], (1, 70));
+        assert_eq!(state.accounts()[&receiver], (0, 30));
+    }
+
+    #[test]
+    fn validity_windows() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = generate_address();
+        let spec = ChainSpec {
+            allocations: vec![
+                Allocation { address: sender, balance: 100 },
+                Allocation { address: receiver, balance: 0 },
+            ],
+            ..Default::default()
+        };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+
+        // a vesting payout for heights 2 to 3, and a refund that expired at height 1
+        let locked = Validity { valid_after_height: Some(1), expires_at_height: Some(4), ..Default::default() };
+        let vesting = |nonce| {
+            let transfer = Transaction { nonce, sender, receiver, value: 10, validity: locked, ..Default::default() };
+            LedgerTransaction::from(sign_transaction(transfer, &key))
+        };
+        let expired = Validity { expires_at_height: Some(1), ..Default::default() };
+        let refund = Transaction { nonce: 1, sender, receiver, value: 5, validity: expired, ..Default::default() };
+        let refund = sign_transaction(refund, &key);
+        assert!(locked.check(1, 0).is_err());
+        assert!(locked.check(3, 0).is_ok());
+        assert!(locked.check(4, 0).is_err());
+        assert!(state.check(&vesting(1)).is_ok());
+        assert_eq!(state.check(&refund.clone().into()).unwrap_err(), "the transaction has expired");
+
+        // the mempool holds the payout until a block can take it
+        let mut mempool = TransactionMemopool::new();
+        mempool.trans_map.insert(vesting(2).hash(), vesting(2));
+        assert!(mempool.ready(1, 0).is_empty());
+        assert_eq!(mempool.ready(2, 0).len(), 1);
+
+        let mut block = generate_random_block(&genesis.hash());
+        block.content.content = vec![vesting(1)];
+        assert!(state.root_after(&block, 1).unwrap_err().contains("only valid above height 1"));
+        let transfer = generate_random_signed_transaction(sender, receiver, 1, 1, &key);
+        let block = state_block(&mut state, &genesis.hash(), 1, vec![transfer.into()]);
+        state.update(&block, 1).unwrap();
+        let next = state_block(&mut state, &block.hash(), 2, vec![vesting(2)]);
+        state.update(&next, 2).unwrap();
+        assert_eq!(state.accounts()[&receiver], (0, 11));
+
+        // a block past its expiry takes it out of the mempool, as it does those it includes
+        let waiting = vesting(3);
+        mempool.trans_map.insert(waiting.hash(), waiting);
+        mempool.remove_block(&next, 2);
+        assert_eq!(mempool.trans_map.len(), 1);
+        let mut late = next.clone();
+        late.content.content.clear();
+        mempool.remove_block(&late, 3);
+        assert!(mempool.trans_map.is_empty());
+    }
+
+    #[test]
+    fn replace_and_cancel() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = generate_address();
+        let spec = ChainSpec {
+            allocations: vec![
+                Allocation { address: sender, balance: 100 },
+                Allocation { address: receiver, balance: 0 },
+            ],
+            ..Default::default()
+        };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+        let transfer = |nonce, receiver, value, fee| {
+            let transfer = Transaction { nonce, sender, receiver, value, fee, ..Default::default() };
+            LedgerTransaction::from(sign_transaction(transfer, &key))
+        };
+
+        // only a higher fee replaces a transfer with the same nonce
+        let mut mempool = TransactionMemopool::new();
+        let first = transfer(1, receiver, 50, 1);
+        assert!(mempool.insert(first.clone()).unwrap());
+        assert!(!mempool.insert(first.clone()).unwrap());
+        assert!(mempool.insert(transfer(1, receiver, 60, 1)).unwrap_err().contains("must exceed"));
+        assert!(mempool.insert(transfer(2, receiver, 10, 0)).unwrap());
+        let cancel = transfer(1, sender, 0, 2);
+        state.check(&cancel).unwrap();
+        assert!(state.check(&transfer(1, receiver, 0, 2)).is_err());
+        assert!(mempool.insert(cancel.clone()).unwrap());
+        assert_eq!(mempool.trans_map.len(), 2);
+        assert_eq!(mempool.pending(&sender, 1).unwrap().0, &cancel.hash());
+
+        // the cancel takes the nonce and the fee, so the transfer it replaced can no longer apply
+        let block = state_block(&mut state, &genesis.hash(), 1, vec![cancel, first]);
+        state.update(&block, 1).unwrap();
+        assert_eq!(state.accounts()[&sender], (1, 98));
+        assert_eq!(state.accounts()[&receiver], (0, 0));
+    }
+
+    #[test]
+    fn state_history() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = generate_address();
+        let spec = ChainSpec {
+            allocations: vec![
+                Allocation { address: sender, balance: 1000 },
+                Allocation { address: receiver, balance: 0 },
+            ],
+            ..Default::default()
+        };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+        let transfer_block = |state: &mut State, parent: &H256, height, nonce| {
+            let content = vec![generate_random_signed_transaction(sender, receiver, 1, nonce, &key).into()];
+            state_block(state, parent, height, content)
+        };
+        let mut hashes = vec![genesis.hash()];
+        for height in 1..=150 {
+            let block = transfer_block(&mut state, &hashes[height - 1], height as u64, height as u32);
+            state.update(&block, height as u64).unwrap();
+            hashes.push(block.hash());
+        }
+        for height in [0, 1, 63, 64, 65, 128, 149, 150] {
+            let accounts = state.accounts_at(&hashes[height]).unwrap();
+            assert_eq!(accounts[&sender], (height as u32, 1000 - height as Amount));
+            assert_eq!(accounts[&receiver], (0, height as Amount));
+        }
+
+        // a fork is applied to the state of its parent, and leaves the tip alone
+        let fork = transfer_block(&mut state, &hashes[70], 71, 71);
+        state.update(&fork, 71).unwrap();
+        assert_eq!(state.accounts_at(&fork.hash()).unwrap()[&sender], (71, 929));
+        assert_eq!(state.accounts()[&sender], (150, 850));
+        let mut invalid = transfer_block(&mut state, &hashes[70], 71, 71);
+        invalid.header.state_root = [1; 32].into();
+        assert!(state.update(&invalid, 71).is_err());
+        // a block on top of the tip is applied in place, and undone if it is invalid
+        let mut invalid = transfer_block(&mut state, &hashes[150], 151, 151);
+        invalid.header.state_root = [1; 32].into();
+        assert!(state.update(&invalid, 151).is_err());
+        assert_eq!(state.accounts()[&sender], (150, 850));
+        assert_eq!(state.accounts()[&receiver], (0, 150));
+
+        // pruning keeps at least `history_depth` blocks below the tip
+        state.set_history_depth(Some(20));
+        let block = transfer_block(&mut state, &hashes[150], 151, 151);
+        state.update(&block, 151).unwrap();
+        assert!(state.accounts_at(&hashes[127]).is_none());
+        assert_eq!(state.accounts_at(&hashes[131]).unwrap()[&sender], (131, 869));
+        assert_eq!(state.accounts_at(&hashes[128]).unwrap()[&sender], (128, 872));
+        let fork = transfer_block(&mut state, &hashes[128], 129, 129);
+        assert!(state.update(&fork, 129).is_ok());
+        let mut deep = fork.clone();
+        deep.header.parent = hashes[100];
+        assert!(state.update(&deep, 101).is_err());
+    }
+}
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
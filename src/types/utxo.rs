@@ -0,0 +1,481 @@
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Instant;
+
+use super::address::Address;
+use super::block::Block;
+use super::hash::{Hashable, H256};
+use super::ledger::{Applying, History, Ledger, LedgerTransaction};
+use super::merkle::{MerkleTree, Scheme};
+use super::transaction::{Accounts, Amount, State};
+use crate::blockchain::spec::{ChainSpec, LedgerModel, RewardSchedule};
+use crate::metrics::METRICS;
+
+/// An output of a transaction: the transaction hash and the index of the output in it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub tx: H256,
+    pub index: u32,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.tx, self.index)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Output {
+    pub address: Address,
+    pub value: Amount,
+}
+
+/// Spends an output, signed by the key of the address it pays
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Input {
+    pub outpoint: OutPoint,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "crate::types::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// Spends outputs of earlier transactions into new ones. Whatever the inputs hold beyond the
+/// outputs is destroyed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+}
+
+impl UtxoTransaction {
+    /// A transaction spending `spent`, outputs paying the address of `key`, into `outputs`
+    pub fn sign(spent: &[OutPoint], outputs: Vec<Output>, key: &Ed25519KeyPair) -> Self {
+        let bytes = signed_data(spent, &outputs);
+        let signature = key.sign(&bytes);
+        let inputs = spent
+            .iter()
+            .map(|outpoint| Input {
+                outpoint: *outpoint,
+                public_key: key.public_key().as_ref().to_vec(),
+                signature: signature.as_ref().to_vec(),
+            })
+            .collect();
+        UtxoTransaction { inputs, outputs }
+    }
+
+    /// The transaction minting the reward of the block at `height`. Its single input refers to no
+    /// transaction and holds the height instead, so the coinbases of different blocks differ,
+    /// which fails past the heights an output index can hold.
+    pub fn coinbase(beneficiary: Address, reward: Amount, height: u64) -> Result<Self, String> {
+        let index = u32::try_from(height).map_err(|_| format!("height {} does not fit in a coinbase", height))?;
+        Ok(UtxoTransaction {
+            inputs: vec![Input {
+                outpoint: OutPoint { tx: H256::default(), index },
+                public_key: Vec::new(),
+                signature: Vec::new(),
+            }],
+            outputs: vec![Output { address: beneficiary, value: reward }],
+        })
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].outpoint.tx == H256::default()
+    }
+
+    /// Check that every input is signed by its key. A coinbase has nothing to sign.
+    pub fn verify_signatures(&self) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+        let outpoints: Vec<OutPoint> = self.inputs.iter().map(|input| input.outpoint).collect();
+        let bytes = signed_data(&outpoints, &self.outputs);
+        self.inputs.iter().all(|input| {
+            UnparsedPublicKey::new(&ED25519, &input.public_key)
+                .verify(&bytes, &input.signature)
+                .is_ok()
+        })
+    }
+}
+
+impl Hashable for UtxoTransaction {
+    fn hash(&self) -> H256 {
+        let data = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &data).into()
+    }
+}
+
+/// What the inputs of a transaction sign: everything but the keys and signatures
+fn signed_data(outpoints: &[OutPoint], outputs: &[Output]) -> Vec<u8> {
+    bincode::serialize(&(outpoints, outputs)).unwrap()
+}
+
+/// Unspent outputs
+type Utxos = HashMap<OutPoint, Output>;
+
+/// The outputs a block created, and those it spent as `None`
+type Changes = HashMap<OutPoint, Option<Output>>;
+
+/// An unspent output as a leaf of the state root
+#[derive(Serialize)]
+struct Entry(OutPoint, Output);
+
+impl Hashable for Entry {
+    fn hash(&self) -> H256 {
+        digest::digest(&digest::SHA256, &bincode::serialize(self).unwrap()).into()
+    }
+}
+
+/// The unspent outputs after each block. The allocations of the chain spec are the outputs of
+/// the spec hash, and each block mints its reward in a coinbase transaction.
+pub struct UtxoState {
+    history: History<OutPoint, Output>,
+    pub reward: RewardSchedule,
+}
+
+impl UtxoState {
+    /// The outputs before the genesis block: the allocations of the chain spec
+    pub fn new(spec: &ChainSpec) -> Self {
+        let tx = spec.hash();
+        let utxos = spec
+            .allocations
+            .iter()
+            .enumerate()
+            .map(|(index, allocation)| {
+                let output = Output { address: allocation.address, value: allocation.balance };
+                (OutPoint { tx, index: index as u32 }, output)
+            })
+            .collect();
+        UtxoState { history: History::new(utxos), reward: spec.block_reward.clone() }
+    }
+
+    /// Apply a block to the outputs after its parent, and look at them with `f` if it applies,
+    /// returning what `f` returns with the outputs the block changed. The state is left as it was
+    /// either way.
+    fn apply<R>(&mut self, block: &Block, height: u64, f: impl FnOnce(&Utxos) -> R) -> Result<(R, Changes), String> {
+        let mut utxos = self.history.take(block, height)?;
+        let result = self.apply_to(block, height, &mut utxos).map(|()| f(&utxos));
+        let changed = self.history.give_back(utxos);
+        result.map(|result| (result, changed))
+    }
+
+    /// Apply a block to the outputs after its parent. Unlike the account ledger, which skips
+    /// transfers it cannot make, a block with a transaction that cannot be applied is invalid.
+    fn apply_to(&self, block: &Block, height: u64, utxos: &mut Applying<OutPoint, Output>) -> Result<(), String> {
+        for (i, tx) in block.content.content.iter().enumerate() {
+            let hash = tx.hash();
+            let tx = match tx {
+                LedgerTransaction::Utxo(tx) => tx,
+                _ => return Err(format!("transaction {} is not a UTXO transaction", hash)),
+            };
+            if tx.is_coinbase() {
+                check_coinbase(tx, i, height, self.reward.at(height))
+                    .map_err(|e| format!("coinbase {}: {}", hash, e))?;
+            } else {
+                check_spend(utxos, tx).map_err(|e| format!("transaction {}: {}", hash, e))?;
+                for input in &tx.inputs {
+                    utxos.remove(&input.outpoint);
+                }
+            }
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint { tx: hash, index: index as u32 };
+                if utxos.insert(outpoint, *output).is_some() {
+                    return Err(format!("transaction {} creates output {} again", hash, outpoint));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A coinbase must be the first transaction of its block, hold the height of the block, and mint
+/// no more than the block reward.
+fn check_coinbase(tx: &UtxoTransaction, position: usize, height: u64, reward: Amount) -> Result<(), String> {
+    if position != 0 {
+        return Err("a coinbase must be the first transaction of its block".to_string());
+    }
+    if tx.inputs[0].outpoint.index as u64 != height {
+        return Err(format!("made for height {}, not {}", tx.inputs[0].outpoint.index, height));
+    }
+    match total(&tx.outputs) {
+        Some(minted) if minted <= reward => Ok(()),
+        _ => Err(format!("mints more than the reward of {}", reward)),
+    }
+}
+
+/// Check that `tx` spends outputs in `utxos` signed for by their owners, each once, into positive
+/// outputs worth no more than them.
+fn check_spend(utxos: &Utxos, tx: &UtxoTransaction) -> Result<(), String> {
+    if tx.inputs.is_empty() || tx.outputs.is_empty() {
+        return Err("a transaction needs inputs and outputs".to_string());
+    }
+    if !tx.verify_signatures() {
+        return Err("invalid signature".to_string());
+    }
+    let mut spent = HashSet::new();
+    let mut total_in: Amount = 0;
+    for input in &tx.inputs {
+        if !spent.insert(input.outpoint) {
+            return Err(format!("spends output {} twice", input.outpoint));
+        }
+        let output = utxos
+            .get(&input.outpoint)
+            .ok_or_else(|| format!("output {} is spent or does not exist", input.outpoint))?;
+        if Address::from_public_key_bytes(&input.public_key) != output.address {
+            return Err(format!("output {} does not belong to the key spending it", input.outpoint));
+        }
+        total_in = total_in
+            .checked_add(output.value)
+            .ok_or_else(|| "the inputs overflow".to_string())?;
+    }
+    if tx.outputs.iter().any(|output| output.value == 0) {
+        return Err("outputs must be positive".to_string());
+    }
+    let total_out = total(&tx.outputs).ok_or_else(|| "the outputs overflow".to_string())?;
+    if total_out > total_in {
+        return Err(format!("pays {} out of {}", total_out, total_in));
+    }
+    Ok(())
+}
+
+/// What the outputs hold together, unless that overflows
+fn total(outputs: &[Output]) -> Option<Amount> {
+    outputs.iter().try_fold(0 as Amount, |sum, output| sum.checked_add(output.value))
+}
+
+/// Root of a Merkle tree over the unspent outputs, sorted by outpoint
+fn root(utxos: &Utxos) -> H256 {
+    let mut entries: Vec<Entry> = utxos.iter().map(|(outpoint, output)| Entry(*outpoint, *output)).collect();
+    entries.sort_by_key(|entry| entry.0);
+    MerkleTree::with_scheme(&entries, Scheme::Tagged).root()
+}
+
+impl Ledger for UtxoState {
+    fn model(&self) -> LedgerModel {
+        LedgerModel::Utxo
+    }
+
+    fn update(&mut self, block: &Block, height: u64) -> Result<(), String> {
+        let start = Instant::now();
+        let (root, changed) = self.apply(block, height, |utxos| block.header.has_state_root().then(|| root(utxos)))?;
+        if let Some(root) = root {
+            if root != block.header.state_root {
+                return Err(format!("state root {} does not match the unspent outputs {}", block.header.state_root, root));
+            }
+        }
+        self.history.insert(block, height, changed);
+        METRICS.state_update.observe(start.elapsed());
+        Ok(())
+    }
+
+    fn root_after(&mut self, block: &Block, height: u64) -> Result<H256, String> {
+        let (root, _) = self.apply(block, height, root)?;
+        Ok(root)
+    }
+
+    /// Coinbases only come in blocks, and the inputs must be unspent at the tip
+    fn check(&self, tx: &LedgerTransaction) -> Result<(), String> {
+        let tx = match tx {
+            LedgerTransaction::Utxo(tx) => tx,
+            _ => return Err("this chain only takes UTXO transactions".to_string()),
+        };
+        if tx.is_coinbase() {
+            return Err("a coinbase cannot be sent on its own".to_string());
+        }
+        check_spend(self.history.current(), tx)
+    }
+
+    fn coinbase(&self, beneficiary: Address, height: u64) -> Result<Option<LedgerTransaction>, String> {
+        match self.reward.at(height) {
+            0 => Ok(None),
+            reward => UtxoTransaction::coinbase(beneficiary, reward, height).map(|tx| Some(tx.into())),
+        }
+    }
+
+    /// Transactions spending the same output conflict, and the first one is taken
+    fn select<'a>(&self, candidates: Vec<&'a LedgerTransaction>) -> Vec<&'a LedgerTransaction> {
+        let mut spent = HashSet::new();
+        let mut selected = Vec::new();
+        for candidate in candidates {
+            let tx = match candidate {
+                LedgerTransaction::Utxo(tx) if !tx.is_coinbase() => tx,
+                _ => continue,
+            };
+            if check_spend(self.history.current(), tx).is_err()
+                || tx.inputs.iter().any(|input| spent.contains(&input.outpoint))
+            {
+                continue;
+            }
+            spent.extend(tx.inputs.iter().map(|input| input.outpoint));
+            selected.push(candidate);
+        }
+        selected
+    }
+
+    fn balance(&self, address: &Address) -> Amount {
+        self.unspent(address).iter().fold(0, |sum, (_, value)| sum.saturating_add(*value))
+    }
+
+    fn nonce(&self, _address: &Address) -> u32 {
+        0
+    }
+
+    fn unspent(&self, address: &Address) -> Vec<(OutPoint, Amount)> {
+        let mut unspent: Vec<(OutPoint, Amount)> = self
+            .history
+            .current()
+            .iter()
+            .filter(|(_, output)| output.address == *address)
+            .map(|(outpoint, output)| (*outpoint, output.value))
+            .collect();
+        unspent.sort();
+        unspent
+    }
+
+    /// The balance of an address is what its unspent outputs hold
+    fn accounts_at(&self, hash: &H256) -> Option<Accounts> {
+        let mut accounts = Accounts::new();
+        for output in self.history.at(hash)?.values() {
+            let account = accounts.entry(output.address).or_insert((0, 0));
+            account.1 = account.1.saturating_add(output.value);
+        }
+        Some(accounts)
+    }
+
+    fn as_accounts(&self) -> Option<&State> {
+        None
+    }
+
+    fn set_history_depth(&mut self, depth: Option<u64>) {
+        self.history.depth = depth;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::Allocation;
+    use crate::types::block::{Content, Header, CURRENT_VERSION};
+    use crate::types::key_pair;
+
+    fn block(parent: &H256, content: Vec<LedgerTransaction>) -> Block {
+        Block {
+            header: Header {
+                version: CURRENT_VERSION,
+                parent: *parent,
+                nonce: 0,
+                difficulty: H256::default(),
+                timestamp: 0,
+                merkle_root: H256::default(),
+                state_root: H256::default(),
+                beneficiary: Address::default(),
+            },
+            content: Content { content },
+        }
+    }
+
+    /// Small amounts, which add up, and any amounts, which overflow
+    fn amount() -> impl proptest::strategy::Strategy<Value = Amount> {
+        proptest::prop_oneof![0..1000 as Amount, proptest::num::u64::ANY]
+    }
+
+    proptest::proptest! {
+        /// A transaction may pay positive outputs worth up to what its inputs hold, and neither
+        /// side may overflow
+        #[test]
+        fn spends_stay_within_inputs(
+            inputs in proptest::collection::vec(amount(), 1..4),
+            outputs in proptest::collection::vec(amount(), 0..4),
+        ) {
+            let key = key_pair::random();
+            let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+            let utxos: Utxos = inputs
+                .iter()
+                .enumerate()
+                .map(|(i, value)| (OutPoint { tx: H256::default(), index: i as u32 }, Output { address: owner, value: *value }))
+                .collect();
+            let spent: Vec<OutPoint> = utxos.keys().copied().collect();
+            let outputs: Vec<Output> = outputs.iter().map(|value| Output { address: owner, value: *value }).collect();
+            let tx = UtxoTransaction::sign(&spent, outputs.clone(), &key);
+
+            let total_in: u128 = inputs.iter().map(|v| *v as u128).sum();
+            let total_out: u128 = outputs.iter().map(|o| o.value as u128).sum();
+            let valid = !outputs.is_empty()
+                && outputs.iter().all(|o| o.value > 0)
+                && total_in <= Amount::MAX as u128
+                && total_out <= total_in;
+            proptest::prop_assert_eq!(check_spend(&utxos, &tx).is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn spend_and_double_spend() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other = key_pair::random();
+        let receiver = Address::from_public_key_bytes(other.public_key().as_ref());
+        let spec = ChainSpec {
+            allocations: vec![Allocation { address: owner, balance: 100 }],
+            ledger: LedgerModel::Utxo,
+            block_reward: RewardSchedule { initial: 50, halving_interval: 0 },
+            ..Default::default()
+        };
+        let mut state = UtxoState::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+        let (allocation, _) = state.unspent(&owner)[0];
+
+        let pay = |value| {
+            let outputs = vec![Output { address: receiver, value }, Output { address: owner, value: 100 - value }];
+            LedgerTransaction::from(UtxoTransaction::sign(&[allocation], outputs, &key))
+        };
+        let tx = pay(30);
+        assert!(state.check(&tx).is_ok());
+        let coinbase = state.coinbase(receiver, 1).unwrap().unwrap();
+        let mut b1 = block(&genesis.hash(), vec![coinbase.clone(), tx.clone()]);
+        b1.header.state_root = state.root_after(&b1, 1).unwrap();
+        state.update(&b1, 1).unwrap();
+        assert_eq!(state.balance(&receiver), 80);
+        assert_eq!(state.balance(&owner), 70);
+        assert_eq!(state.accounts_at(&b1.hash()).unwrap()[&receiver], (0, 80));
+
+        // the allocation is spent now, in the mempool and in a block
+        let again = pay(40);
+        assert!(state.check(&again).is_err());
+        let b2 = block(&b1.hash(), vec![again.clone()]);
+        assert!(state.root_after(&b2, 2).is_err());
+        // but not on a fork from the genesis block, where only one of them goes in
+        let fork = block(&genesis.hash(), vec![tx.clone(), again.clone()]);
+        assert!(state.root_after(&fork, 1).is_err());
+        let mut before = UtxoState::new(&spec);
+        before.update(&genesis, 0).unwrap();
+        assert_eq!(before.select(vec![&tx, &again]).len(), 1);
+
+        // spending someone else's output, or minting too much
+        let (theirs, _) = state.unspent(&receiver)[0];
+        let stolen = UtxoTransaction::sign(&[theirs], vec![Output { address: owner, value: 1 }], &key);
+        assert!(state.check(&stolen.into()).is_err());
+        let greedy = UtxoTransaction::coinbase(owner, 51, 2).unwrap();
+        assert!(state.root_after(&block(&b1.hash(), vec![greedy.into()]), 2).is_err());
+        let late = UtxoTransaction::coinbase(owner, 50, 2).unwrap();
+        assert!(state.root_after(&block(&b1.hash(), vec![tx.clone(), late.into()]), 2).is_err());
+        let mut forged = pay(30);
+        if let LedgerTransaction::Utxo(tx) = &mut forged {
+            tx.outputs[0].value = 100;
+        }
+        assert!(state.root_after(&block(&genesis.hash(), vec![forged]), 1).is_err());
+
+        // a height past what the coinbase holds is refused rather than wrapped around
+        assert!(state.coinbase(owner, u32::MAX as u64).unwrap().is_some());
+        assert!(state.coinbase(owner, u32::MAX as u64 + 1).is_err());
+
+        // a transaction with anything after it does not decode
+        let hex = tx.to_hex();
+        assert_eq!(LedgerTransaction::decode(&hex, LedgerModel::Utxo).unwrap().hash(), tx.hash());
+        assert!(LedgerTransaction::decode(&format!("{}00", hex), LedgerModel::Utxo).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use crate::types::address::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Most gas a contract transaction can use
+pub const MAX_GAS: u64 = 1_000_000;
+/// Most instructions a contract can have
+pub const MAX_CODE: usize = 1024;
+/// Most arguments a call can pass
+pub const MAX_ARGS: usize = 16;
+/// Deepest the stack can get
+const MAX_STACK: usize = 256;
+/// Gas a deploy uses for each instruction it stores
+pub const DEPLOY_GAS: u64 = 10;
+
+/// An instruction of the contract machine: a stack of 64-bit words, a key/value storage of them
+/// per contract, and gas for every instruction run. Comparisons and `not` push 1 for true and 0
+/// for false; binary operations pop their right operand first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Push(u64),
+    Pop,
+    /// Push a copy of the word this far below the top, 0 being the top
+    Dup(u8),
+    Swap,
+    /// Arithmetic fails on overflow and division by zero rather than wrapping
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Lt,
+    Gt,
+    Eq,
+    Not,
+    /// Go to an instruction; going past the last one stops
+    Jump(u32),
+    /// Pop a word and go to an instruction if it is not zero
+    JumpIf(u32),
+    /// Push an argument of the call
+    Arg(u8),
+    /// Push whether the sender of the call is this address
+    IsCaller(Address),
+    /// Push the height of the block
+    Height,
+    /// Push the timestamp of the block, in milliseconds since the Unix epoch
+    Timestamp,
+    /// Pop a key and push the value stored under it, zero if none
+    Load,
+    /// Pop a value, then a key, and store the value under the key
+    Store,
+    /// Stop, returning the word on top of the stack
+    Return,
+    Stop,
+    /// Fail, undoing every store of the call
+    Revert,
+}
+
+impl Op {
+    pub fn gas(&self) -> u64 {
+        match self {
+            Op::Store => 20,
+            Op::Load => 5,
+            Op::Mul | Op::Div | Op::Mod => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// What a call can see of the transaction and block it runs in
+pub struct Context<'a> {
+    pub caller: Address,
+    pub args: &'a [u64],
+    pub height: u64,
+    pub timestamp: u64,
+}
+
+/// How a call that did not fail ended
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    pub gas_used: u64,
+    /// The word `return` returned, if the call ended with it
+    pub result: Option<u64>,
+}
+
+/// Run `code` on `storage` with at most `gas_limit` gas. On failure `storage` may be half changed,
+/// so the caller runs it on a copy it can throw away.
+pub fn execute(code: &[Op], storage: &mut BTreeMap<u64, u64>, context: &Context, gas_limit: u64) -> Result<Outcome, String> {
+    let mut stack = Vec::new();
+    let mut gas_used: u64 = 0;
+    let mut pc = 0;
+    while let Some(op) = code.get(pc) {
+        gas_used = gas_used.saturating_add(op.gas());
+        if gas_used > gas_limit {
+            return Err(format!("out of gas at instruction {}", pc));
+        }
+        match step(*op, &mut stack, storage, context).map_err(|e| format!("instruction {}: {}", pc, e))? {
+            Flow::Next => pc += 1,
+            Flow::Jump(target) => pc = target as usize,
+            Flow::Return(result) => return Ok(Outcome { gas_used, result }),
+            Flow::Revert => return Err(format!("reverted at instruction {}", pc)),
+        }
+    }
+    Ok(Outcome { gas_used, result: None })
+}
+
+enum Flow {
+    Next,
+    Jump(u32),
+    Return(Option<u64>),
+    Revert,
+}
+
+fn step(op: Op, stack: &mut Vec<u64>, storage: &mut BTreeMap<u64, u64>, context: &Context) -> Result<Flow, String> {
+    let pushed = match op {
+        Op::Push(word) => word,
+        Op::Pop => {
+            pop(stack)?;
+            return Ok(Flow::Next);
+        }
+        Op::Dup(depth) => *stack
+            .iter()
+            .rev()
+            .nth(depth as usize)
+            .ok_or_else(|| format!("no word {} below the top", depth))?,
+        Op::Swap => {
+            let (a, b) = (pop(stack)?, pop(stack)?);
+            stack.push(a);
+            b
+        }
+        Op::Add => binary(stack, u64::checked_add)?,
+        Op::Sub => binary(stack, u64::checked_sub)?,
+        Op::Mul => binary(stack, u64::checked_mul)?,
+        Op::Div => binary(stack, u64::checked_div)?,
+        Op::Mod => binary(stack, u64::checked_rem)?,
+        Op::Lt => binary(stack, |a, b| Some((a < b) as u64))?,
+        Op::Gt => binary(stack, |a, b| Some((a > b) as u64))?,
+        Op::Eq => binary(stack, |a, b| Some((a == b) as u64))?,
+        Op::Not => (pop(stack)? == 0) as u64,
+        Op::Jump(target) => return Ok(Flow::Jump(target)),
+        Op::JumpIf(target) => {
+            return Ok(if pop(stack)? != 0 { Flow::Jump(target) } else { Flow::Next });
+        }
+        Op::Arg(index) => *context
+            .args
+            .get(index as usize)
+            .ok_or_else(|| format!("no argument {}", index))?,
+        Op::IsCaller(address) => (context.caller == address) as u64,
+        Op::Height => context.height,
+        Op::Timestamp => context.timestamp,
+        Op::Load => {
+            let key = pop(stack)?;
+            storage.get(&key).copied().unwrap_or(0)
+        }
+        Op::Store => {
+            let (value, key) = (pop(stack)?, pop(stack)?);
+            // zero is what an absent key loads as, so it is not stored
+            if value == 0 {
+                storage.remove(&key);
+            } else {
+                storage.insert(key, value);
+            }
+            return Ok(Flow::Next);
+        }
+        Op::Return => return Ok(Flow::Return(Some(pop(stack)?))),
+        Op::Stop => return Ok(Flow::Return(None)),
+        Op::Revert => return Ok(Flow::Revert),
+    };
+    if stack.len() == MAX_STACK {
+        return Err("stack overflow".to_string());
+    }
+    stack.push(pushed);
+    Ok(Flow::Next)
+}
+
+fn pop(stack: &mut Vec<u64>) -> Result<u64, String> {
+    stack.pop().ok_or_else(|| "stack underflow".to_string())
+}
+
+fn binary(stack: &mut Vec<u64>, f: impl Fn(u64, u64) -> Option<u64>) -> Result<u64, String> {
+    let (b, a) = (pop(stack)?, pop(stack)?);
+    f(a, b).ok_or_else(|| "arithmetic overflow or division by zero".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::{Allocation, ChainSpec, LedgerModel};
+    use crate::types::block::generate_random_block;
+    use crate::types::contract::{contract_address, ContractAction, ContractTransaction, SignedContractTransaction};
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::ledger::{Ledger, LedgerTransaction};
+    use crate::types::state_tree::StateTree;
+    use crate::types::transaction::{State, Validity};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// A price feed: its reporter sets `price` with `set(price)`, anyone reads it with `get()`.
+    /// Argument 0 is the method, 1 the price.
+    fn price_feed(reporter: Address) -> Vec<Op> {
+        vec![
+            Op::Arg(0),
+            Op::JumpIf(4),
+            // get
+            Op::Push(0),
+            Op::Jump(11),
+            // set, only by the reporter
+            Op::IsCaller(reporter),
+            Op::JumpIf(7),
+            Op::Revert,
+            Op::Push(0),
+            Op::Arg(1),
+            Op::Store,
+            Op::Stop,
+            Op::Load,
+            Op::Return,
+        ]
+    }
+
+    #[test]
+    fn price_feed_storage_and_gas() {
+        let reporter: Address = [7; 20].into();
+        let code = price_feed(reporter);
+        let mut storage = BTreeMap::new();
+        let set = Context { caller: reporter, args: &[1, 1234], height: 1, timestamp: 0 };
+        let outcome = execute(&code, &mut storage, &set, MAX_GAS).unwrap();
+        assert_eq!(outcome, Outcome { gas_used: 27, result: None });
+        assert_eq!(storage.get(&0), Some(&1234));
+
+        let get = Context { caller: [8; 20].into(), args: &[0], height: 2, timestamp: 0 };
+        assert_eq!(execute(&code, &mut storage, &get, MAX_GAS).unwrap().result, Some(1234));
+        let forged = Context { args: &[1, 1], ..get };
+        assert!(execute(&code, &mut storage.clone(), &forged, MAX_GAS).unwrap_err().contains("reverted"));
+        assert!(execute(&code, &mut storage.clone(), &set, 26).unwrap_err().contains("out of gas"));
+
+        // a loop runs until its gas is gone, and arithmetic does not wrap
+        assert!(execute(&[Op::Jump(0)], &mut storage, &get, 1000).unwrap_err().contains("out of gas"));
+        let overflow = [Op::Push(u64::MAX), Op::Push(1), Op::Add];
+        assert!(execute(&overflow, &mut storage, &get, 10).unwrap_err().contains("overflow"));
+        assert!(execute(&[Op::Add], &mut storage, &get, 10).unwrap_err().contains("underflow"));
+    }
+
+    #[test]
+    fn deploy_and_call() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other = key_pair::random();
+        let other_sender = Address::from_public_key_bytes(other.public_key().as_ref());
+        let spec = ChainSpec {
+            allocations: vec![
+                Allocation { address: sender, balance: 10_000 },
+                Allocation { address: other_sender, balance: 10_000 },
+            ],
+            fee_per_gas: 1,
+            ..Default::default()
+        };
+        let mut state = State::new(&spec);
+        let genesis = spec.genesis();
+        state.update(&genesis, 0).unwrap();
+
+        // a price the deployer sets with (1, price) and anyone gets with (0)
+        let code = vec![
+            Op::Arg(0),
+            Op::JumpIf(5),
+            Op::Push(0),
+            Op::Load,
+            Op::Return,
+            Op::IsCaller(sender),
+            Op::JumpIf(8),
+            Op::Revert,
+            Op::Push(0),
+            Op::Arg(1),
+            Op::Store,
+            Op::Stop,
+        ];
+        let contract = contract_address(&sender, 1);
+        let signed = |key: &Ed25519KeyPair, nonce, action, gas_limit, fee| {
+            let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+            let tx = ContractTransaction { nonce, sender, action, gas_limit, fee, validity: Validity::default() };
+            LedgerTransaction::from(SignedContractTransaction::sign(tx, key))
+        };
+        let deploy = signed(&key, 1, ContractAction::Deploy { code: code.clone() }, 200, 0);
+        assert!(state.check(&deploy).unwrap_err().contains("gas limit of 200"));
+        let deploy = signed(&key, 1, ContractAction::Deploy { code: code.clone() }, 200, 200);
+        state.check(&deploy).unwrap();
+        assert!(state.check(&signed(&key, 1, ContractAction::Deploy { code: code.clone() }, 100, 100)).is_err());
+        let set = signed(&key, 2, ContractAction::Call { contract, args: vec![1, 42] }, 50, 50);
+        assert!(state.check(&set).unwrap_err().contains("is not a contract"));
+        for encoded in [deploy.to_hex(), deploy.to_json()] {
+            let decoded = LedgerTransaction::decode(&encoded, LedgerModel::Account).unwrap();
+            assert_eq!(decoded.hash(), deploy.hash());
+        }
+
+        // a failed call or a deploy over its gas limit still takes the nonce and the fee
+        let forged = signed(&other, 1, ContractAction::Call { contract, args: vec![1, 7] }, 50, 50);
+        let short = signed(&key, 3, ContractAction::Deploy { code: code.clone() }, 10, 10);
+        let mut block = generate_random_block(&genesis.hash());
+        block.content.content = vec![deploy, set, forged, short];
+        block.header.state_root = state.root_after(&block, 1).unwrap();
+        state.update(&block, 1).unwrap();
+        assert_ne!(block.header.state_root, StateTree::new(state.accounts()).root());
+        assert_eq!(state.contract(&contract).unwrap().storage.get(&0), Some(&42));
+        assert!(state.contract(&contract_address(&sender, 3)).is_none());
+        assert_eq!(state.accounts()[&sender], (3, 10_000 - 260));
+        assert_eq!(state.accounts()[&other_sender], (1, 10_000 - 50));
+        assert_eq!(state.accounts()[&contract], (0, 0));
+        assert_eq!(state.call(&contract, other_sender, &[0]).unwrap().result, Some(42));
+        assert!(state.call(&contract, other_sender, &[1, 7]).is_err());
+        assert_eq!(state.contracts_at(&genesis.hash()).unwrap().len(), 0);
+    }
+}
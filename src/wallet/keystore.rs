@@ -0,0 +1,109 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+const KEYSTORE_VERSION: u32 = 1;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// On-disk keystore: a list of PKCS#8 Ed25519 keys, each encrypted with a key derived from the
+/// passphrase (PBKDF2-HMAC-SHA256) using ChaCha20-Poly1305.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Keystore {
+    pub version: u32,
+    pub accounts: Vec<EncryptedKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedKey {
+    /// Hex address, stored in the clear so accounts can be listed without the passphrase
+    pub address: String,
+    /// Hex public key
+    pub public_key: String,
+    pub salt: String,
+    pub nonce: String,
+    /// Hex ciphertext of the PKCS#8 document, followed by the authentication tag
+    pub ciphertext: String,
+}
+
+impl Keystore {
+    pub fn new() -> Self {
+        Keystore {
+            version: KEYSTORE_VERSION,
+            accounts: Vec::new(),
+        }
+    }
+
+    /// Load a keystore, or start an empty one if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading keystore {}: {}", path.display(), e))?;
+        let keystore: Keystore = serde_json::from_str(&data)
+            .map_err(|e| format!("error parsing keystore {}: {}", path.display(), e))?;
+        if keystore.version != KEYSTORE_VERSION {
+            return Err(format!("unsupported keystore version {}", keystore.version));
+        }
+        Ok(keystore)
+    }
+
+    /// Write the keystore atomically, so a crash never leaves a truncated file behind.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_string_pretty(self).unwrap();
+        let tmp = path.with_extension("tmp");
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("error creating {}: {}", dir.display(), e))?;
+        }
+        std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| format!("error writing keystore {}: {}", path.display(), e))
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+pub fn encrypt(pkcs8: &[u8], address: &str, public_key: &[u8], passphrase: &str) -> EncryptedKey {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).unwrap();
+    rng.fill(&mut nonce).unwrap();
+    let mut in_out = pkcs8.to_vec();
+    derive_key(passphrase, &salt)
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(address.as_bytes()), &mut in_out)
+        .unwrap();
+    EncryptedKey {
+        address: address.to_string(),
+        public_key: hex::encode(public_key),
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(in_out),
+    }
+}
+
+/// Decrypt a stored key back into its PKCS#8 document.
+pub fn decrypt(key: &EncryptedKey, passphrase: &str) -> Result<Vec<u8>, String> {
+    let salt = hex::decode(&key.salt).map_err(|e| format!("bad salt: {}", e))?;
+    let mut nonce = [0u8; NONCE_LEN];
+    hex::decode_to_slice(&key.nonce, &mut nonce).map_err(|e| format!("bad nonce: {}", e))?;
+    let mut in_out = hex::decode(&key.ciphertext).map_err(|e| format!("bad ciphertext: {}", e))?;
+    let plain = derive_key(passphrase, &salt)
+        .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(key.address.as_bytes()), &mut in_out)
+        .map_err(|_| format!("wrong passphrase for account {}", key.address))?;
+    Ok(plain.to_vec())
+}
@@ -0,0 +1,448 @@
+pub mod keystore;
+
+use crate::types::address::Address;
+use crate::types::batch::{BatchTransaction, Payment, SignedBatch};
+use crate::types::contract::{ContractAction, ContractTransaction, SignedContractTransaction};
+use crate::types::key_pair;
+use crate::types::ledger::{Ledger, LedgerTransaction};
+use crate::types::multisig::{MultisigPolicy, MultisigTransaction, PartialSignature};
+use crate::types::transaction::{sign_transaction, Amount, SignedTransaction, State, Transaction, Validity};
+use crate::types::utxo::{OutPoint, Output, UtxoTransaction};
+use keystore::Keystore;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+struct Account {
+    address: Address,
+    pkcs8: Vec<u8>,
+}
+
+/// Balance and nonce of a wallet account, as seen in the current state
+#[derive(Serialize, Clone, Debug)]
+pub struct AccountInfo {
+    pub address: String,
+    pub nonce: u32,
+    pub balance: Amount,
+}
+
+/// A set of Ed25519 keys, optionally backed by an encrypted keystore file.
+pub struct Wallet {
+    /// Where the keystore lives; `None` for a wallet that is lost on exit
+    path: Option<PathBuf>,
+    passphrase: String,
+    keystore: Keystore,
+    accounts: Vec<Account>,
+    /// Highest nonce signed per account, so that several transfers can be built before the
+    /// first one is mined
+    sent_nonces: HashMap<Address, u32>,
+    /// Outputs spent by the transfers built so far, for the same reason
+    spent_outputs: HashSet<OutPoint>,
+}
+
+impl Wallet {
+    /// A wallet that only lives in memory
+    pub fn in_memory() -> Self {
+        Wallet {
+            path: None,
+            passphrase: String::new(),
+            keystore: Keystore::new(),
+            accounts: Vec::new(),
+            sent_nonces: HashMap::new(),
+            spent_outputs: HashSet::new(),
+        }
+    }
+
+    /// Open (or create) the keystore at `path`, decrypting every key with `passphrase`.
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, String> {
+        let keystore = Keystore::load(path)?;
+        let mut accounts = Vec::new();
+        for encrypted in &keystore.accounts {
+            let pkcs8 = keystore::decrypt(encrypted, passphrase)?;
+            let address = address_of(&parse_pkcs8(&pkcs8)?);
+            if address.to_string() != encrypted.address {
+                return Err(format!("keystore entry {} does not match its key", encrypted.address));
+            }
+            accounts.push(Account { address, pkcs8 });
+        }
+        Ok(Wallet {
+            path: Some(path.to_path_buf()),
+            passphrase: passphrase.to_string(),
+            keystore,
+            accounts,
+            sent_nonces: HashMap::new(),
+            spent_outputs: HashSet::new(),
+        })
+    }
+
+    /// Generate a new key and store it.
+    pub fn new_account(&mut self) -> Result<Address, String> {
+        self.import(&key_pair::random_pkcs8())
+    }
+
+    /// Import a PKCS#8-encoded Ed25519 key and store it.
+    pub fn import(&mut self, pkcs8: &[u8]) -> Result<Address, String> {
+        let key = parse_pkcs8(pkcs8)?;
+        let address = address_of(&key);
+        if self.accounts.iter().any(|a| a.address == address) {
+            return Err(format!("account {} is already in the wallet", address));
+        }
+        if self.path.is_some() {
+            let encrypted = keystore::encrypt(
+                pkcs8,
+                &address.to_string(),
+                key.public_key().as_ref(),
+                &self.passphrase,
+            );
+            self.keystore.accounts.push(encrypted);
+            self.save()?;
+        }
+        self.accounts.push(Account {
+            address,
+            pkcs8: pkcs8.to_vec(),
+        });
+        Ok(address)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => self.keystore.save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn addresses(&self) -> Vec<Address> {
+        self.accounts.iter().map(|a| a.address).collect()
+    }
+
+    /// The first account of the wallet, created if the wallet is empty
+    pub fn default_address(&mut self) -> Result<Address, String> {
+        match self.accounts.first() {
+            Some(a) => Ok(a.address),
+            None => self.new_account(),
+        }
+    }
+
+    /// The key pair of an account. `Ed25519KeyPair` cannot be cloned, so a fresh one is parsed.
+    pub fn key_pair(&self, address: &Address) -> Option<Ed25519KeyPair> {
+        self.accounts
+            .iter()
+            .find(|a| a.address == *address)
+            .map(|a| parse_pkcs8(&a.pkcs8).unwrap())
+    }
+
+    pub fn account_info(&self, address: &Address, state: &dyn Ledger) -> AccountInfo {
+        AccountInfo {
+            address: address.to_string(),
+            nonce: state.nonce(address),
+            balance: state.balance(address),
+        }
+    }
+
+    pub fn accounts_info(&self, state: &dyn Ledger) -> Vec<AccountInfo> {
+        self.accounts
+            .iter()
+            .map(|a| self.account_info(&a.address, state))
+            .collect()
+    }
+
+    /// Build and sign a transfer from one of our accounts, of the model of the ledger. Only
+    /// account transfers have a window of validity, and a fee, which is the least the mempool
+    /// takes.
+    pub fn transfer(
+        &mut self,
+        from: &Address,
+        to: &Address,
+        value: Amount,
+        validity: Validity,
+        state: &dyn Ledger,
+    ) -> Result<LedgerTransaction, String> {
+        let key = self
+            .key_pair(from)
+            .ok_or_else(|| format!("account {} is not in the wallet", from))?;
+        if value == 0 {
+            return Err("value must be positive".to_string());
+        }
+        match state.as_accounts() {
+            Some(state) => self.account_transfer(from, to, value, validity, &key, state).map(LedgerTransaction::from),
+            None if validity != Validity::default() => Err("a UTXO transfer has no window of validity".to_string()),
+            None => self.utxo_transfer(from, to, value, &key, state).map(LedgerTransaction::from),
+        }
+    }
+
+    fn account_transfer(
+        &mut self,
+        from: &Address,
+        to: &Address,
+        value: Amount,
+        validity: Validity,
+        key: &Ed25519KeyPair,
+        state: &State,
+    ) -> Result<SignedTransaction, String> {
+        let mut transfer = Transaction { nonce: 0, sender: *from, receiver: *to, value, fee: 0, validity };
+        transfer.fee = state.min_fee(&transfer);
+        transfer.nonce = self.next_nonce(from, value.saturating_add(transfer.fee), state)?;
+        Ok(sign_transaction(transfer, key))
+    }
+
+    /// Build and sign a batch of payments from one of our accounts, all under one nonce, paying
+    /// the least fee the mempool takes
+    pub fn batch_transfer(
+        &mut self,
+        from: &Address,
+        payments: Vec<Payment>,
+        validity: Validity,
+        state: &dyn Ledger,
+    ) -> Result<SignedBatch, String> {
+        let key = self
+            .key_pair(from)
+            .ok_or_else(|| format!("account {} is not in the wallet", from))?;
+        let state = state.as_accounts().ok_or("a UTXO chain has no batches")?;
+        if payments.is_empty() {
+            return Err("a batch needs at least one payment".to_string());
+        }
+        if payments.iter().any(|payment| payment.value == 0) {
+            return Err("value must be positive".to_string());
+        }
+        let mut batch = BatchTransaction { nonce: 0, sender: *from, payments, fee: 0, validity };
+        batch.fee = state.min_fee(&batch);
+        let total = batch
+            .payments
+            .iter()
+            .try_fold(batch.fee, |total, payment| total.checked_add(payment.value))
+            .ok_or("the payments add up to more than the largest amount")?;
+        batch.nonce = self.next_nonce(from, total, state)?;
+        Ok(SignedBatch::sign(batch, &key))
+    }
+
+    /// Build and sign a deploy or call of a contract from one of our accounts, paying the least
+    /// fee the mempool takes for its size and gas limit
+    pub fn contract_transaction(
+        &mut self,
+        from: &Address,
+        action: ContractAction,
+        gas_limit: u64,
+        validity: Validity,
+        state: &dyn Ledger,
+    ) -> Result<SignedContractTransaction, String> {
+        let key = self
+            .key_pair(from)
+            .ok_or_else(|| format!("account {} is not in the wallet", from))?;
+        let state = state.as_accounts().ok_or("a UTXO chain has no contracts")?;
+        let mut tx = ContractTransaction { nonce: 0, sender: *from, action, gas_limit, fee: 0, validity };
+        tx.fee = state.min_fee(&tx);
+        state.check_contract(&tx)?;
+        tx.nonce = self.next_nonce(from, tx.fee, state)?;
+        Ok(SignedContractTransaction::sign(tx, &key))
+    }
+
+    /// Sign a cancel of the pending transfer from one of our accounts with `nonce`: a transfer of
+    /// nothing to itself, paying the least fee the mempool takes and more than `pending_fee`, the
+    /// fee of the transfer it replaces
+    pub fn cancel(&self, from: &Address, nonce: u32, pending_fee: Amount, state: &dyn Ledger) -> Result<SignedTransaction, String> {
+        let key = self
+            .key_pair(from)
+            .ok_or_else(|| format!("account {} is not in the wallet", from))?;
+        let state = state.as_accounts().ok_or("a UTXO chain has no nonces to cancel")?;
+        let (state_nonce, balance) = match state.accounts().get(from) {
+            Some(v) => *v,
+            None => return Err(format!("account {} does not exist on chain", from)),
+        };
+        if nonce <= state_nonce {
+            return Err(format!("nonce {} of {} is taken on chain already", nonce, from));
+        }
+        let mut transfer = Transaction { nonce, sender: *from, receiver: *from, value: 0, fee: 0, validity: Validity::default() };
+        transfer.fee = state.min_fee(&transfer).max(pending_fee.saturating_add(1));
+        if balance < transfer.fee {
+            return Err(format!("insufficient balance: {} < {}", balance, transfer.fee));
+        }
+        Ok(sign_transaction(transfer, &key))
+    }
+
+    /// The nonce of the next transfer from an account that can pay `total`, which follows those
+    /// of the transfers from it built so far
+    fn next_nonce(&mut self, from: &Address, total: Amount, state: &State) -> Result<u32, String> {
+        let (state_nonce, balance) = match state.accounts().get(from) {
+            Some(v) => *v,
+            None => return Err(format!("account {} does not exist on chain", from)),
+        };
+        if balance < total {
+            return Err(format!("insufficient balance: {} < {}", balance, total));
+        }
+        let sent_nonce = self.sent_nonces.get(from).cloned().unwrap_or(0);
+        // the nonce of a transaction is the sender's nonce after it is applied
+        let nonce = state_nonce.max(sent_nonce) + 1;
+        self.sent_nonces.insert(*from, nonce);
+        Ok(nonce)
+    }
+
+    /// Start a transfer from a multisig account, for its cosigners to sign. Its nonce follows
+    /// those of the transfers from the account started so far, and its fee is the least the
+    /// mempool takes, which the signatures do not change.
+    pub fn multisig_transfer(
+        &mut self,
+        policy: MultisigPolicy,
+        to: &Address,
+        value: Amount,
+        validity: Validity,
+        state: &dyn Ledger,
+    ) -> Result<MultisigTransaction, String> {
+        let state = state.as_accounts().ok_or("a UTXO chain has no multisig accounts")?;
+        if value == 0 {
+            return Err("value must be positive".to_string());
+        }
+        let from = policy.address();
+        let mut transfer = Transaction { nonce: 0, sender: from, receiver: *to, value, fee: 0, validity };
+        transfer.fee = state.min_fee(&transfer);
+        // the policy is checked before the nonce is taken
+        policy.validate()?;
+        transfer.nonce = self.next_nonce(&from, value.saturating_add(transfer.fee), state)?;
+        MultisigTransaction::unsigned(transfer, policy)
+    }
+
+    /// Sign a multisig transfer with every account of the wallet that is a key of it and has
+    /// not signed yet, returning those accounts
+    pub fn sign_multisig(&self, tx: &mut MultisigTransaction) -> Result<Vec<Address>, String> {
+        let mut signed = Vec::new();
+        for account in &self.accounts {
+            let key = parse_pkcs8(&account.pkcs8)?;
+            let public_key = key.public_key().as_ref();
+            if tx.policy.public_keys.iter().any(|k| k == public_key)
+                && !tx.signatures.iter().any(|s| s.public_key == public_key)
+            {
+                tx.add_signature(PartialSignature::sign(&tx.transaction, &key))?;
+                signed.push(account.address);
+            }
+        }
+        if signed.is_empty() {
+            return Err(format!("no account of the wallet has a key of {} left to sign with", tx.policy.address()));
+        }
+        Ok(signed)
+    }
+
+    /// Spend the oldest unspent outputs of `from` that cover `value`, paying the rest back to it
+    fn utxo_transfer(
+        &mut self,
+        from: &Address,
+        to: &Address,
+        value: Amount,
+        key: &Ed25519KeyPair,
+        state: &dyn Ledger,
+    ) -> Result<UtxoTransaction, String> {
+        let mut spent = Vec::new();
+        let mut total: Amount = 0;
+        for (outpoint, output_value) in state.unspent(from) {
+            if total >= value {
+                break;
+            }
+            if self.spent_outputs.contains(&outpoint) {
+                continue;
+            }
+            spent.push(outpoint);
+            total = total.saturating_add(output_value);
+        }
+        if total < value {
+            return Err(format!("insufficient unspent outputs: {} < {}", total, value));
+        }
+        let mut outputs = vec![Output { address: *to, value }];
+        if total > value {
+            outputs.push(Output { address: *from, value: total - value });
+        }
+        let tx = UtxoTransaction::sign(&spent, outputs, key);
+        self.spent_outputs.extend(spent);
+        Ok(tx)
+    }
+}
+
+/// The key in `text`, as hex-encoded PKCS#8 or a key file written by `keygen`. The errors leave
+/// the text out, since it is a private key.
+pub fn decode_pkcs8(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    let hex = if text.starts_with('{') {
+        let key_file: serde_json::Value = serde_json::from_str(text).map_err(|_| "invalid key file".to_string())?;
+        key_file["pkcs8"].as_str().ok_or("the key file has no pkcs8 field")?.to_string()
+    } else {
+        text.to_string()
+    };
+    hex::decode(hex).map_err(|_| "the key is not valid hex".to_string())
+}
+
+pub fn parse_pkcs8(pkcs8: &[u8]) -> Result<Ed25519KeyPair, String> {
+    Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|e| format!("invalid PKCS#8 Ed25519 key: {}", e))
+}
+
+/// The address controlled by a key pair
+pub fn address_of(key: &Ed25519KeyPair) -> Address {
+    Address::from_public_key_bytes(key.public_key().as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::{Allocation, ChainSpec, LedgerModel};
+    use crate::types::utxo::UtxoState;
+
+    #[test]
+    fn keystore_round_trip() {
+        let path = std::env::temp_dir().join(format!("keystore-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut wallet = Wallet::open(&path, "correct horse").unwrap();
+        let a = wallet.new_account().unwrap();
+        let b = wallet.new_account().unwrap();
+
+        let reopened = Wallet::open(&path, "correct horse").unwrap();
+        assert_eq!(reopened.addresses(), vec![a, b]);
+        assert_eq!(address_of(&reopened.key_pair(&b).unwrap()), b);
+        assert!(Wallet::open(&path, "wrong").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn transfer_nonces() {
+        let mut wallet = Wallet::in_memory();
+        let from = wallet.new_account().unwrap();
+        let to = wallet.new_account().unwrap();
+        let mut spec = ChainSpec::default();
+        spec.allocations.push(Allocation { address: from, balance: 1000 });
+        let state = State::new(&spec);
+        let t1 = wallet.transfer(&from, &to, 10, Validity::default(), &state).unwrap();
+        let t2 = wallet.transfer(&from, &to, 10, Validity::default(), &state).unwrap();
+        assert_eq!(t1.as_account().unwrap().transaction.nonce, 1);
+        assert_eq!(t2.as_account().unwrap().transaction.nonce, 2);
+        // a batch takes one nonce for all of its payments
+        let payments = vec![Payment { receiver: to, value: 5 }, Payment { receiver: from, value: 5 }];
+        let batch = wallet.batch_transfer(&from, payments, Validity::default(), &state).unwrap();
+        assert_eq!(batch.batch.nonce, 3);
+        assert!(wallet.batch_transfer(&from, Vec::new(), Validity::default(), &state).is_err());
+        // a cancel reuses a pending nonce and outbids its fee
+        let cancel = wallet.cancel(&from, 2, 3, &state).unwrap();
+        assert_eq!((cancel.transaction.nonce, cancel.transaction.receiver, cancel.transaction.fee), (2, from, 4));
+        assert!(wallet.cancel(&from, 0, 0, &state).is_err());
+        assert!(wallet.transfer(&from, &to, 5000, Validity::default(), &state).is_err());
+        assert!(wallet.transfer(&to, &from, 1, Validity::default(), &state).is_err());
+    }
+
+    #[test]
+    fn utxo_transfers() {
+        let mut wallet = Wallet::in_memory();
+        let from = wallet.new_account().unwrap();
+        let to = wallet.new_account().unwrap();
+        let mut spec = ChainSpec { ledger: LedgerModel::Utxo, ..Default::default() };
+        spec.allocations.push(Allocation { address: from, balance: 500 });
+        spec.allocations.push(Allocation { address: from, balance: 300 });
+        let state = UtxoState::new(&spec);
+        // the first output covers it, with change back to the sender
+        let t1 = wallet.transfer(&from, &to, 10, Validity::default(), &state).unwrap();
+        assert!(state.check(&t1).is_ok());
+        let LedgerTransaction::Utxo(t1) = t1 else { panic!("not a UTXO transaction") };
+        assert_eq!(t1.inputs.len(), 1);
+        assert_eq!(t1.outputs, vec![Output { address: to, value: 10 }, Output { address: from, value: 490 }]);
+        // the next transfer cannot spend it again before it is mined
+        let t2 = wallet.transfer(&from, &to, 300, Validity::default(), &state).unwrap();
+        let LedgerTransaction::Utxo(t2) = t2 else { panic!("not a UTXO transaction") };
+        assert_ne!(t2.inputs[0].outpoint, t1.inputs[0].outpoint);
+        assert_eq!(t2.outputs.len(), 1);
+        assert!(wallet.transfer(&from, &to, 1, Validity::default(), &state).is_err());
+    }
+}